@@ -0,0 +1,84 @@
+//! `--bundle`: packs the final binaries, the flavor manifests and the split debug info
+//! into a single `.tar.zst` release artifact, described by a `MANIFEST.json` entry.
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    target: &'a str,
+    profile: &'a str,
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Default)]
+pub(crate) struct Bundle {
+    // Path in the archive, file to pack
+    entries: RefCell<Vec<(String, PathBuf)>>,
+}
+
+impl Bundle {
+    /// Packs `file` as `name`, replacing a previous entry with the same name
+    /// like a copy to a flat output directory would
+    pub(crate) fn add(&self, name: String, file: &Path) {
+        let mut entries = self.entries.borrow_mut();
+        entries.retain(|(entry, _)| *entry != name);
+        entries.push((name, file.to_owned()));
+    }
+
+    /// Writes the archive, its files sorted by name for reproducible bundles
+    pub(crate) fn write(&self, path: &Path, target: &str, profile: &str) -> anyhow::Result<()> {
+        let mut entries = self.entries.borrow().clone();
+        entries.sort();
+
+        let mut files = Vec::with_capacity(entries.len());
+        for (name, file) in &entries {
+            let content =
+                fs::read(file).with_context(|| format!("Failed to read `{}`", file.display()))?;
+            files.push(ManifestEntry {
+                path: name.clone(),
+                size: content.len() as u64,
+                sha256: format!("{:x}", Sha256::digest(&content)),
+            });
+        }
+        let manifest = serde_json::to_vec_pretty(&Manifest {
+            target,
+            profile,
+            files,
+        })
+        .context("Failed to encode the bundle manifest")?;
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory `{}`", dir.display()))?;
+        }
+        let write_archive = || -> std::io::Result<()> {
+            let encoder = zstd::Encoder::new(File::create(path)?, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            let mut archive = tar::Builder::new(encoder);
+            // No timestamps nor owners, only the executable bit is kept
+            archive.mode(tar::HeaderMode::Deterministic);
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(manifest.len() as u64);
+            header.set_mode(0o644);
+            archive.append_data(&mut header, "MANIFEST.json", manifest.as_slice())?;
+            for (name, file) in &entries {
+                archive.append_path_with_name(file, name)?;
+            }
+            archive.into_inner()?.finish()?;
+            Ok(())
+        };
+        write_archive().with_context(|| format!("Failed to write the bundle `{}`", path.display()))
+    }
+}
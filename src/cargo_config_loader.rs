@@ -1,13 +1,18 @@
-use std::collections::{btree_set, BTreeSet, HashMap};
+use std::collections::{btree_set, BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
 
-use cargo_metadata;
+use anyhow::Context;
+
 use itertools::Itertools;
 use serde::{Deserialize, Deserializer};
-use serde_json;
-use target_lexicon::{Architecture, Triple};
+use target_lexicon::{Architecture, OperatingSystem};
 
-use crate::rustc_queries::Rustc;
+use crate::cli::{OverrideMode, Preset};
+use crate::format;
+use crate::rustc_queries::RustcQueries;
 
 // Dealing with the orphan rule is such a pain ....
 
@@ -44,22 +49,261 @@ impl<'a> From<&'a Architecture> for &'a ArchitectureWrapper {
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Debug, Default)]
+#[serde(from = "BTreeSet<String>")]
 #[repr(transparent)]
 pub(crate) struct CpuFeatures(BTreeSet<String>);
 
 impl FromIterator<String> for CpuFeatures {
     fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Self {
-        Self(BTreeSet::from_iter(iter))
+        Self(
+            iter.into_iter()
+                .map(|feature| normalize_feature(&feature))
+                .collect(),
+        )
+    }
+}
+
+impl From<BTreeSet<String>> for CpuFeatures {
+    fn from(features: BTreeSet<String>) -> Self {
+        features.into_iter().collect()
+    }
+}
+
+/// rustc feature names are lowercase but users write `AVX2`, `+avx2`, `avx-512f` or `sse4_2`,
+/// those would not match `rustc --print=cfg` and the flavor would be silently dropped.
+fn normalize_feature(feature: &str) -> String {
+    if feature.starts_with('@') {
+        // Alias names are user-defined
+        return feature.to_owned();
     }
+    let feature = feature.trim().trim_start_matches('+').to_ascii_lowercase();
+    if ["avx512", "avx-512", "avx_512"]
+        .iter()
+        .any(|prefix| feature.starts_with(prefix))
+    {
+        // avx512f, avx512vnni, avx512bf16, ...
+        return feature.replace(['-', '_'], "");
+    }
+    match feature.as_str() {
+        // x86
+        "sse4_1" | "sse4-1" => "sse4.1",
+        "sse4_2" | "sse4-2" => "sse4.2",
+        "pni" => "sse3",
+        // LLVM and /proc/cpuinfo spell it `bmi`
+        "bmi" => "bmi1",
+        "cx16" => "cmpxchg16b",
+        "sha_ni" | "sha-ni" => "sha",
+        // aarch64
+        "asimd" => "neon",
+        other => other,
+    }
+    .to_owned()
+}
+
+/// A binary flavor to build:
+/// a set of CPU features and the build customizations applied only to this flavor.
+/// In Cargo.toml, either a list of CPU features `["avx2", "bmi2"]`
+/// or a table `{ cpufeatures = ["avx2", "bmi2"], label = "haswell", features = ["simd"], rustflags = ["-Zsanitizer=address"], pgo_profile = "pgo/avx2.profdata", min_glibc = "2.34", priority = 10 }`
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(from = "FlavorRepr")]
+pub(crate) struct Flavor {
+    pub(crate) cpufeatures: CpuFeatures,
+    // Name of the flavor in progress and reports, e.g. "haswell"
+    pub(crate) label: Option<String>,
+    // Package features, passed to cargo on top of --features
+    pub(crate) cargo_features: BTreeSet<String>,
+    // Passed to rustc after -Ctarget-feature, for example for instrumentation
+    pub(crate) rustflags: Vec<String>,
+    // Passed to rustc as -Cprofile-use, relative to the package directory
+    pub(crate) pgo_profile: Option<PathBuf>,
+    // Minimum glibc version of the host, e.g. "2.34", checked by the dispatcher
+    pub(crate) min_glibc: Option<String>,
+    // Selected by the dispatcher over the ranked flavors, the highest first
+    pub(crate) priority: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FlavorRepr {
+    CpuFeatures(CpuFeatures),
+    Table {
+        cpufeatures: CpuFeatures,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        features: BTreeSet<String>,
+        #[serde(default)]
+        rustflags: Vec<String>,
+        #[serde(default)]
+        pgo_profile: Option<PathBuf>,
+        #[serde(default)]
+        min_glibc: Option<String>,
+        #[serde(default)]
+        priority: Option<u32>,
+    },
 }
 
-pub(crate) fn has_no_features(list: &BTreeSet<CpuFeatures>) -> bool {
-    list.is_empty() || list.len() == 1 && {
-        // Clap parser will put an empty string here
-        list.last().unwrap().is_empty()
+impl From<FlavorRepr> for Flavor {
+    fn from(repr: FlavorRepr) -> Self {
+        match repr {
+            FlavorRepr::CpuFeatures(cpufeatures) => cpufeatures.into(),
+            FlavorRepr::Table {
+                cpufeatures,
+                label,
+                features,
+                rustflags,
+                pgo_profile,
+                min_glibc,
+                priority,
+            } => Self {
+                cpufeatures,
+                label,
+                cargo_features: features,
+                rustflags,
+                pgo_profile,
+                min_glibc,
+                priority,
+            },
+        }
     }
 }
 
+type BuildOptions<'a> = (
+    &'a BTreeSet<String>,
+    &'a [String],
+    Option<&'a PathBuf>,
+    Option<&'a String>,
+);
+
+impl Flavor {
+    /// Everything but the CPU features that makes two flavors distinct builds
+    fn build_options(&self) -> BuildOptions<'_> {
+        (
+            &self.cargo_features,
+            &self.rustflags,
+            self.pgo_profile.as_ref(),
+            self.min_glibc.as_ref(),
+        )
+    }
+
+    /// What makes two flavors distinct builds. The label and the priority do not change the binary,
+    /// entries differing only by them would build it twice to the same file.
+    fn build_key(&self) -> (&CpuFeatures, BuildOptions<'_>) {
+        (&self.cpufeatures, self.build_options())
+    }
+
+    /// Human readable description of the flavor for progress and reports
+    pub(crate) fn describe(&self) -> String {
+        let arch_flags = self.cpufeatures.to_compiler_flags();
+        format!(
+            "{}{}{}{}{}{}{}",
+            match &self.label {
+                Some(label) => format!("{label}: "),
+                None => String::new(),
+            },
+            if !arch_flags.is_empty() {
+                &arch_flags
+            } else {
+                "default fallback"
+            },
+            if self.cargo_features.is_empty() {
+                String::new()
+            } else {
+                format!(" (features: {})", self.cargo_features.iter().join(","))
+            },
+            if self.rustflags.is_empty() {
+                String::new()
+            } else {
+                format!(" (rustflags: {})", self.rustflags.join(" "))
+            },
+            match &self.pgo_profile {
+                Some(profile) => format!(" (pgo: {})", profile.display()),
+                None => String::new(),
+            },
+            match &self.min_glibc {
+                Some(version) => format!(" (glibc >= {version})"),
+                None => String::new(),
+            },
+            match self.priority {
+                Some(priority) => format!(" (priority {priority})"),
+                None => String::new(),
+            }
+        )
+    }
+
+    /// Makes the PGO profile path absolute, relative to `base`,
+    /// and checks that it is readable to fail before building
+    pub(crate) fn resolve_pgo_profile(mut self, base: &Path) -> anyhow::Result<Self> {
+        if let Some(profile) = &self.pgo_profile {
+            let profile = base.join(profile);
+            File::open(&profile).with_context(|| {
+                format!(
+                    "Failed to read the PGO profile `{}` of flavor {}",
+                    profile.display(),
+                    self.describe()
+                )
+            })?;
+            self.pgo_profile = Some(profile);
+        }
+        Ok(self)
+    }
+
+    fn expand_aliases(self, aliases: &HashMap<String, CpuFeatures>) -> anyhow::Result<Self> {
+        Ok(Self {
+            cpufeatures: self.cpufeatures.expand_aliases(aliases)?,
+            ..self
+        })
+    }
+}
+
+impl PartialEq for Flavor {
+    fn eq(&self, other: &Self) -> bool {
+        self.build_key() == other.build_key()
+    }
+}
+
+impl Eq for Flavor {}
+
+impl PartialOrd for Flavor {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Flavor {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.build_key().cmp(&other.build_key())
+    }
+}
+
+impl std::hash::Hash for Flavor {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.build_key().hash(state)
+    }
+}
+
+impl From<CpuFeatures> for Flavor {
+    fn from(cpufeatures: CpuFeatures) -> Self {
+        Self {
+            cpufeatures,
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) fn has_no_features(list: &BTreeSet<Flavor>) -> bool {
+    list.is_empty()
+        || list.len() == 1 && {
+            // Clap parser will put an empty string here
+            let flavor = list.last().unwrap();
+            flavor.cpufeatures.is_empty()
+                && flavor.cargo_features.is_empty()
+                && flavor.rustflags.is_empty()
+                && flavor.pgo_profile.is_none()
+                && flavor.min_glibc.is_none()
+        }
+}
+
 impl CpuFeatures {
     pub(crate) fn iter(&self) -> btree_set::Iter<'_, String> {
         self.0.iter()
@@ -75,6 +319,24 @@ impl CpuFeatures {
         }
     }
 
+    /// Replaces `@alias` references by the features they stand for
+    fn expand_aliases(self, aliases: &HashMap<String, CpuFeatures>) -> anyhow::Result<Self> {
+        let mut expanded = BTreeSet::new();
+        for feature in self.0 {
+            if let Some(alias) = feature.strip_prefix('@') {
+                let features = aliases.get(alias).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Unknown CPU features alias `@{alias}`, aliases are defined in [package.metadata.multiarch.aliases]"
+                    )
+                })?;
+                expanded.extend(features.iter().cloned());
+            } else {
+                expanded.insert(feature);
+            }
+        }
+        Ok(Self(expanded))
+    }
+
     /// Builds a string of CPU feature flags that can be given to `rustc -C target-feature=` (e.g., `+aes,+avx,+sse`)
     pub fn to_compiler_flags(&self) -> String {
         if !self.0.is_empty() {
@@ -85,88 +347,423 @@ impl CpuFeatures {
     }
 }
 
+/// Expected structure of [package.metadata.multiarch], for `--print config-schema`
+pub(crate) const CONFIG_SCHEMA: &str = r#"# [package.metadata.multiarch] in Cargo.toml, or the root of a --config file
+
+# One table per architecture, e.g. x86_64 or aarch64
+[package.metadata.multiarch.x86_64]
+# CPUs to build for, one flavor per distinct feature set, "" for none
+cpus = ["x86-64-v2", "x86-64-v3"]
+# Flavors to build, a list of flavors, each
+# - a list of CPU features, possibly `@alias` references
+# - or a table with CPU features and customizations of the flavor
+cpufeatures = [
+    ["avx2", "bmi2"],
+    { cpufeatures = ["avx512f"], label = "skylake-x", features = ["simd"], rustflags = [], pgo_profile = "pgo/avx512.profdata" },
+    # Skipped by the dispatcher on hosts with an older glibc
+    { cpufeatures = ["avx2"], min_glibc = "2.34" },
+    # Preferred by the dispatcher over the ranking when the host supports it, the highest first
+    { cpufeatures = ["avx2", "fma"], priority = 10 },
+]
+
+# Optional table per OS of the target triple, e.g. linux, windows or darwin,
+# replacing the architecture table when building for this OS
+[package.metadata.multiarch.x86_64.windows]
+cpus = ["x86-64-v2"]
+cpufeatures = []
+
+# Named lists of CPU features, referenced as `@name` in cpufeatures and --cpufeatures
+[package.metadata.multiarch.aliases]
+v3 = ["avx2", "bmi", "bmi2", "fma"]"#;
+
+/// JSON schema of [package.metadata.multiarch], for `--print config-schema --print-format json`
+pub(crate) fn config_json_schema() -> serde_json::Value {
+    let strings = serde_json::json!({ "type": "array", "items": { "type": "string" } });
+    let flavors = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "cpus": strings,
+            "cpufeatures": {
+                "type": "array",
+                "items": {
+                    "oneOf": [
+                        strings,
+                        {
+                            "type": "object",
+                            "properties": {
+                                "cpufeatures": strings,
+                                "label": { "type": "string" },
+                                "features": strings,
+                                "rustflags": strings,
+                                "pgo_profile": { "type": "string" },
+                                "min_glibc": { "type": "string", "pattern": "^[0-9]+\\.[0-9]+$" },
+                                "priority": { "type": "integer", "minimum": 0, "maximum": u32::MAX },
+                            },
+                            "required": ["cpufeatures"],
+                            "additionalProperties": false,
+                        },
+                    ],
+                },
+            },
+        },
+        "additionalProperties": false,
+    });
+    let mut arch = flavors.clone();
+    arch["description"] = "CPU architecture, e.g. x86_64 or aarch64".into();
+    arch["additionalProperties"] = flavors;
+    arch["additionalProperties"]["description"] =
+        "OS of the target, e.g. linux or windows, replaces the architecture table".into();
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "[package.metadata.multiarch]",
+        "type": "object",
+        "properties": {
+            "aliases": { "type": "object", "additionalProperties": strings },
+        },
+        "additionalProperties": arch,
+    })
+}
+
+/// Checks the structure of the multiarch tables before deserializing them,
+/// serde errors do not tell which table and key are wrong
+fn validate_tables(multiarch: &serde_json::Value) -> anyhow::Result<()> {
+    let is_strings = |value: &serde_json::Value| {
+        value
+            .as_array()
+            .is_some_and(|items| items.iter().all(serde_json::Value::is_string))
+    };
+    let tables = multiarch
+        .as_object()
+        .context("Expected tables, e.g. [x86_64] and [aliases]")?;
+
+    for (name, table) in tables {
+        if name == "aliases" {
+            let aliases = table
+                .as_object()
+                .context("[aliases] must be a table of CPU features lists")?;
+            for (alias, features) in aliases {
+                anyhow::ensure!(
+                    is_strings(features),
+                    "[aliases] `{alias}` must be a list of CPU features, e.g. [\"avx2\", \"bmi2\"]"
+                );
+            }
+            continue;
+        }
+
+        anyhow::ensure!(
+            Architecture::from_str(name).is_ok(),
+            "Unknown architecture [{name}], expected a table per architecture such as [x86_64] or [aarch64], or [aliases]"
+        );
+        validate_arch_table(name, table, true, &is_strings)?;
+    }
+    Ok(())
+}
+
+/// Checks an [<arch>] table, or without `os_tables` an [<arch>.<os>] table
+fn validate_arch_table(
+    name: &str,
+    table: &serde_json::Value,
+    os_tables: bool,
+    is_strings: &impl Fn(&serde_json::Value) -> bool,
+) -> anyhow::Result<()> {
+    let arch = table
+        .as_object()
+        .with_context(|| format!("[{name}] must be a table with `cpus` and `cpufeatures`"))?;
+    for (key, value) in arch {
+        match key.as_str() {
+            "cpus" => anyhow::ensure!(
+                is_strings(value),
+                "[{name}] `cpus` must be a list of CPU names, e.g. [\"x86-64-v3\"]"
+            ),
+            "cpufeatures" => {
+                let flavors = value
+                    .as_array()
+                    .with_context(|| format!("[{name}] `cpufeatures` must be a list of flavors"))?;
+                for flavor in flavors {
+                    validate_flavor(name, flavor, is_strings)?;
+                }
+            }
+            os if os_tables && OperatingSystem::from_str(os).is_ok() => {
+                validate_arch_table(&format!("{name}.{os}"), value, false, is_strings)?
+            }
+            _ if os_tables => anyhow::bail!(
+                "[{name}] has an unknown key `{key}`, expected `cpus`, `cpufeatures` or an OS table such as [{name}.linux], see `cargo multiarch --print config-schema`"
+            ),
+            _ => anyhow::bail!(
+                "[{name}] has an unknown key `{key}`, expected `cpus` or `cpufeatures`, see `cargo multiarch --print config-schema`"
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn validate_flavor(
+    arch: &str,
+    flavor: &serde_json::Value,
+    is_strings: impl Fn(&serde_json::Value) -> bool,
+) -> anyhow::Result<()> {
+    match flavor {
+        serde_json::Value::String(feature) => anyhow::bail!(
+            "[{arch}] `cpufeatures` is a list of flavors, each a list of CPU features: \
+            found `{feature}` instead of a list, write `cpufeatures = [[\"avx2\", \"bmi2\"]]` for a single flavor"
+        ),
+        serde_json::Value::Array(_) => {
+            anyhow::ensure!(
+                is_strings(flavor),
+                "[{arch}] `cpufeatures` flavors must be lists of CPU features, found {flavor}"
+            );
+            Ok(())
+        }
+        serde_json::Value::Object(table) => {
+            anyhow::ensure!(
+                table.contains_key("cpufeatures"),
+                "[{arch}] flavor {flavor} has no `cpufeatures`"
+            );
+            for (key, value) in table {
+                let valid = match key.as_str() {
+                    "cpufeatures" | "features" | "rustflags" => is_strings(value),
+                    "label" | "pgo_profile" => value.is_string(),
+                    "min_glibc" => value.as_str().and_then(format::parse_glibc_version).is_some(),
+                    "priority" => value.as_u64().is_some_and(|priority| priority <= u32::MAX.into()),
+                    _ => anyhow::bail!(
+                        "[{arch}] flavor {flavor} has an unknown key `{key}`, \
+                        expected `cpufeatures`, `label`, `features`, `rustflags`, `pgo_profile`, `min_glibc` or `priority`"
+                    ),
+                };
+                anyhow::ensure!(
+                    valid,
+                    "[{arch}] flavor {flavor} has an invalid `{key}`, see `cargo multiarch --print config-schema`"
+                );
+            }
+            Ok(())
+        }
+        _ => anyhow::bail!(
+            "[{arch}] `cpufeatures` flavors must be lists of CPU features or tables, found {flavor}"
+        ),
+    }
+}
+
 /// cargo-multiarch will compile a binary
 /// - per cpu
 /// - and per set of CPU features
 #[derive(Default, PartialEq, Eq, Hash, Debug, Clone, Deserialize)]
+#[serde(default)] // An architecture table may only hold OS tables
 struct ConfigTargetsForArch {
     cpus: BTreeSet<String>,
     // a single <feature list> MUST be sorted and ideally deduped
     // and the list of <feature list> might as well be
-    cpufeatures: BTreeSet<CpuFeatures>,
+    cpufeatures: BTreeSet<Flavor>,
 }
 
 #[derive(Debug)]
 pub(crate) struct ConfigMultiArch {
-    target: Triple,
+    rustc: Rc<dyn RustcQueries>,
+    arch: Architecture, // Selects the [<arch>] table, --arch or the target architecture
+    os: OperatingSystem, // Selects the [<arch>.<os>] table over the [<arch>] table
+    target_name: String, // --target given to rustc, a triple or a target spec path
     archs: HashMap<ArchitectureWrapper, ConfigTargetsForArch>,
+    // Named CPU features lists, referenced as `@name` in cpufeatures
+    aliases: HashMap<String, CpuFeatures>,
 }
 
 impl ConfigMultiArch {
-    pub(crate) fn new(target: Triple) -> Self {
+    pub(crate) fn new(
+        rustc: Rc<dyn RustcQueries>,
+        arch: Architecture,
+        os: OperatingSystem,
+        target_name: String,
+    ) -> Self {
         Self {
-            target,
+            rustc,
+            arch,
+            os,
+            target_name,
             archs: Default::default(),
+            aliases: Default::default(),
         }
     }
-    pub(crate) fn load_cargo_toml(
-        mut self,
-        toml: &cargo_metadata::Package,
-    ) -> anyhow::Result<Self> {
+    pub(crate) fn load_cargo_toml(self, toml: &cargo_metadata::Package) -> anyhow::Result<Self> {
         if toml.metadata.is_null() {
             return Ok(self);
         };
 
         let metadata: HashMap<String, serde_json::Value> =
             serde_json::from_value(toml.metadata.clone())?;
-        let Some(multiarch) = metadata.get("multiarch") else {
+        let Some(multiarch) = metadata.get("multiarch").cloned() else {
             return Ok(self);
         };
-        let archs: HashMap<ArchitectureWrapper, ConfigTargetsForArch> =
+        self.load_tables(multiarch)
+            .context("Invalid [package.metadata.multiarch] in Cargo.toml")
+    }
+
+    /// Loads a standalone TOML file with the same structure as [package.metadata.multiarch]
+    /// Its architectures and aliases override those already loaded.
+    pub(crate) fn load_file(self, path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read `{}`", path.display()))?;
+        let multiarch: serde_json::Value = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse `{}`", path.display()))?;
+        self.load_tables(multiarch)
+            .with_context(|| format!("Invalid multiarch config `{}`", path.display()))
+    }
+
+    /// Loads `<arch>` and `aliases` tables
+    fn load_tables(mut self, mut multiarch: serde_json::Value) -> anyhow::Result<Self> {
+        validate_tables(&multiarch)?;
+
+        // Aliases live next to the architectures tables
+        let aliases: HashMap<String, CpuFeatures> = multiarch
+            .as_object_mut()
+            .and_then(|tables| tables.remove("aliases"))
+            .map(Deserialize::deserialize)
+            .transpose()?
+            .unwrap_or_default();
+        if let Some((alias, _)) = aliases
+            .iter()
+            .find(|(_, features)| features.iter().any(|feature| feature.starts_with('@')))
+        {
+            anyhow::bail!("CPU features alias `@{alias}` cannot reference another alias");
+        }
+        self.aliases.extend(aliases);
+
+        // The table of the target OS, e.g. [x86_64.linux], replaces its architecture table
+        for arch_table in multiarch
+            .as_object_mut()
+            .into_iter()
+            .flat_map(|tables| tables.values_mut())
+            .filter_map(serde_json::Value::as_object_mut)
+        {
+            let os_keys: Vec<String> = arch_table
+                .keys()
+                .filter(|key| !matches!(key.as_str(), "cpus" | "cpufeatures"))
+                .cloned()
+                .collect();
+            let mut target_os_table = None;
+            for key in os_keys {
+                let os_table = arch_table.remove(&key);
+                if OperatingSystem::from_str(&key).is_ok_and(|os| os == self.os) {
+                    target_os_table = os_table;
+                }
+            }
+            if let Some(serde_json::Value::Object(os_table)) = target_os_table {
+                *arch_table = os_table;
+            }
+        }
+
+        let mut archs: HashMap<ArchitectureWrapper, ConfigTargetsForArch> =
             Deserialize::deserialize(multiarch)?;
+        for target_config in archs.values_mut() {
+            target_config.cpufeatures = std::mem::take(&mut target_config.cpufeatures)
+                .into_iter()
+                .map(|flavor| flavor.expand_aliases(&self.aliases))
+                .collect::<anyhow::Result<_>>()?;
+        }
 
-        self.archs = archs;
+        self.archs.extend(archs);
         Ok(self)
     }
 
-    pub(crate) fn override_cpus(mut self, cpus: BTreeSet<String>) -> anyhow::Result<Self> {
-        if cpus.is_empty() {
+    /// Adds the CPUs of a built-in matrix to those configured for the target architecture.
+    /// The CPUs are resolved to their features like configured ones.
+    pub(crate) fn load_preset(mut self, preset: Preset) -> anyhow::Result<Self> {
+        let (name, matches_arch, cpus): (_, fn(&Architecture) -> bool, &[&str]) = match preset {
+            Preset::X86_64Levels => (
+                "x86-64-levels",
+                |arch| *arch == Architecture::X86_64,
+                &["x86-64-v2", "x86-64-v3", "x86-64-v4"],
+            ),
+            Preset::Aarch64Common => (
+                "aarch64-common",
+                |arch| matches!(arch, Architecture::Aarch64(_)),
+                &["neoverse-n1", "neoverse-v1"],
+            ),
+        };
+        anyhow::ensure!(
+            matches_arch(&self.arch),
+            "Preset `{name}` does not apply to target '{}'",
+            self.target_name
+        );
+
+        let arch = self.arch;
+        let target_config = self.archs.entry(arch.into()).or_default();
+        target_config
+            .cpus
+            .extend(cpus.iter().map(|&cpu| cpu.to_owned()));
+        Ok(self)
+    }
+
+    /// Rejects CPU features unknown to rustc for the target, rustc would ignore them.
+    pub(crate) fn check_cpu_features(self) -> anyhow::Result<Self> {
+        let Some(target_config) = self.archs.get((&self.arch).into()) else {
             return Ok(self);
         };
+        let requested: BTreeSet<&String> = target_config
+            .cpufeatures
+            .iter()
+            .flat_map(|flavor| flavor.cpufeatures.iter())
+            .filter(|feature| !feature.is_empty())
+            .collect();
+        if requested.is_empty() {
+            return Ok(self);
+        }
 
-        let arch = &self.target.architecture;
+        let known = self.rustc.get_target_features(&self.target_name)?;
+        let unknown = requested
+            .into_iter()
+            .filter(|&feature| !known.contains(feature))
+            .map(|feature| format!("`{feature}`"))
+            .join(", ");
+        anyhow::ensure!(
+            unknown.is_empty(),
+            "Unknown CPU features {unknown} for target '{}', list them with `cargo multiarch --print target-cpu-features`",
+            self.target_name
+        );
+        Ok(self)
+    }
 
-        if let Some(target_config) = self.archs.get_mut(arch.into()) {
-            target_config.cpus = cpus;
-        } else {
-            let config_arch = ConfigTargetsForArch {
-                cpus,
-                cpufeatures: BTreeSet::new(),
-            };
-            let _ = self.archs.insert((*arch).into(), config_arch);
+    pub(crate) fn override_cpus(
+        mut self,
+        cpus: BTreeSet<String>,
+        mode: OverrideMode,
+    ) -> anyhow::Result<Self> {
+        if cpus.is_empty() {
+            return Ok(self);
         };
+
+        let arch = self.arch;
+        let target_config = self.archs.entry(arch.into()).or_default();
+        match mode {
+            OverrideMode::Replace => target_config.cpus = cpus,
+            OverrideMode::Merge => target_config.cpus.extend(cpus),
+            OverrideMode::Ignore if target_config.cpus.is_empty() => target_config.cpus = cpus,
+            OverrideMode::Ignore => {}
+        }
         Ok(self)
     }
 
     pub(crate) fn override_features_lists(
         mut self,
-        cpufeat_lists: BTreeSet<CpuFeatures>,
+        cpufeat_lists: BTreeSet<Flavor>,
+        mode: OverrideMode,
     ) -> anyhow::Result<Self> {
         if has_no_features(&cpufeat_lists) {
             return Ok(self);
         };
+        let cpufeat_lists: BTreeSet<Flavor> = cpufeat_lists
+            .into_iter()
+            .map(|flavor| flavor.expand_aliases(&self.aliases))
+            .collect::<anyhow::Result<_>>()?;
 
-        let arch = &self.target.architecture;
-
-        if let Some(target_config) = self.archs.get_mut(arch.into()) {
-            target_config.cpufeatures = cpufeat_lists;
-        } else {
-            let config_arch = ConfigTargetsForArch {
-                cpus: BTreeSet::new(),
-                cpufeatures: cpufeat_lists,
-            };
-            let _ = self.archs.insert((*arch).into(), config_arch);
-        };
+        let arch = self.arch;
+        let target_config = self.archs.entry(arch.into()).or_default();
+        match mode {
+            OverrideMode::Replace => target_config.cpufeatures = cpufeat_lists,
+            OverrideMode::Merge => target_config.cpufeatures.extend(cpufeat_lists),
+            OverrideMode::Ignore if target_config.cpufeatures.is_empty() => {
+                target_config.cpufeatures = cpufeat_lists
+            }
+            OverrideMode::Ignore => {}
+        }
         Ok(self)
     }
 
@@ -175,28 +772,493 @@ impl ConfigMultiArch {
     /// The returned list is sorted and deduplicated at 2 level:
     /// - the inner list of features per build
     /// - the outer list of builds
-    pub(crate) fn get_cpu_features(&self) -> BTreeSet<CpuFeatures> {
-        let Some(target_config) = self.archs.get((&self.target.architecture).into()) else {
+    pub(crate) fn get_cpu_features(&self) -> BTreeSet<Flavor> {
+        let Some(target_config) = self.archs.get((&self.arch).into()) else {
             return BTreeSet::new();
         };
 
-        let features_of_cpus: BTreeSet<CpuFeatures> = target_config
+        let features_of_cpus: BTreeSet<Flavor> = target_config
             .cpus
             .iter()
             .flat_map(|cpu| {
-                Rustc::get_cpufeatures_for_programs(Some(&self.target.to_string()), Some(&cpu))
-                    .map(|list| CpuFeatures::from_iter(list))
+                self.rustc
+                    .get_cpufeatures_for_programs(Some(&self.target_name), Some(cpu))
+                    .map(CpuFeatures::from_iter)
             })
             .filter(|list| !list.is_empty())
+            .map(Flavor::from)
             .collect();
 
         if has_no_features(&target_config.cpufeatures) {
-            return features_of_cpus;
+            features_of_cpus
         } else {
-            return features_of_cpus
+            features_of_cpus
                 .union(&target_config.cpufeatures)
                 .cloned()
+                .collect()
+        }
+    }
+
+    /// Why no flavor is configured for the target architecture, with how to configure one.
+    /// A config for other architectures hints at a wrong table name or target.
+    pub(crate) fn explain_no_flavors(&self) -> String {
+        let arch = self.arch;
+        let configured = self
+            .archs
+            .iter()
+            .filter(|(_, target_config)| **target_config != ConfigTargetsForArch::default())
+            .map(|(arch, _)| arch.0.to_string())
+            .sorted()
+            .join(", ");
+        if configured.is_empty() {
+            format!(
+                "No CPUs or CPU features configured for {arch}, add a [package.metadata.multiarch.{arch}] table or pass --cpus, --cpufeatures or --preset"
+            )
+        } else {
+            format!(
+                "Found a multiarch config for [{configured}] but '{}' is {arch}, add a [package.metadata.multiarch.{arch}] table or pass --cpus",
+                self.target_name
+            )
+        }
+    }
+
+    /// Detect configured flavors that will build the same binary,
+    /// either exact duplicates between CPUs and features lists
+    /// or features lists that only differ by implied features (avx2 implies avx).
+    /// Returns a warning message per group of redundant entries.
+    pub(crate) fn find_redundant_flavors(&self) -> anyhow::Result<Vec<String>> {
+        let Some(target_config) = self.archs.get((&self.arch).into()) else {
+            return Ok(Vec::new());
+        };
+        let target = &self.target_name;
+
+        // Group the configuration entries by the features rustc will effectively enable
+        // and the other options of the build, as the flavors are deduplicated
+        let cpu_flavor = Flavor::default();
+        let mut builds: BTreeMap<_, Vec<String>> = BTreeMap::new();
+        for cpu in target_config.cpus.iter().filter(|cpu| !cpu.is_empty()) {
+            let features: BTreeSet<String> = self
+                .rustc
+                .get_cpufeatures_for_programs(Some(target), Some(cpu))?
+                .into_iter()
+                .collect();
+            builds
+                .entry((features, cpu_flavor.build_options()))
+                .or_default()
+                .push(format!("cpu `{cpu}`"));
+        }
+        for flavor in target_config
+            .cpufeatures
+            .iter()
+            .filter(|flavor| !flavor.cpufeatures.is_empty())
+        {
+            let features = self
+                .rustc
+                .get_effective_cpufeatures(target, &flavor.cpufeatures.to_compiler_flags())?;
+            builds
+                .entry((features, flavor.build_options()))
+                .or_default()
+                .push(format!(
+                    "cpufeatures [{}]",
+                    flavor.cpufeatures.iter().join(", ")
+                ));
+        }
+
+        Ok(builds
+            .into_values()
+            .filter(|entries| entries.len() > 1)
+            .map(|entries| {
+                format!(
+                    "{} enable the same CPU features, only one flavor will be kept",
+                    entries.join(" and ")
+                )
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A toolchain that knows a few x86_64 CPUs, without running rustc
+    #[derive(Debug)]
+    struct FakeRustc;
+
+    impl FakeRustc {
+        const CPUS: &'static [(&'static str, &'static [&'static str])] = &[
+            (
+                "x86-64-v2",
+                &["popcnt", "sse3", "sse4.1", "sse4.2", "ssse3"],
+            ),
+            ("haswell", &["avx", "avx2", "fma"]),
+            ("x86-64-v3", &["avx", "avx2", "bmi1", "bmi2", "fma"]),
+        ];
+        const IMPLIED: &'static [(&'static str, &'static str)] =
+            &[("avx2", "avx"), ("fma", "avx"), ("sse4.2", "sse4.1")];
+    }
+
+    impl RustcQueries for FakeRustc {
+        fn get_host_target(&self) -> anyhow::Result<String> {
+            Ok("x86_64-unknown-linux-gnu".to_string())
+        }
+
+        fn get_cpufeatures_for_programs(
+            &self,
+            _target_triple: Option<&str>,
+            target_cpu: Option<&str>,
+        ) -> anyhow::Result<Vec<String>> {
+            let cpu = target_cpu.unwrap_or("x86-64");
+            Ok(Self::CPUS
+                .iter()
+                .find(|(name, _)| *name == cpu)
+                .map(|(_, features)| features.iter().map(|f| f.to_string()).collect())
+                .unwrap_or_default())
+        }
+
+        fn get_effective_cpufeatures(
+            &self,
+            _target_triple: &str,
+            target_features: &str,
+        ) -> anyhow::Result<BTreeSet<String>> {
+            let enabled: BTreeSet<&str> = target_features
+                .split(',')
+                .filter_map(|feature| feature.strip_prefix('+'))
                 .collect();
+            let implied = Self::IMPLIED
+                .iter()
+                .filter(|(feature, _)| enabled.contains(feature))
+                .map(|(_, implied)| *implied);
+            Ok(enabled
+                .iter()
+                .copied()
+                .chain(implied)
+                .map(String::from)
+                .collect())
         }
+
+        fn get_target_features(&self, _target_triple: &str) -> anyhow::Result<BTreeSet<String>> {
+            Ok(Self::CPUS
+                .iter()
+                .flat_map(|(_, features)| features.iter())
+                .map(|feature| feature.to_string())
+                .collect())
+        }
+
+        fn get_cpu_names(&self, _target_triple: &str) -> anyhow::Result<BTreeSet<String>> {
+            Ok(Self::CPUS
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect())
+        }
+    }
+
+    fn config(toml: &str) -> ConfigMultiArch {
+        ConfigMultiArch::new(
+            Rc::new(FakeRustc),
+            Architecture::X86_64,
+            OperatingSystem::Linux,
+            "x86_64-unknown-linux-gnu".to_string(),
+        )
+        .load_tables(toml::from_str(toml).unwrap())
+        .unwrap()
+    }
+
+    fn features(list: &[&str]) -> CpuFeatures {
+        list.iter().map(|feature| feature.to_string()).collect()
+    }
+
+    fn flavors(lists: &[&[&str]]) -> BTreeSet<Flavor> {
+        lists.iter().map(|list| features(list).into()).collect()
+    }
+
+    fn aliases() -> HashMap<String, CpuFeatures> {
+        HashMap::from([("v3".to_string(), features(&["avx2", "bmi1", "bmi2", "fma"]))])
+    }
+
+    #[test]
+    fn aliases_expand_in_place_of_the_reference() {
+        let flavor = Flavor {
+            cargo_features: BTreeSet::from(["simd".to_string()]),
+            ..features(&["@v3", "aes"]).into()
+        };
+        let expanded = flavor.expand_aliases(&aliases()).unwrap();
+        assert_eq!(
+            expanded.cpufeatures,
+            features(&["aes", "avx2", "bmi1", "bmi2", "fma"])
+        );
+        assert_eq!(
+            expanded.cargo_features,
+            BTreeSet::from(["simd".to_string()])
+        );
+    }
+
+    #[test]
+    fn unknown_alias_is_an_error() {
+        let flavor = Flavor::from(features(&["@v4"]));
+        let err = flavor.expand_aliases(&aliases()).unwrap_err();
+        assert!(
+            err.to_string().contains("Unknown CPU features alias `@v4`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn bmi_is_normalized_to_bmi1() {
+        assert_eq!(features(&["BMI", "+avx2"]), features(&["avx2", "bmi1"]));
+        let config = config(
+            r#"
+            [x86_64]
+            cpus = []
+            cpufeatures = [["bmi", "bmi2"]]
+            "#,
+        );
+        assert!(config.check_cpu_features().is_ok());
+    }
+
+    #[test]
+    fn host_target_comes_from_the_queries() {
+        assert_eq!(
+            FakeRustc.target_triple_or_host(None).unwrap(),
+            "x86_64-unknown-linux-gnu"
+        );
+        assert_eq!(
+            FakeRustc
+                .target_triple_or_host(Some("x86_64-pc-windows-msvc"))
+                .unwrap(),
+            "x86_64-pc-windows-msvc"
+        );
+    }
+
+    #[test]
+    fn cpus_are_resolved_to_their_features() {
+        let config = config(
+            r#"
+            [x86_64]
+            cpus = ["haswell", "unknown-cpu"]
+            cpufeatures = [["sse4.2"]]
+            "#,
+        );
+        assert_eq!(
+            config.get_cpu_features(),
+            flavors(&[&["avx", "avx2", "fma"], &["sse4.2"]])
+        );
+    }
+
+    #[test]
+    fn identical_flavors_are_deduplicated() {
+        let config = config(
+            r#"
+            [x86_64]
+            cpus = ["haswell"]
+            cpufeatures = [["avx", "avx2", "fma"], ["FMA", "+avx2", "avx"], ["sse4_2"], ["sse4.2"]]
+            "#,
+        );
+        assert_eq!(
+            config.get_cpu_features(),
+            flavors(&[&["avx", "avx2", "fma"], &["sse4.2"]])
+        );
+    }
+
+    #[test]
+    fn command_line_overrides_by_mode() {
+        let configured = r#"
+            [aliases]
+            v3 = ["avx2", "fma"]
+            [x86_64]
+            cpus = ["x86-64-v2"]
+            cpufeatures = [["sse4.2"]]
+            "#;
+        let cli_features = || flavors(&[&["@v3"]]);
+        let cli_cpus = || BTreeSet::from(["haswell".to_string()]);
+        let overridden = |mode| {
+            config(configured)
+                .override_cpus(cli_cpus(), mode)
+                .unwrap()
+                .override_features_lists(cli_features(), mode)
+                .unwrap()
+                .get_cpu_features()
+        };
+        let v2 = ["popcnt", "sse3", "sse4.1", "sse4.2", "ssse3"];
+
+        assert_eq!(
+            overridden(OverrideMode::Replace),
+            flavors(&[&["avx", "avx2", "fma"], &["avx2", "fma"]])
+        );
+        assert_eq!(
+            overridden(OverrideMode::Merge),
+            flavors(&[&["avx", "avx2", "fma"], &v2, &["avx2", "fma"], &["sse4.2"]])
+        );
+        assert_eq!(
+            overridden(OverrideMode::Ignore),
+            flavors(&[&v2, &["sse4.2"]])
+        );
+
+        // Without configuration, the command line applies in every mode
+        let unconfigured = config("[aliases]\nv3 = [\"avx2\", \"fma\"]")
+            .override_features_lists(cli_features(), OverrideMode::Ignore)
+            .unwrap();
+        assert_eq!(
+            unconfigured.get_cpu_features(),
+            flavors(&[&["avx2", "fma"]])
+        );
+    }
+
+    #[test]
+    fn features_lists_differing_by_implied_features_are_redundant() {
+        let config = config(
+            r#"
+            [x86_64]
+            cpus = []
+            cpufeatures = [["avx2"], ["avx", "avx2"], ["sse4.2"]]
+            "#,
+        );
+        assert_eq!(
+            config.find_redundant_flavors().unwrap(),
+            ["cpufeatures [avx, avx2] and cpufeatures [avx2] enable the same CPU features, only one flavor will be kept"]
+        );
+    }
+
+    #[test]
+    fn cpu_and_its_features_list_are_redundant() {
+        let config = config(
+            r#"
+            [x86_64]
+            cpus = ["haswell"]
+            cpufeatures = [["avx2", "fma"]]
+            "#,
+        );
+        assert_eq!(
+            config.find_redundant_flavors().unwrap(),
+            ["cpu `haswell` and cpufeatures [avx2, fma] enable the same CPU features, only one flavor will be kept"]
+        );
+    }
+
+    #[test]
+    fn flavors_with_other_build_options_are_not_redundant() {
+        let config = config(
+            r#"
+            [x86_64]
+            cpus = []
+            cpufeatures = [
+                ["avx2"],
+                { cpufeatures = ["avx", "avx2"], features = ["simd"] },
+                { cpufeatures = ["avx2"], rustflags = ["-Cdebuginfo=2"] },
+            ]
+            "#,
+        );
+        assert!(config.find_redundant_flavors().unwrap().is_empty());
+    }
+
+    #[test]
+    fn flavors_differing_by_min_glibc_are_not_redundant() {
+        let config = config(
+            r#"
+            [x86_64]
+            cpus = []
+            cpufeatures = [["avx2"], { cpufeatures = ["avx2"], min_glibc = "2.34" }]
+            "#,
+        );
+        assert!(config.find_redundant_flavors().unwrap().is_empty());
+    }
+
+    #[test]
+    fn flavors_as_lists_and_tables() {
+        let config = config(
+            r#"
+            [x86_64]
+            cpus = []
+            cpufeatures = [
+                ["AVX2", "+fma"],
+                { cpufeatures = ["sse4_2"], label = "nehalem", features = ["simd"], rustflags = ["-Cdebuginfo=2"], min_glibc = "2.17" },
+            ]
+            "#,
+        );
+        let flavors: Vec<Flavor> = config.get_cpu_features().into_iter().collect();
+        assert_eq!(flavors.len(), 2);
+        assert_eq!(flavors[0], Flavor::from(features(&["avx2", "fma"])));
+        assert_eq!(flavors[0].label, None);
+        let table = &flavors[1];
+        assert_eq!(table.cpufeatures, features(&["sse4.2"]));
+        assert_eq!(table.label.as_deref(), Some("nehalem"));
+        assert_eq!(table.cargo_features, BTreeSet::from(["simd".to_string()]));
+        assert_eq!(table.rustflags, ["-Cdebuginfo=2"]);
+        assert_eq!(table.min_glibc.as_deref(), Some("2.17"));
+    }
+
+    #[test]
+    fn label_does_not_make_a_distinct_flavor() {
+        let config = config(
+            r#"
+            [x86_64]
+            cpus = []
+            cpufeatures = [
+                ["avx2"],
+                { cpufeatures = ["avx2"], label = "haswell" },
+                { cpufeatures = ["avx2"], features = ["simd"] },
+            ]
+            "#,
+        );
+        let flavors = config.get_cpu_features();
+        assert_eq!(flavors.len(), 2);
+        assert!(flavors
+            .iter()
+            .any(|flavor| flavor.cargo_features.contains("simd")));
+    }
+
+    #[test]
+    fn os_table_replaces_the_arch_table_on_its_os() {
+        let config = config(
+            r#"
+            [x86_64]
+            cpufeatures = [["avx2"]]
+            [x86_64.linux]
+            cpufeatures = [["avx512f"]]
+            [x86_64.windows]
+            cpufeatures = [["sse4.2"]]
+            "#,
+        );
+        assert_eq!(
+            config.get_cpu_features(),
+            BTreeSet::from([Flavor::from(features(&["avx512f"]))])
+        );
+    }
+
+    #[test]
+    fn arch_table_applies_without_a_table_for_the_os() {
+        let config = config(
+            r#"
+            [x86_64]
+            cpufeatures = [["avx2"]]
+            [x86_64.darwin]
+            cpufeatures = [["avx512f"]]
+            "#,
+        );
+        assert_eq!(
+            config.get_cpu_features(),
+            BTreeSet::from([Flavor::from(features(&["avx2"]))])
+        );
+    }
+
+    #[test]
+    fn arch_table_may_only_hold_os_tables() {
+        let toml = r#"
+            [x86_64.linux]
+            cpus = ["haswell"]
+            "#;
+        assert_eq!(
+            config(toml).get_cpu_features(),
+            BTreeSet::from([Flavor::from(features(&["avx", "avx2", "fma"]))])
+        );
+
+        let other_os = ConfigMultiArch::new(
+            Rc::new(FakeRustc),
+            Architecture::X86_64,
+            OperatingSystem::Windows,
+            "x86_64-pc-windows-msvc".to_string(),
+        )
+        .load_tables(toml::from_str(toml).unwrap())
+        .unwrap();
+        assert!(other_os.get_cpu_features().is_empty());
     }
 }
@@ -1,56 +1,238 @@
+use std::io::{self, BufRead, BufReader, IsTerminal};
 use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use escargot::error::CargoError;
-use escargot::CommandMessages;
+use anyhow::Context;
+use cargo_metadata::diagnostic::DiagnosticLevel;
+use cargo_metadata::Message;
+use escargot::CargoBuild;
+use itertools::Itertools;
 
-pub trait CommandMessagesExt {
-    /// Finds the executable artifact in the stream of messages from Cargo while printing rustc messages.
-    fn find_executable(self) -> Result<Option<PathBuf>, CargoError>;
+use crate::interrupt;
+
+pub trait CargoBuildExt {
+    /// Runs cargo and finds the executable artifact in the stream of messages from Cargo while printing rustc messages.
+    /// `what` names the build in errors, for example the flavor.
+    /// If a timeout is given, cargo and the rustc processes it spawned are killed when it expires,
+    /// as on Ctrl-C.
+    /// When stderr is not a terminal, the warnings cargo prints itself, like those of build scripts,
+    /// are appended to `warnings`.
+    /// If the build fails, the error contains the compiler errors and, when captured, the error reported by cargo.
+    fn exec_find_executable(
+        self,
+        what: &str,
+        timeout: Option<Duration>,
+        warnings: &mut Vec<String>,
+    ) -> anyhow::Result<Option<PathBuf>>;
 }
 
-impl CommandMessagesExt for CommandMessages {
-    fn find_executable(self) -> Result<Option<PathBuf>, CargoError> {
-        self.into_iter()
-            .filter_map(|message| {
-                let message = match message {
-                    Ok(message) => message,
-                    Err(e) => return Some(Err(e)),
-                };
-                match message.decode() {
-                    Ok(escargot::format::Message::CompilerArtifact(artifact)) => artifact
-                        .executable
-                        .as_deref()
-                        .map(ToOwned::to_owned)
-                        .map(Ok),
-                    Ok(escargot::format::Message::CompilerMessage(e)) => {
-                        // We ignore the messages that are generated due to the use of `-Ctarget-feature`
-                        if e.message
-                            .message
-                            .contains("unstable feature specified for `-Ctarget-feature`")
-                        {
-                            return None;
-                        }
-                        // We also ignore the "N warnings emitted" messages, because they are no longer accurate
-                        // since we ignore the "unstable feature specified for `-Ctarget-feature`" messages.
-                        if e.message.message.contains("warning emitted")
-                            || e.message.message.contains("warnings emitted")
-                        {
-                            return None;
-                        }
-                        if let Some(rendered) = e.message.rendered {
-                            eprint!("{rendered}");
-                        }
-
-                        None
+impl CargoBuildExt for CargoBuild {
+    fn exec_find_executable(
+        self,
+        what: &str,
+        timeout: Option<Duration>,
+        warnings: &mut Vec<String>,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let mut cmd = self.into_command();
+        cmd.stdout(Stdio::piped());
+        // On a terminal, cargo shows its progress and build scripts may check for one,
+        // so its output is only captured, and forwarded as is, when it goes elsewhere
+        let capture_stderr = !io::stderr().is_terminal();
+        if capture_stderr {
+            cmd.stderr(Stdio::piped());
+            if console::colors_enabled_stderr() && std::env::var_os("CARGO_TERM_COLOR").is_none() {
+                cmd.env("CARGO_TERM_COLOR", "always");
+            }
+        }
+        #[cfg(unix)]
+        {
+            // Own process group so that rustc processes are killed with cargo
+            use std::os::unix::process::CommandExt;
+            cmd.process_group(0);
+        }
+
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Failed to execute cargo to build {what}"))?;
+        let _interrupt = interrupt::ChildGuard::new(child.id());
+        let stdout = child
+            .stdout
+            .take()
+            .context("Failed to capture cargo output")?;
+        let stderr_reader = child
+            .stderr
+            .take()
+            .map(|stderr| thread::spawn(move || forward_stderr(BufReader::new(stderr))));
+
+        // Set once cargo is reaped, so that the watchdog never kills a reused pid
+        let reaped = Arc::new(Mutex::new(false));
+        let watchdog = timeout.map(|timeout| {
+            let (done, done_rx) = mpsc::channel::<()>();
+            let pid = child.id();
+            let reaped = Arc::clone(&reaped);
+            let handle = thread::spawn(move || match done_rx.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => {
+                    let reaped = reaped.lock().unwrap_or_else(|e| e.into_inner());
+                    if !*reaped {
+                        kill_process_tree(pid);
                     }
-                    Ok(_) => {
-                        // Ignored
-                        None
+                    !*reaped
+                }
+                _ => false,
+            });
+            (done, handle)
+        });
+
+        let executable = find_executable(BufReader::new(stdout));
+        let status = child.wait().context("Failed to wait for cargo");
+        *reaped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        let (cargo_warnings, cargo_error) = stderr_reader
+            .and_then(|reader| reader.join().ok())
+            .unwrap_or_default();
+        warnings.extend(cargo_warnings);
+        let killed = watchdog.is_some_and(|(done, handle)| {
+            let _ = done.send(());
+            handle.join().unwrap_or(false)
+        });
+        let status = status?;
+
+        // Cargo may have exited on its own right before the kill
+        if killed && !status.success() {
+            anyhow::bail!(
+                "cargo build of {what} was killed after {}s (build timeout)",
+                timeout.unwrap_or_default().as_secs()
+            );
+        }
+
+        let (executable, compiler_errors) = executable.context("Failed to parse cargo messages")?;
+        if !status.success() {
+            // Printed above, but possibly far above or among other messages
+            let diagnostics = compiler_errors.into_iter().chain(cargo_error).join("\n");
+            if diagnostics.is_empty() {
+                anyhow::bail!("cargo build of {what} failed ({status})");
+            }
+            anyhow::bail!("cargo build of {what} failed ({status}):\n{}", diagnostics.trim_end());
+        }
+        Ok(executable)
+    }
+}
+
+/// The executable artifact and the rendered compiler errors
+fn find_executable(stdout: impl BufRead) -> io::Result<(Option<PathBuf>, Vec<String>)> {
+    let mut executable = None;
+    let mut errors = Vec::new();
+    for message in Message::parse_stream(stdout) {
+        match message? {
+            Message::CompilerArtifact(artifact) => {
+                // Test harnesses are executables too, but with the libtest entry point
+                if artifact.profile.test {
+                    continue;
+                }
+                if let Some(path) = artifact.executable {
+                    executable = Some(path.into_std_path_buf());
+                }
+            }
+            Message::CompilerMessage(e) => {
+                // We ignore the messages that are generated due to the use of `-Ctarget-feature`
+                if e.message
+                    .message
+                    .contains("unstable feature specified for `-Ctarget-feature`")
+                {
+                    continue;
+                }
+                // We also ignore the "N warnings emitted" messages, because they are no longer accurate
+                // since we ignore the "unstable feature specified for `-Ctarget-feature`" messages.
+                if e.message.message.contains("warning emitted")
+                    || e.message.message.contains("warnings emitted")
+                {
+                    continue;
+                }
+                if let Some(rendered) = e.message.rendered {
+                    eprint!("{rendered}");
+                    if matches!(
+                        e.message.level,
+                        DiagnosticLevel::Error | DiagnosticLevel::Ice
+                    ) {
+                        errors.push(rendered);
                     }
-                    Err(e) => Some(Err(e)),
                 }
-            })
-            .last()
-            .transpose()
+            }
+            _ => {
+                // Ignored
+            }
+        }
+    }
+    Ok((executable, errors))
+}
+
+/// Prints the output of cargo and returns its warnings, without the
+/// "generated N warnings" counts of the compiler messages, and its error if any:
+/// cargo ends with it, followed by the output of a failed build script
+fn forward_stderr(stderr: impl BufRead) -> (Vec<String>, Option<String>) {
+    let mut warnings = Vec::new();
+    let mut error: Option<String> = None;
+    for line in stderr.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        eprintln!("{line}");
+        let plain = console::strip_ansi_codes(&line);
+        if let Some(error) = &mut error {
+            error.push('\n');
+            error.push_str(&plain);
+        } else if plain.starts_with("error") {
+            // The "could not compile" summary of compiler errors, or a cargo error
+            error = Some(plain.into_owned());
+        } else if let Some(warning) = plain.strip_prefix("warning: ") {
+            // e.g. "`foo` (bin "foo") generated 2 warnings"
+            if !(warning.starts_with('`') && warning.contains(" generated ")) {
+                warnings.push(warning.to_owned());
+            }
+        }
+    }
+    (warnings, error)
+}
+
+#[cfg(unix)]
+pub(crate) fn kill_process_tree(pid: u32) {
+    // The process group id is the pid of its leader
+    unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+}
+
+#[cfg(windows)]
+pub(crate) fn kill_process_tree(pid: u32) {
+    let _ = std::process::Command::new("taskkill")
+        .args(["/F", "/T", "/PID", &pid.to_string()])
+        .status();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cargo_warnings_and_error() {
+        let stderr =
+            "\x1b[1m\x1b[33mwarning\x1b[0m: app@0.1.0: libfoo not found, using the bundled copy
+   Compiling app v0.1.0
+warning: `app` (bin \"app\") generated 2 warnings
+error: failed to run custom build command for `app v0.1.0`
+
+Caused by:
+  process didn't exit successfully
+warning: build failed, waiting for other jobs to finish...
+";
+        let (warnings, error) = forward_stderr(stderr.as_bytes());
+        assert_eq!(
+            warnings,
+            ["app@0.1.0: libfoo not found, using the bundled copy"]
+        );
+        let error = error.unwrap();
+        assert!(error.starts_with("error: failed to run custom build command"));
+        assert!(error.ends_with("waiting for other jobs to finish..."));
     }
 }
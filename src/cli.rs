@@ -1,7 +1,7 @@
 use std::path::PathBuf;
+use std::str::FromStr;
 
-use clap;
-use clap_cargo;
+use target_lexicon::Architecture;
 
 #[derive(clap::Parser)]
 #[command(name = "cargo", bin_name = "cargo")]
@@ -11,7 +11,7 @@ pub enum Cargo {
 }
 
 /// Query RUSTC
-#[derive(clap::ValueEnum, Clone, Copy)]
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
 pub enum Print {
     /// List all (CPU, OS) "target triple" this version of rustc can build for.
     TargetList,
@@ -25,10 +25,212 @@ pub enum Print {
     /// Defaults to host CPU
     #[clap(verbatim_doc_comment)]
     TargetCpuFeatures,
+    /// Print the JSON target specification of "--target <TRIPLE>",
+    /// a starting point for custom targets that can then be passed to --target.
+    /// Requires nightly rustc.
+    #[clap(verbatim_doc_comment)]
+    TargetSpecJson,
+    /// Print which configured flavor the dispatcher would select
+    /// on a host with the CPU features of "--target-cpu", without building.
+    /// Defaults to host CPU
+    #[clap(verbatim_doc_comment)]
+    SelectedFlavor,
+    /// List the flavors each package resolves to and the total number of builds,
+    /// without building.
+    #[clap(verbatim_doc_comment)]
+    BuildPlan,
+    /// Print the expected structure of [package.metadata.multiarch],
+    /// a JSON schema with --print-format json
+    #[clap(verbatim_doc_comment)]
+    ConfigSchema,
+    /// Summarize the toolchain: rustc path, version and channel,
+    /// host target and CPU, to paste into bug reports
+    #[clap(verbatim_doc_comment)]
+    RustcVersion,
+}
+
+/// Coloring of the output
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Color {
+    /// Colored when writing to a terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Output format of --print
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PrintFormat {
+    /// Text with the raw rustc output
+    #[default]
+    Human,
+    /// Structured JSON for scripts
+    Json,
+}
+
+/// Layout of the final artifacts in --out-dir
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutLayout {
+    /// All artifacts in <out-dir>/<bin>
+    #[default]
+    Flat,
+    /// Artifacts in <out-dir>/<package>/<bin>, avoids collisions between packages
+    PerPackage,
+}
+
+/// Packaging of the flavors of a binary
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Emit {
+    /// A single executable that extracts and runs the best flavor
+    #[default]
+    Fatbin,
+    /// A POSIX shell launcher next to the flavors, each flavor stays a distinct file
+    Script,
+    /// The fat binary in a single-layer OCI image layout directory, `<bin>.oci`,
+    /// to push to a container registry
+    Oci,
+}
+
+/// Strategy of the dispatcher to pick a flavor among those supported by the host
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ranking {
+    /// Hand-tuned ranking of CPU features per microarchitecture level,
+    /// simple on architectures without one
+    #[default]
+    Curated,
+    /// The flavor with the most CPU features wins
+    Simple,
+}
+
+/// Global allocator of the dispatcher
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DispatcherAlloc {
+    /// Rust default allocator
+    #[default]
+    Default,
+    /// Explicitly use the system allocator
+    System,
+    /// Bump allocator that never frees small allocations, backed by the system allocator
+    Minimal,
+}
+
+/// Stripping of the final fat binary
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StripFatbin {
+    /// Keep symbols and debug info
+    None,
+    /// Strip symbols and debug info
+    #[default]
+    Symbols,
+    /// Strip debug info only
+    Debuginfo,
+}
+
+/// How --cpus and --cpufeatures combine with the configured values
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverrideMode {
+    /// The command-line values replace the configured ones
+    #[default]
+    Replace,
+    /// The command-line values are added to the configured ones
+    Merge,
+    /// The configured values win, the command-line values only apply when none are configured
+    Ignore,
+}
+
+/// Built-in flavor matrices, for projects without [package.metadata.multiarch]
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// x86_64: the x86-64-v2, x86-64-v3 and x86-64-v4 microarchitecture levels
+    #[value(name = "x86-64-levels")]
+    X86_64Levels,
+    /// aarch64: Neoverse N1 (Graviton2, Ampere Altra) and Neoverse V1 (Graviton3) servers
+    #[value(name = "aarch64-common")]
+    Aarch64Common,
+}
+
+#[derive(clap::Subcommand, Clone)]
+pub(crate) enum Command {
+    /// Pack flavors built outside of cargo-multiarch into a fat binary.
+    /// The dispatcher is configured by the options before `pack`, e.g. --target, --ranking or --out-dir
+    #[clap(verbatim_doc_comment)]
+    Pack(PackArgs),
+    /// Reconstruct every flavor embedded in a fat binary and check it against its hash.
+    /// The fat binary is run in a verification mode, it must be executable on this host
+    #[clap(verbatim_doc_comment)]
+    Verify(VerifyArgs),
+    /// Build two flavors of a binary and diff their disassembly, to see what CPU features change.
+    /// Requires llvm-objdump or objdump (or OBJDUMP) and diff
+    #[clap(verbatim_doc_comment)]
+    Disasm(DisasmArgs),
+    /// Add a prebuilt flavor to an existing fat binary, which is replaced.
+    /// Its flavors are unpacked by running it, it must be executable on this host
+    #[clap(verbatim_doc_comment)]
+    AddFlavor(AddFlavorArgs),
+}
+
+#[derive(clap::Args, Clone)]
+pub(crate) struct PackArgs {
+    /// Flavor built without extra CPU features, run when no other flavor is supported.
+    /// Alone, it is the final binary without dispatcher,
+    /// for example a binary that multiversions its hot functions
+    #[clap(long, value_name = "PATH", verbatim_doc_comment)]
+    pub fallback: PathBuf,
+
+    /// Flavor built with the comma-separated CPU features, can be repeated.
+    /// For example "avx2,bmi2=target/x86-64-v3/release/app"
+    #[clap(long, value_name = "FEATURES=PATH", verbatim_doc_comment)]
+    pub flavor: Vec<String>,
+
+    /// File name of the fat binary, defaults to the file name of the fallback
+    #[clap(long, value_name = "NAME")]
+    pub name: Option<String>,
+}
+
+#[derive(clap::Args, Clone)]
+pub(crate) struct VerifyArgs {
+    /// Fat binary built by cargo-multiarch
+    #[clap(value_name = "FATBIN")]
+    pub fatbin: PathBuf,
+}
+
+#[derive(clap::Args, Clone)]
+pub(crate) struct DisasmArgs {
+    /// Comma-separated CPU features of the first flavor, e.g. "+avx2".
+    /// Empty for the flavor without extra CPU features
+    #[clap(long, value_name = "CPUFEATURES", verbatim_doc_comment)]
+    pub flavor_a: String,
+
+    /// Comma-separated CPU features of the second flavor, e.g. "+avx512f"
+    #[clap(long, value_name = "CPUFEATURES")]
+    pub flavor_b: String,
+
+    /// Only diff the functions whose demangled name contains this, e.g. "my_crate::hot_loop"
+    #[clap(long, value_name = "NAME")]
+    pub function: Option<String>,
 }
 
-#[derive(clap::Args)]
+#[derive(clap::Args, Clone)]
+pub(crate) struct AddFlavorArgs {
+    /// Fat binary built by cargo-multiarch
+    #[clap(long, value_name = "PATH")]
+    pub fatbin: PathBuf,
+
+    /// Comma-separated CPU features the flavor was built with, e.g. "+avx512f"
+    #[clap(long, value_name = "CPUFEATURES")]
+    pub features: String,
+
+    /// The flavor, built for the same target as the fat binary
+    #[clap(long, value_name = "PATH")]
+    pub from: PathBuf,
+}
+
+#[derive(clap::Args, Clone)]
 pub(crate) struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Query or build for the target triple.
     /// For example "x86_64-unknown-linux-gnu" or "aarch64-apple-darwin".
     /// A target-triple is an LLVM concept.
@@ -38,23 +240,242 @@ pub(crate) struct Args {
     #[clap(long, value_name = "TRIPLE", verbatim_doc_comment)]
     pub target: Option<String>,
 
+    /// Architecture whose [package.metadata.multiarch.<ARCH>] table applies, e.g. "x86_64".
+    /// Defaults to the architecture of --target, for custom triples or target specs
+    /// whose architecture is not the configured table name.
+    /// The target passed to rustc is unchanged
+    #[clap(long, value_name = "ARCH", value_parser = parse_arch, verbatim_doc_comment)]
+    pub arch: Option<Architecture>,
+
+    /// Build the dispatcher of the fat binary for another target triple than the flavors.
+    /// For example "x86_64-unknown-linux-musl" for a static dispatcher
+    /// that runs on any Linux host, while flavors target glibc.
+    /// Defaults to --target
+    #[clap(long, value_name = "TRIPLE", verbatim_doc_comment)]
+    pub dispatcher_target: Option<String>,
+
     /// Query rustc
     #[clap(short, long, value_name = "QUERY")]
     pub print: Option<Print>,
 
+    /// Output format of --print queries.
+    /// JSON is available for target-list, target-cpus, target-cpu-features, target-spec-json,
+    /// config-schema and rustc-version
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t,
+        verbatim_doc_comment
+    )]
+    pub print_format: PrintFormat,
+
+    /// Coloring of the output
+    #[clap(long, value_name = "WHEN", value_enum, default_value_t)]
+    pub color: Color,
+
     /// Query (query only) for the specified CPU
     #[clap(long, value_name = "CPU")]
     pub target_cpu: Option<String>,
 
+    /// Packaging of the flavors
+    #[clap(long, value_name = "KIND", value_enum, default_value_t)]
+    pub emit: Emit,
+
+    /// Write the disassembly of the functions whose name contains FUNCTION for each flavor
+    /// to <target-dir>/asm/<target>, to check that the compiler used the CPU features.
+    /// Requires llvm-objdump or objdump (or OBJDUMP), the flavors must keep their symbols
+    #[clap(long, value_name = "FUNCTION", verbatim_doc_comment)]
+    pub emit_asm: Option<String>,
+
+    /// Flavor ranking used by the dispatcher at runtime
+    #[clap(long, value_name = "RANKING", value_enum, default_value_t)]
+    pub ranking: Ranking,
+
+    /// Build only the specified binary, can be repeated.
+    /// Required for packages with multiple binaries
+    #[clap(long, value_name = "NAME")]
+    pub bin: Vec<String>,
+
+    /// Global allocator of the dispatcher
+    #[clap(long, value_name = "ALLOC", value_enum, default_value_t)]
+    pub dispatcher_alloc: DispatcherAlloc,
+
+    /// Build the dispatcher as a static position-independent executable,
+    /// for hardened deployments. Fails on targets without static PIE support
+    #[clap(long, verbatim_doc_comment)]
+    pub dispatcher_static_pie: bool,
+
+    /// Keep building the other packages when one fails,
+    /// failures are reported at the end
+    #[clap(long)]
+    pub keep_going: bool,
+
+    /// Embed the build time, the git commit (GIT_COMMIT or `git rev-parse HEAD`)
+    /// and the rustc version in the fat binary, reported by `verify`.
+    /// The fat binary is then no longer byte-for-byte reproducible
+    #[clap(long, verbatim_doc_comment)]
+    pub embed_build_info: bool,
+
+    /// Do not build the flavor without extra CPU features, the flavor with the fewest
+    /// becomes the baseline. The fat binary fails on hosts that support no flavor.
+    /// For fleets where every host is known
+    #[clap(long, verbatim_doc_comment)]
+    pub no_fallback: bool,
+
+    /// Report why each configured flavor was kept or dropped as identical to another
+    #[clap(long)]
+    pub explain: bool,
+
+    /// Generate the patches between flavors faster, at the cost of a slightly larger fat binary.
+    /// Useful for large binaries where diffing dominates packing time
+    #[clap(long, verbatim_doc_comment)]
+    pub bsdiff_fast: bool,
+
+    /// Diff each flavor against the next one with fewer CPU features instead of the fallback,
+    /// e.g. v4 against v3 against v2. Smaller fat binaries for graduated feature sets,
+    /// the dispatcher applies several patches to rebuild the higher flavors
+    #[clap(long, verbatim_doc_comment)]
+    pub delta_chain: bool,
+
+    /// Comma-separated CPU features the fat binary does not trust in virtual machines,
+    /// e.g. "avx512f" for hypervisors that advertise it but trap its instructions.
+    /// Flavors using them are skipped on hosts with the CPUID hypervisor bit (x86 only)
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_delimiter = ',',
+        value_name = "CPUFEATURES",
+        verbatim_doc_comment
+    )]
+    pub hypervisor_ignore_features: Vec<String>,
+
+    /// Build the flavors with debug info, split it into `<flavor>.debug` files
+    /// copied to <out-dir>/debug, and embed the stripped flavors.
+    /// ELF targets only, requires objcopy (or OBJCOPY)
+    #[clap(long, verbatim_doc_comment)]
+    pub split_debuginfo: bool,
+
+    /// Strip the fat binary, the embedded flavors are data and are not affected
+    #[clap(long, value_name = "STRIP", value_enum, default_value_t)]
+    pub strip_fatbin: StripFatbin,
+
+    /// Lockfile of the dispatcher crate, its dependencies are built with --locked.
+    /// Start from <target-dir>/multiarch-dispatch-autogen/Cargo.lock of a previous build
+    #[clap(long, value_name = "PATH", verbatim_doc_comment)]
+    pub dispatcher_lockfile: Option<PathBuf>,
+
+    /// Copy the sources of the dispatcher of each fat binary to DIR/<bin>, including the
+    /// `fatbin.rs` generated by its build script with the embedded feature lists, for inspection
+    #[clap(long, value_name = "DIR", verbatim_doc_comment)]
+    pub emit_dispatcher_src: Option<PathBuf>,
+
+    /// Build the dispatcher in a per-user cache shared across projects,
+    /// so that it and its dependencies are compiled once per cargo-multiarch version and target.
+    /// Concurrent invocations wait for each other while the dispatcher is built
+    #[clap(long, verbatim_doc_comment)]
+    pub shared_dispatcher_cache: bool,
+
+    /// Command run on each final binary before it is copied to --out-dir, e.g. "upx --best".
+    /// It is split on whitespace and receives the path of the binary as last argument
+    #[clap(long, value_name = "CMD", verbatim_doc_comment)]
+    pub post_process: Option<String>,
+
+    /// Build for both x86_64 and aarch64 macOS and combine the fat binaries
+    /// into a Mach-O universal binary with lipo (or LIPO).
+    /// The OS loader picks the architecture, the dispatcher the flavor
+    #[clap(long, verbatim_doc_comment)]
+    pub macho_universal: bool,
+
     /// Copy final artifacts to this directory
     #[clap(short, long, value_name = "PATH")]
     pub out_dir: Option<PathBuf>,
 
+    /// Pack the final binaries, their flavor manifests and the split debug info
+    /// into a zstd-compressed tarball, e.g. app.tar.zst, with a MANIFEST.json
+    #[clap(long, value_name = "PATH", verbatim_doc_comment)]
+    pub bundle: Option<PathBuf>,
+
+    /// Fail instead of warning when --out-dir is in the git working tree and not ignored
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Layout of the artifacts copied to --out-dir
+    #[clap(long, value_name = "LAYOUT", value_enum, default_value_t)]
+    pub out_layout: OutLayout,
+
+    /// Working directory of cargo-multiarch for flavors, dispatcher and outputs.
+    /// Defaults to <cargo target dir>/cargo-multiarch
+    /// This is distinct from cargo's own --target-dir
+    /// which can be passed after `--`
+    #[clap(long, value_name = "PATH", verbatim_doc_comment)]
+    pub target_dir: Option<PathBuf>,
+
     /// Build artifacts with the specified cargo profile
     /// Built-in profiles are dev, release, test, and bench
     #[clap(long, value_name = "PROFILE", default_value = "release")]
     pub profile: String,
 
+    /// Subdirectory of the target directory where cargo outputs the --profile artifacts.
+    /// Defaults to debug for dev and test, release for release and bench,
+    /// and the profile name for custom profiles
+    #[clap(long, value_name = "DIR", verbatim_doc_comment)]
+    pub profile_dir: Option<String>,
+
+    /// Standalone TOML file with the same structure as [package.metadata.multiarch]
+    /// for example a `[x86_64]` table with `cpus` and `cpufeatures`.
+    /// It overrides the package metadata and is overridden by --cpus and --cpufeatures
+    #[clap(long, value_name = "PATH", verbatim_doc_comment)]
+    pub config: Option<PathBuf>,
+
+    /// Adds a built-in flavor matrix to the configured one, the fallback is built as usual
+    #[clap(long, value_name = "PRESET", value_enum)]
+    pub preset: Option<Preset>,
+
+    /// Cargo config override forwarded as `--config KEY=VALUE` to every cargo build,
+    /// including the dispatcher. Can be repeated
+    #[clap(long, value_name = "KEY=VALUE", verbatim_doc_comment)]
+    pub cargo_config: Vec<String>,
+
+    /// Linker for the target, set as `target.<triple>.linker` for the flavor builds
+    /// and the dispatcher build, like a `[target.<triple>] linker` entry of .cargo/config.toml
+    #[clap(long, value_name = "PATH", verbatim_doc_comment)]
+    pub linker: Option<PathBuf>,
+
+    /// Report how long each flavor took to compile and each fat binary to pack,
+    /// as JSON and HTML in PATH, defaults to <target-dir>/timings
+    #[clap(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        require_equals = true,
+        verbatim_doc_comment
+    )]
+    pub timings: Option<Option<PathBuf>>,
+
+    /// After packing, run the fat binary once per flavor the host supports, with ARG as
+    /// its only argument, and fail if a flavor does not load. Defaults to --version.
+    /// Skipped when cross-compiling
+    #[clap(
+        long,
+        value_name = "ARG",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "--version",
+        allow_hyphen_values = true,
+        verbatim_doc_comment
+    )]
+    pub selftest: Option<String>,
+
+    /// Kill the build of a flavor if it takes longer than this
+    #[clap(long, value_name = "SECONDS")]
+    pub build_timeout: Option<u64>,
+
+    /// Fail if a fat binary is larger than SIZE, in bytes or with a unit like 50MiB or 2MB,
+    /// and report the size each flavor takes in it
+    #[clap(long, value_name = "SIZE", value_parser = parse_size, verbatim_doc_comment)]
+    pub max_fatbin_size: Option<u64>,
+
     /// Comma-separated list of CPUs, a binary will be build for each.
     /// This overwrites Cargo.toml CPUs
     #[clap(
@@ -65,6 +486,16 @@ pub(crate) struct Args {
     )]
     pub cpus: Option<Vec<String>>,
 
+    /// File listing the CPUs of a fleet, one rustc CPU name per line, `#` starts a comment.
+    /// Added to --cpus, a binary is built for each distinct feature set.
+    /// Unknown CPUs are skipped with a warning
+    #[clap(long, value_name = "FILE", verbatim_doc_comment)]
+    pub cpus_from: Option<PathBuf>,
+
+    /// How --cpus and --cpus-from combine with the configured CPUs
+    #[clap(long, value_name = "MODE", value_enum, default_value_t)]
+    pub cpus_mode: OverrideMode,
+
     /// A list of cpufeatures to support.
     /// When building from the CLI,
     /// it is not possible to set multiple cpufeatures based build
@@ -80,6 +511,10 @@ pub(crate) struct Args {
     )]
     pub cpufeatures: Option<Vec<String>>,
 
+    /// How --cpufeatures combines with the configured CPU features lists
+    #[clap(long, value_name = "MODE", value_enum, default_value_t)]
+    pub cpufeatures_mode: OverrideMode,
+
     #[command(flatten)]
     pub manifest: clap_cargo::Manifest,
 
@@ -93,3 +528,40 @@ pub(crate) struct Args {
     #[clap(raw = true)]
     pub args: Vec<String>,
 }
+
+/// Parses a size in bytes, with an optional decimal (kB, MB, GB) or binary (KiB, MiB, GiB) unit
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size `{size}`, expected e.g. 1048576, 500kB or 50MiB"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "unknown size unit `{unit}`, expected B, kB, MB, GB, KiB, MiB or GiB"
+            ))
+        }
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses an architecture named like the config tables, unknown ones are rejected
+fn parse_arch(arch: &str) -> Result<Architecture, String> {
+    match Architecture::from_str(arch) {
+        Ok(Architecture::Unknown) | Err(_) => Err(format!(
+            "unknown architecture `{arch}`, expected e.g. x86_64, aarch64 or riscv64gc"
+        )),
+        Ok(arch) => Ok(arch),
+    }
+}
@@ -1,194 +1,1176 @@
+use std::cell::{OnceCell, RefCell};
 use std::collections::BTreeSet;
-use std::ffi::OsString;
-use std::fs;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Context};
-use cargo_metadata::{Metadata, Package};
-use clap_cargo;
+use cargo_metadata::{Metadata, Package, Target};
 use console::{style, Term};
 use escargot::CargoBuild;
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use serde::Serialize;
 use sha2::{Digest, Sha256};
-use target_lexicon::{Environment, Triple};
+use target_lexicon::{Architecture, BinaryFormat, Environment, OperatingSystem, Triple};
 
-use crate::cargo_config_loader::{ConfigMultiArch, CpuFeatures};
-use crate::cargo_msg_parser::CommandMessagesExt;
-use crate::cli::Args;
-use crate::gen_fatbin_pkg::FatbinCrate;
-use crate::rustc_queries::Rustc;
+use crate::bundle::Bundle;
+use crate::cargo_config_loader::{ConfigMultiArch, CpuFeatures, Flavor};
+use crate::cargo_msg_parser::CargoBuildExt;
+use crate::cli::{
+    AddFlavorArgs, Args, DisasmArgs, Emit, OutLayout, OverrideMode, PackArgs, Preset, Ranking,
+};
+use crate::gen_fatbin_pkg::{DispatcherOptions, EmbeddedSize, FatbinBuild, FatbinCrate};
+use crate::gen_launcher_script;
+use crate::interrupt::{self, PartialFile};
+use crate::oci;
+use crate::ranking;
+use crate::rustc_queries::{Rustc, RustcQueries};
+use crate::selftest::{self, Probe};
+use crate::timings::Timings;
+use crate::unpack_fatbin;
 
 #[derive(Serialize)]
 struct BinaryDesc {
     path: PathBuf,
     // Empty for the default fallback binary
     cpufeatures: Vec<String>,
+    // Skipped by the dispatcher on hosts with an older glibc
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_glibc: Option<String>,
+    // Selected by the dispatcher over the ranked flavors, the highest first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<u32>,
     #[serde(skip)]
     original_filename: Option<OsString>,
+    #[serde(skip)]
+    description: String, // Configured flavor, for --explain
+    // Source revision the flavor was built from, if known
+    #[serde(skip)]
+    fingerprint: Option<String>,
 }
 
 #[derive(Default, Serialize)]
 struct Artifacts {
     bins: Vec<BinaryDesc>,
+    #[serde(skip)]
+    fallback_hash: Option<[u8; 32]>,
+    // Why each configured flavor was kept or dropped, in configuration order
+    #[serde(skip)]
+    verdicts: Vec<String>,
+}
+/// Built by `compile_pkg` for the binary targets of a package
+#[derive(Default)]
+struct PkgBuilds {
+    fallbacks: Vec<(String, [u8; 32])>, // Hash of the fallback by binary name
+    executables: Vec<(OsString, PathBuf)>, // Final executables by file name
 }
 pub(crate) struct Multiarch {
     metadata: Metadata,
-    target: Triple,      // CPU target
-    target_dir: PathBuf, // Rust compilation /target directory
+    rustc: Rc<dyn RustcQueries>,         // Target and CPU queries
+    target: Triple,                      // CPU target
+    config_arch: Architecture,           // Selects the config table, --arch or the target's
+    target_name: String,                 // --target given to rustc and cargo
+    baseline_features: BTreeSet<String>, // CPU features enabled by default on the target
+    target_dir: PathBuf,                 // Rust compilation /target directory
     outdir: Option<PathBuf>,
+    out_layout: OutLayout,
     fatbin: FatbinCrate,
+    emit: Emit,
+    emit_asm: Option<(String, OsString)>, // --emit-asm function and the disassembler
+    ranking: Ranking,
+    dispatcher_target: Option<String>, // Defaults to the flavors target
     workspace: clap_cargo::Workspace,
     pkg_features: clap_cargo::Features, // passed to cargo as --features <list> like --features derive
+    config_file: Option<PathBuf>, // Standalone multiarch config, overrides the package metadata
+    preset: Option<Preset>,
     override_cpus: BTreeSet<String>,
     override_cpufeatures: CpuFeatures,
+    cpus_mode: OverrideMode,
+    cpufeatures_mode: OverrideMode,
     progress: ProgressBar,
+    interactive: bool, // false in CI logs and pipes, the progress bar is replaced by plain lines
+    build_timeout: Option<Duration>, // Per flavor
+    max_fatbin_size: Option<u64>, // Bytes
+    post_process: Option<String>, // Run on final binaries, e.g. a packer or a signing tool
     profile: String,
     profile_dir: String,
     cargo_args: Vec<String>,
+    cargo_config: Vec<String>, // --config KEY=VALUE overrides
+    bins: Vec<String>,         // Selected binary targets, all if empty
+    keep_going: bool,          // Build all packages before reporting failures
+    explain: bool,             // Report why flavors are kept or dropped
+    no_fallback: bool,         // The flavor with the fewest features is the baseline
+    split_debuginfo: bool,     // Flavors are stripped, their debug info kept aside
+    embed_build_info: bool,    // Not reproducible, off by default
+    // Hash of the workspace sources, computed once and recorded next to each flavor
+    source_fingerprint: OnceCell<String>,
+    timings: Timings,
+    timings_dir: Option<PathBuf>, // --timings report, none if not requested
+    selftest: Option<String>,     // Argument the packed flavors are run with
+    bundle: Bundle,
+    bundle_path: Option<PathBuf>, // --bundle archive, none if not requested
+    // Printed as they occur and repeated at the end of the build
+    warnings: RefCell<Vec<String>>,
 }
 
 struct CompilationConfig<'a> {
     binary_name: &'a str,
     cargo_toml: &'a Path,
-    rust_flags: &'a str,
+    rust_flags: &'a [String],
     pkg_features: &'a str,
+    source_fingerprint: Option<&'a str>,
 }
 
 impl Multiarch {
     pub(crate) fn from_args(args: Args) -> anyhow::Result<Self> {
+        Self::from_args_with_rustc(args, Rc::new(Rustc))
+    }
+
+    /// Like [`Self::from_args`], with the target and CPU queries answered by `rustc`
+    pub(crate) fn from_args_with_rustc(
+        args: Args,
+        rustc: Rc<dyn RustcQueries>,
+    ) -> anyhow::Result<Self> {
         let metadata = args
             .manifest
             .metadata()
             .exec()
             .context("Failed to execute `cargo metadata`")?;
+        Self::from_metadata(args, metadata, rustc)
+    }
 
-        let target = Rustc::target_triple_or_host(args.target.as_deref()).and_then(|triple| {
-            Triple::from_str(&triple)
-                .map_err(|e| anyhow!("Error while parsing target triple '{triple}': {e}"))
-        })?;
-        let override_cpus: BTreeSet<String> =
+    /// Like [`Self::from_args_with_rustc`], for the workspace described by `metadata`
+    /// instead of running `cargo metadata`
+    pub(crate) fn from_metadata(
+        args: Args,
+        metadata: cargo_metadata::Metadata,
+        rustc: Rc<dyn RustcQueries>,
+    ) -> anyhow::Result<Self> {
+        // Forwarded as is to rustc and cargo, a target triple or the path to a custom target spec
+        let target_name = rustc.target_triple_or_host(args.target.as_deref())?;
+        let target = if is_target_spec(&target_name) {
+            triple_from_target_spec(Path::new(&target_name))
+        } else {
+            Triple::from_str(&target_name)
+                .map_err(|e| anyhow!("Error while parsing target triple '{target_name}': {e}"))
+        };
+        let target = match (target, args.arch) {
+            (Ok(target), _) => target,
+            // A custom target unknown to target-lexicon, only its architecture is needed
+            (Err(_), Some(architecture)) => Triple {
+                architecture,
+                ..Triple::unknown()
+            },
+            (Err(e), None) => anyhow::bail!("{e:#}, pass --arch to name its architecture"),
+        };
+        let config_arch = args.arch.unwrap_or(target.architecture);
+        if let Some(dispatcher_target) = args.dispatcher_target.as_deref() {
+            let dispatcher_triple = if is_target_spec(dispatcher_target) {
+                triple_from_target_spec(Path::new(dispatcher_target))?
+            } else {
+                Triple::from_str(dispatcher_target).map_err(|e| {
+                    anyhow!(
+                        "Error while parsing dispatcher target triple '{dispatcher_target}': {e}"
+                    )
+                })?
+            };
+            FatbinCrate::check_dispatcher_target(&dispatcher_triple, &target)?;
+        }
+        anyhow::ensure!(
+            !args.split_debuginfo || target.binary_format == BinaryFormat::Elf,
+            "--split-debuginfo only supports ELF targets, '{target_name}' is not"
+        );
+        anyhow::ensure!(
+            !is_wasm(&target) || (args.emit == Emit::Fatbin && args.dispatcher_target.is_none()),
+            "WebAssembly flavors are emitted with a selection manifest, --emit and --dispatcher-target do not apply to '{target_name}'"
+        );
+        let baseline_features = rustc.get_effective_cpufeatures(&target_name, "")?;
+        let mut override_cpus: BTreeSet<String> =
             args.cpus.iter().flat_map(ToOwned::to_owned).collect();
+        let mut warnings = Vec::new();
+        if let Some(path) = args.cpus_from.as_deref() {
+            override_cpus.extend(read_cpu_inventory(
+                rustc.as_ref(),
+                path,
+                &target_name,
+                &mut warnings,
+            )?);
+        }
         let override_cpufeatures: CpuFeatures = args
             .cpufeatures
             .iter()
             .flat_map(ToOwned::to_owned)
             .collect();
 
-        // Rust <project root>/target
-        let target_dir = metadata
-            .target_directory
-            .join(clap::crate_name!())
-            .into_std_path_buf();
+        // Rust <project root>/target/cargo-multiarch unless overridden
+        let target_dir = match args.target_dir {
+            Some(target_dir) => std::path::absolute(&target_dir)
+                .with_context(|| format!("Invalid target directory `{}`", target_dir.display()))?,
+            None => metadata
+                .target_directory
+                .join(clap::crate_name!())
+                .into_std_path_buf(),
+        };
 
-        let fatbin = FatbinCrate::generate(target_dir.clone())?;
+        if let Some(out_dir) = args.out_dir.as_deref() {
+            if let Some(worktree) =
+                unignored_in_worktree(out_dir, metadata.workspace_root.as_std_path())
+            {
+                let warning = format!(
+                    "--out-dir `{}` is in the git working tree `{}` and not ignored, the binaries could be committed",
+                    out_dir.display(),
+                    worktree.display()
+                );
+                anyhow::ensure!(!args.strict, "{warning}");
+                eprintln!("{:>12} {warning}", style("Warning").bold().yellow());
+                warnings.push(warning);
+            }
+        }
 
-        let progress = indicatif::ProgressBar::new(0).with_style(
-            ProgressStyle::with_template(
-                "{prefix:>12.cyan.bold} [{bar:57}] {pos}/{len} {spinner}",
-            )?
-            .progress_chars("=> "),
-        );
-        progress.enable_steady_tick(Duration::from_millis(200));
+        // Fails early rather than after the builds
+        let emit_asm = args
+            .emit_asm
+            .map(|function| anyhow::Ok((function, crate::disasm::find_disassembler()?)))
+            .transpose()?;
 
-        let profile_dir = if args.profile == "dev" {
-            "debug"
-        } else {
-            &args.profile
+        let mut cargo_config = args.cargo_config;
+        if let Some(linker) = args.linker.as_deref() {
+            // Applied after the user overrides so that the flag wins
+            cargo_config.extend(linker_config(
+                linker,
+                [
+                    Some(target_name.as_str()),
+                    args.dispatcher_target.as_deref(),
+                ]
+                .into_iter()
+                .flatten()
+                .unique(),
+            )?);
         }
-        .to_owned();
+
+        let timings_dir = args
+            .timings
+            .map(|dir| dir.unwrap_or_else(|| target_dir.join("timings")));
+
+        // Normalized like configured features, to match the names embedded for the flavors
+        let hypervisor_ignore_features: CpuFeatures =
+            args.hypervisor_ignore_features.iter().cloned().collect();
+        let fatbin = FatbinCrate::generate(
+            target_dir.clone(),
+            DispatcherOptions {
+                ranking: args.ranking,
+                alloc: args.dispatcher_alloc,
+                bsdiff_fast: args.bsdiff_fast,
+                delta_chain: args.delta_chain,
+                hypervisor_ignore_features: hypervisor_ignore_features.iter().cloned().collect(),
+                strip: args.strip_fatbin,
+                static_pie: args.dispatcher_static_pie,
+            },
+            args.dispatcher_lockfile.as_deref(),
+            args.shared_dispatcher_cache,
+            args.emit_dispatcher_src,
+        )?;
+
+        // The progress bar is drawn on stderr
+        let interactive = Term::stderr().is_term();
+        let progress = if interactive {
+            let progress = indicatif::ProgressBar::new(0).with_style(
+                ProgressStyle::with_template(
+                    "{prefix:>12.cyan.bold} [{bar:57}] {pos}/{len} {spinner}",
+                )?
+                .progress_chars("=> "),
+            );
+            progress.enable_steady_tick(Duration::from_millis(200));
+            progress
+        } else {
+            ProgressBar::hidden()
+        };
+        interrupt::install(&progress)?;
+
+        // Cargo outputs the built-in profiles to debug and release, custom profiles to their name
+        let profile_dir = args.profile_dir.unwrap_or_else(|| {
+            match args.profile.as_str() {
+                "dev" | "test" => "debug",
+                "bench" => "release",
+                profile => profile,
+            }
+            .to_owned()
+        });
 
         Ok(Self {
             metadata,
+            rustc,
             target,
+            config_arch,
+            target_name,
+            baseline_features,
             target_dir,
             outdir: args.out_dir,
+            out_layout: args.out_layout,
             fatbin,
+            emit: args.emit,
+            emit_asm,
+            ranking: args.ranking,
+            dispatcher_target: args.dispatcher_target,
             workspace: args.workspace,
             pkg_features: args.features,
+            config_file: args.config,
+            preset: args.preset,
             override_cpus,
             override_cpufeatures,
+            cpus_mode: args.cpus_mode,
+            cpufeatures_mode: args.cpufeatures_mode,
             progress,
+            interactive,
             cargo_args: args.args,
+            cargo_config,
+            bins: args.bin,
+            keep_going: args.keep_going,
+            explain: args.explain,
+            no_fallback: args.no_fallback,
+            split_debuginfo: args.split_debuginfo,
+            source_fingerprint: OnceCell::new(),
+            timings: Timings::default(),
+            timings_dir,
+            selftest: args.selftest,
+            bundle: Bundle::default(),
+            bundle_path: args.bundle,
+            warnings: RefCell::new(warnings),
+            embed_build_info: args.embed_build_info,
+            post_process: args.post_process,
+            build_timeout: args.build_timeout.map(Duration::from_secs),
+            max_fatbin_size: args.max_fatbin_size,
             profile: args.profile,
             profile_dir,
         })
     }
 
-    pub fn compile_workspace(&self) -> anyhow::Result<()> {
+    /// Builds the workspace for x86_64 and aarch64 macOS
+    /// and combines each pair of final binaries into a Mach-O universal binary
+    pub fn compile_macho_universal(args: Args) -> anyhow::Result<()> {
+        const TARGETS: [&str; 2] = ["x86_64-apple-darwin", "aarch64-apple-darwin"];
+        anyhow::ensure!(
+            args.target.is_none(),
+            "--macho-universal builds for {}, it cannot be combined with --target",
+            TARGETS.join(" and ")
+        );
+        anyhow::ensure!(
+            args.command.is_none()
+                && args.emit == Emit::Fatbin
+                && args.dispatcher_target.is_none()
+                && args.arch.is_none(),
+            "--macho-universal cannot be combined with `pack`, --emit, --dispatcher-target or --arch"
+        );
+        let lipo = std::env::var_os("LIPO").unwrap_or_else(|| "lipo".into());
+        Command::new(&lipo)
+            .arg("-info")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .with_context(|| {
+                format!(
+                    "`{}` is required by --macho-universal, install the Xcode command line tools or set LIPO",
+                    lipo.to_string_lossy()
+                )
+            })?;
+
+        // Per-architecture builds are only post-processed and copied to --out-dir once combined
+        let mut per_arch = Vec::with_capacity(TARGETS.len());
+        for target in TARGETS {
+            let mut args = args.clone();
+            args.target = Some(target.to_owned());
+            args.out_dir = None;
+            args.post_process = None;
+            args.bundle = None;
+            per_arch.push(Self::from_args(args)?.compile_workspace()?);
+        }
+
+        let universal = Self::from_args(args)?;
+        let [x86_64, aarch64] = &per_arch[..] else {
+            unreachable!("built for two targets");
+        };
+        for (pkg_name, original_filename, x86_64_path) in x86_64 {
+            let Some((_, _, aarch64_path)) = aarch64
+                .iter()
+                .find(|(pkg, filename, _)| pkg == pkg_name && filename == original_filename)
+            else {
+                continue;
+            };
+            let output_dir = universal
+                .target_dir
+                .join("universal-apple-darwin")
+                .join(&universal.profile_dir);
+            fs::create_dir_all(&output_dir).with_context(|| {
+                format!("Failed to create directory `{}`", output_dir.display())
+            })?;
+            let output_path = output_dir.join(original_filename);
+            let status = Command::new(&lipo)
+                .arg("-create")
+                .arg("-output")
+                .arg(&output_path)
+                .args([x86_64_path, aarch64_path])
+                .status()
+                .with_context(|| format!("Failed to run `{}`", lipo.to_string_lossy()))?;
+            anyhow::ensure!(
+                status.success(),
+                "`{}` failed to combine `{}` and `{}` ({status})",
+                lipo.to_string_lossy(),
+                x86_64_path.display(),
+                aarch64_path.display()
+            );
+            universal.post_process(&output_path)?;
+            universal.copy_to_out_dir(&output_path, original_filename, pkg_name)?;
+            universal.println(format!(
+                "{:>16} universal binary ({})",
+                style("Finished").green(),
+                output_path.display()
+            ));
+        }
+        universal.write_bundle()
+    }
+
+    /// Builds the selected binaries of the workspace.
+    /// Returns the final binaries, as (package name, file name, path), that are executables:
+    /// WebAssembly modules and launcher scripts are left out.
+    pub fn compile_workspace(&self) -> anyhow::Result<Vec<(String, OsString, PathBuf)>> {
         let (pkgs, _) = self.workspace.partition_packages(&self.metadata);
-        let pkgs: Vec<_> = pkgs
+        let pkgs: Vec<(&Package, Vec<&Target>)> = pkgs
             .iter()
-            .filter(|&pkg| pkg.targets.iter().any(|target| target.is_bin()))
-            .collect();
+            .map(|&pkg| self.select_bins(pkg).map(|bins| (pkg, bins)))
+            .filter_ok(|(_, bins)| !bins.is_empty())
+            .collect::<anyhow::Result<_>>()?;
 
         if pkgs.is_empty() {
             anyhow::bail!("cargo-multiarch can only build binaries.");
         }
+        if let Some(missing) = self.bins.iter().find(|&name| {
+            !pkgs
+                .iter()
+                .any(|(_, bins)| bins.iter().any(|bin| &bin.name == name))
+        }) {
+            anyhow::bail!("No binary target named `{missing}` in the selected packages");
+        }
+        if self.emit == Emit::Script {
+            anyhow::ensure!(
+                self.target.operating_system != OperatingSystem::Windows,
+                "`--emit script` generates a POSIX shell launcher and does not support Windows targets"
+            );
+        }
+        if self.emit == Emit::Oci {
+            anyhow::ensure!(
+                self.target.operating_system == OperatingSystem::Linux
+                    && oci::image_architecture(&self.target).is_some(),
+                "`--emit oci` generates Linux container images, '{}' is not a supported Linux target",
+                self.target_name
+            );
+        }
 
-        let num_packages: u64 = pkgs
-            .iter()
-            .map(|&pkg| pkg.targets.iter().filter(|target| target.is_bin()).count() as u64)
-            .sum();
+        if matches!(self.profile.as_str(), "test" | "bench") {
+            self.warn(12, format!(
+                "`--profile {}` is meant for test and benchmark harnesses, the fat binary packs the regular binaries built with its settings, consider `--profile release` or a custom profile",
+                self.profile,
+            ));
+        }
+
+        let num_bins: u64 = pkgs.iter().map(|(_, bins)| bins.len() as u64).sum();
 
-        self.progress.set_length(num_packages);
+        if self.outdir.is_some() && self.out_layout == OutLayout::Flat {
+            let bin_names = pkgs
+                .iter()
+                .flat_map(|(_, bins)| bins)
+                .map(|target| &target.name);
+            for name in bin_names.duplicates() {
+                self.warn(12, format!(
+                    "binary `{name}` is produced by multiple packages and will be overwritten in the output directory, consider `--out-layout per-package`"
+                ));
+            }
+        }
+
+        self.progress.set_length(num_bins);
         self.progress.set_prefix("Building");
 
-        self.progress.disable_steady_tick();
-        self.progress.set_style(
-            ProgressStyle::with_template(if Term::stdout().size().1 > 80 {
-                "{prefix:>12.cyan.bold} [{bar:57}] {pos}/{len} (time remaining {eta}) {wide_msg}"
-            } else {
-                "{prefix:>12.cyan.bold} [{bar:57}] {pos}/{len}"
-            })?
-            .progress_chars("=> "),
-        );
+        if self.interactive {
+            self.progress.disable_steady_tick();
+            self.progress.set_style(
+                ProgressStyle::with_template(if Term::stderr().size().1 > 80 {
+                    "{prefix:>12.cyan.bold} [{bar:57}] {pos}/{len} (time remaining {eta}) {wide_msg}"
+                } else {
+                    "{prefix:>12.cyan.bold} [{bar:57}] {pos}/{len}"
+                })?
+                .progress_chars("=> "),
+            );
+        }
 
-        for pkg in pkgs {
-            self.progress.println(format!(
+        let mut failures = Vec::new();
+        let mut fallbacks = Vec::new();
+        let mut produced = Vec::new();
+        for (pkg, bins) in pkgs {
+            self.println(format!(
                 "{:>12} {} v{} ({})",
                 style("Compiling").bold().green(),
                 pkg.name,
                 pkg.version,
                 self.metadata.workspace_root
             ));
-            self.compile_pkg(pkg)?;
-            self.progress.inc(1);
+            match self.compile_pkg(pkg, &bins) {
+                Ok(builds) => {
+                    fallbacks.extend(
+                        builds
+                            .fallbacks
+                            .into_iter()
+                            .map(|(bin_name, hash)| (hash, pkg, bin_name)),
+                    );
+                    produced.extend(
+                        builds
+                            .executables
+                            .into_iter()
+                            .map(|(filename, path)| (pkg.name.clone(), filename, path)),
+                    );
+                }
+                Err(e) if self.keep_going => {
+                    self.println(format!(
+                        "{:>12} {} v{}: {e:#}",
+                        style("Failed").bold().red(),
+                        pkg.name,
+                        pkg.version
+                    ));
+                    failures.push((pkg, e));
+                }
+                Err(e) => return Err(e),
+            }
         }
         self.progress.finish_and_clear();
+        self.write_timings()?;
+
+        // Informational, identical fallbacks hint that the code could be shared
+        for identical in fallbacks
+            .into_iter()
+            .into_group_map_by(|(hash, _, _)| *hash)
+            .into_values()
+            .filter(|identical| identical.iter().map(|(_, pkg, _)| &pkg.id).unique().count() > 1)
+        {
+            let bins = identical
+                .iter()
+                .map(|(_, pkg, bin_name)| format!("`{bin_name}` ({})", pkg.name))
+                .join(", ");
+            eprintln!(
+                "{:>12} {bins} have identical fallback builds, the packages could share a single binary",
+                style("Note").bold().cyan(),
+            );
+        }
+
+        self.print_warnings_summary();
+
+        if !failures.is_empty() {
+            let summary = failures
+                .iter()
+                .map(|(pkg, e)| format!("  {} v{}: {e:#}", pkg.name, pkg.version))
+                .join("\n");
+            anyhow::bail!("{} package(s) failed to build:\n{summary}", failures.len());
+        }
+        self.write_bundle()?;
+        Ok(produced)
+    }
+
+    /// Pack flavors built outside of cargo-multiarch into a fat binary,
+    /// they are deduplicated like built flavors.
+    pub fn pack(&self, pack: PackArgs) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.no_fallback,
+            "--no-fallback does not apply to `pack`, the fallback is given with --fallback"
+        );
+        anyhow::ensure!(
+            !is_wasm(&self.target),
+            "`pack` builds a native dispatcher, it does not apply to WebAssembly targets"
+        );
+        let original_filename: OsString = match pack.name {
+            Some(name) => name.into(),
+            None => pack
+                .fallback
+                .file_name()
+                .with_context(|| format!("Invalid fallback `{}`", pack.fallback.display()))?
+                .to_owned(),
+        };
+
+        let mut prebuilts = vec![(CpuFeatures::default(), pack.fallback, None, None)];
+        for flavor in &pack.flavor {
+            let (features, path) = flavor
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid flavor `{flavor}`, expected <FEATURES>=<PATH>"))?;
+            let cpufeatures: CpuFeatures = features.split(',').map(ToOwned::to_owned).collect();
+            anyhow::ensure!(
+                !cpufeatures.is_empty(),
+                "Flavor `{flavor}` has no CPU features, the flavor without features is given with --fallback"
+            );
+            prebuilts.push((cpufeatures, PathBuf::from(path), None, None));
+        }
+        self.pack_prebuilts(prebuilts, original_filename)?;
         Ok(())
     }
 
-    fn compile_pkg(&self, package: &Package) -> anyhow::Result<()> {
-        let cargo_toml = package.manifest_path.as_std_path();
-        let pkg_features = self.pkg_features.features.join(" ");
-        let mut rust_flags = std::env::var("RUSTFLAGS").unwrap_or_default();
+    /// Add a flavor built outside of cargo-multiarch to an existing fat binary,
+    /// its flavors are unpacked and repacked with the new one in place of the fat binary.
+    pub fn add_flavor(&self, add: AddFlavorArgs) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.no_fallback,
+            "--no-fallback does not apply to `add-flavor`, the fallback of the fat binary is kept"
+        );
+        anyhow::ensure!(
+            !is_wasm(&self.target),
+            "`add-flavor` builds a native dispatcher, it does not apply to WebAssembly targets"
+        );
+        let original_filename = add
+            .fatbin
+            .file_name()
+            .with_context(|| format!("Invalid fat binary `{}`", add.fatbin.display()))?
+            .to_owned();
+        let cpufeatures: CpuFeatures = add
+            .features
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .map(ToOwned::to_owned)
+            .collect();
+        anyhow::ensure!(
+            !cpufeatures.is_empty(),
+            "The flavor has no CPU features, the fat binary already has a fallback"
+        );
+        let effective: BTreeSet<String> = self
+            .effective_cpufeatures(&cpufeatures)?
+            .into_iter()
+            .collect();
+
+        let unpack_dir = self.target_dir.join("add-flavor").join(&original_filename);
+        let (options, flavors) = unpack_fatbin::unpack(&add.fatbin, &unpack_dir)?;
+        // The dispatcher is generated from the current options, not from the fat binary
+        let missing = options.missing_options(self.fatbin.options(), self.embed_build_info);
+        anyhow::ensure!(
+            missing.is_empty(),
+            "`{}` was built with other dispatcher options, run `add-flavor` with {}",
+            add.fatbin.display(),
+            missing.join(", ")
+        );
+        anyhow::ensure!(
+            flavors.iter().any(|flavor| flavor.cpufeatures.is_empty()),
+            "`{}` was built with --no-fallback, `add-flavor` needs its fallback to diff against",
+            add.fatbin.display()
+        );
+        if let Some(existing) = flavors
+            .iter()
+            .find(|flavor| flavor.cpufeatures.iter().cloned().collect::<BTreeSet<_>>() == effective)
+        {
+            anyhow::bail!(
+                "`{}` already has a flavor with [{}]",
+                add.fatbin.display(),
+                existing.cpufeatures.join(", ")
+            );
+        }
+
+        // The fallback first, the format of the new flavor is checked against it
+        let mut prebuilts: Vec<(CpuFeatures, PathBuf, Option<String>, Option<u32>)> = flavors
+            .into_iter()
+            .sorted_by_key(|flavor| !flavor.cpufeatures.is_empty())
+            .map(|flavor| {
+                let cpufeatures = flavor.cpufeatures.into_iter().collect();
+                (cpufeatures, flavor.path, flavor.min_glibc, flavor.priority)
+            })
+            .collect();
+        prebuilts.push((cpufeatures, add.from, None, None));
+        let fatbin = self.pack_prebuilts(prebuilts, original_filename)?;
+
+        fs::copy(&fatbin, &add.fatbin).with_context(|| {
+            format!(
+                "Failed to copy `{}` to `{}`",
+                fatbin.display(),
+                add.fatbin.display()
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Packs prebuilt flavors, the fallback first, as (CPU features, path, min_glibc, priority).
+    /// Returns the path of the final binary.
+    fn pack_prebuilts(
+        &self,
+        prebuilts: Vec<(CpuFeatures, PathBuf, Option<String>, Option<u32>)>,
+        original_filename: OsString,
+    ) -> anyhow::Result<PathBuf> {
+        let fallback_format = executable_format(&prebuilts[0].1)?;
+        let mut binaries_desc: Vec<([u8; 32], BinaryDesc)> = Vec::with_capacity(prebuilts.len());
+        for (cpufeatures, path, min_glibc, priority) in prebuilts {
+            let format = executable_format(&path)?;
+            anyhow::ensure!(
+                format == fallback_format,
+                "`{}` is a {format} executable but the fallback is a {fallback_format} executable",
+                path.display()
+            );
+            // The dispatcher is built from another directory
+            let path = fs::canonicalize(&path)
+                .with_context(|| format!("Failed to resolve `{}`", path.display()))?;
+            let hash = fs::read(&path)
+                .map(Sha256::digest)
+                .with_context(|| format!("Failed to read `{}`", path.display()))?;
+
+            // Recorded when the flavor was built by cargo-multiarch
+            let fingerprint = fs::read_to_string(fingerprint_path(&path))
+                .ok()
+                .map(|fingerprint| fingerprint.trim().to_owned());
+            let desc = BinaryDesc {
+                path,
+                cpufeatures: self.effective_cpufeatures(&cpufeatures)?,
+                min_glibc,
+                priority,
+                original_filename: None,
+                description: Flavor::from(cpufeatures).describe(),
+                fingerprint,
+            };
+            binaries_desc.push((hash.into(), desc));
+        }
 
-        let cargo_config = ConfigMultiArch::new(self.target.clone())
-            .load_cargo_toml(package)
-            .and_then(|cfg| cfg.override_cpus(self.override_cpus.clone()))
-            .and_then(|cfg| {
-                cfg.override_features_lists(BTreeSet::from([self.override_cpufeatures.clone()]))
+        let artifacts = dedup_binaries(binaries_desc);
+        self.explain(&artifacts);
+        let bin_name = original_filename.to_string_lossy().into_owned();
+        let final_path = if let [lone] = &artifacts.bins[..] {
+            // No dispatcher needed, for example for a binary that multiversions its hot
+            // functions and dispatches them itself. Staged as the final binary is moved
+            let staged_dir = self.target_dir.join(&bin_name);
+            fs::create_dir_all(&staged_dir).with_context(|| {
+                format!("Failed to create directory `{}`", staged_dir.display())
+            })?;
+            let staged = staged_dir.join(&original_filename);
+            fs::copy(&lone.path, &staged).with_context(|| {
+                format!(
+                    "Failed to copy `{}` to `{}`",
+                    lone.path.display(),
+                    staged.display()
+                )
             })?;
+            self.handle_single_arch(&staged, original_filename, &bin_name)?
+        } else {
+            self.handle_multi_arch(&artifacts, original_filename, &bin_name, &bin_name)?
+        };
+        self.progress.finish_and_clear();
+        self.write_timings()?;
+        self.write_bundle()?;
+        Ok(final_path)
+    }
 
-        let cpu_features = cargo_config.get_cpu_features();
+    /// Hash of the sources and manifests of the workspace packages and of the lockfile,
+    /// see [`source_fingerprint`]. Data files next to the packages are not hashed.
+    fn source_fingerprint(&self) -> &str {
+        if let Some(fingerprint) = self.source_fingerprint.get() {
+            return fingerprint;
+        }
+        let workspace_root = self.metadata.workspace_root.as_std_path();
+        let mut inputs = vec![
+            workspace_root.join("Cargo.toml"),
+            workspace_root.join("Cargo.lock"),
+        ];
+        for package in self.metadata.workspace_packages() {
+            let manifest = package.manifest_path.as_std_path();
+            inputs.push(manifest.to_owned());
+            for target in &package.targets {
+                let src_path = target.src_path.as_std_path();
+                match src_path.parent() {
+                    // e.g. build.rs, the package directory is not only sources
+                    Some(dir) if Some(dir) == manifest.parent() => inputs.push(src_path.to_owned()),
+                    Some(dir) => inputs.push(dir.to_owned()),
+                    None => inputs.push(src_path.to_owned()),
+                }
+            }
+        }
+        let (fingerprint, unreadable) = source_fingerprint(workspace_root, inputs);
+        for path in unreadable {
+            self.println(format!(
+                "{:>16} `{}` is not readable, it is left out of the source fingerprint",
+                style("Warning").bold().yellow(),
+                path.display()
+            ));
+        }
+        self.source_fingerprint.get_or_init(|| fingerprint)
+    }
 
+    /// Rustflags of the environment shared by all flavors
+    fn base_rustflags(&self) -> Vec<String> {
+        let mut rust_flags = Rustc::get_env_rustflags();
         if self.target.environment == Environment::Msvc {
-            rust_flags.push_str(" -C link-args=/Brepro");
+            rust_flags.push("-Clink-args=/Brepro".to_owned());
+        };
+        rust_flags
+    }
+
+    /// Build two flavors of the selected binary and print the diff of their disassembly
+    pub fn disasm(&self, disasm: DisasmArgs) -> anyhow::Result<()> {
+        // Fails early rather than after two builds
+        let disassembler = crate::disasm::find_disassembler()?;
+
+        let (pkgs, _) = self.workspace.partition_packages(&self.metadata);
+        let bins: Vec<(&Package, &Target)> = pkgs
+            .iter()
+            .map(|&pkg| {
+                self.select_bins(pkg)
+                    .map(|bins| bins.into_iter().map(move |bin| (pkg, bin)))
+            })
+            .flatten_ok()
+            .collect::<anyhow::Result<_>>()?;
+        let [(package, bin_target)] = bins[..] else {
+            anyhow::bail!(
+                "`disasm` compares a single binary, select it with --package and --bin <NAME>\nAvailable binaries: {}",
+                bins.iter().map(|(_, bin)| &bin.name).join(", ")
+            );
+        };
+
+        let pkg_features = self.pkg_features.features.join(" ");
+        let rust_flags = self.base_rustflags();
+        let cfg = CompilationConfig {
+            binary_name: &bin_target.name,
+            cargo_toml: package.manifest_path.as_std_path(),
+            rust_flags: &rust_flags,
+            pkg_features: &pkg_features,
+            source_fingerprint: None,
+        };
+
+        let disasm_dir = self.target_dir.join("disasm");
+        fs::create_dir_all(&disasm_dir)
+            .with_context(|| format!("Failed to create directory `{}`", disasm_dir.display()))?;
+        let mut listings = Vec::with_capacity(2);
+        for (step, features) in [&disasm.flavor_a, &disasm.flavor_b].into_iter().enumerate() {
+            let cpufeatures: CpuFeatures = features
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(ToOwned::to_owned)
+                .collect();
+            let flavor = Flavor::from(cpufeatures);
+            let (_, desc) = self.compile_bin(&cfg, &flavor, (step + 1, 2))?;
+
+            let listing =
+                crate::disasm::disassemble(&disassembler, &desc.path, disasm.function.as_deref())?;
+            // Appended, flavor names such as `-sse4.1` contain dots
+            let mut file_name = desc.path.file_name().unwrap_or_default().to_owned();
+            file_name.push(".s");
+            let listing_path = disasm_dir.join(file_name);
+            fs::write(&listing_path, listing)
+                .with_context(|| format!("Failed to write `{}`", listing_path.display()))?;
+            listings.push(listing_path);
+        }
+        self.progress.finish_and_clear();
+
+        let diff = crate::disasm::unified_diff(&listings[0], &listings[1])?;
+        if diff.is_empty() {
+            eprintln!(
+                "{:>12} the disassembly of both flavors is identical",
+                style("Identical").green().bold()
+            );
+        } else {
+            print!("{diff}");
+        }
+        Ok(())
+    }
+
+    /// Writes the --emit-asm disassembly of a flavor, if requested
+    fn emit_asm(&self, desc: &BinaryDesc) -> anyhow::Result<()> {
+        let Some((function, disassembler)) = &self.emit_asm else {
+            return Ok(());
+        };
+        let asm_dir = self.target_dir.join("asm").join(self.target.to_string());
+        fs::create_dir_all(&asm_dir)
+            .with_context(|| format!("Failed to create directory `{}`", asm_dir.display()))?;
+        let listing = crate::disasm::disassemble(disassembler, &desc.path, Some(function))?;
+        // Appended, flavors such as `-sse4.1` and `-sse4.2` would overwrite each other's listing
+        let mut file_name = desc.path.file_name().unwrap_or_default().to_owned();
+        file_name.push(".s");
+        let listing_path = asm_dir.join(file_name);
+        fs::write(&listing_path, listing)
+            .with_context(|| format!("Failed to write `{}`", listing_path.display()))?;
+        self.println(format!(
+            "{:>20} {}",
+            style("Disassembled").green(),
+            listing_path.display()
+        ));
+        Ok(())
+    }
+
+    /// Writes the --timings report, if requested
+    fn write_timings(&self) -> anyhow::Result<()> {
+        let Some(dir) = self.timings_dir.as_deref() else {
+            return Ok(());
+        };
+        let report = self.timings.write_report(dir)?;
+        eprintln!(
+            "{:>12} report saved to {}",
+            style("Timings").green().bold(),
+            report.display()
+        );
+        Ok(())
+    }
+
+    fn write_bundle(&self) -> anyhow::Result<()> {
+        let Some(path) = self.bundle_path.as_deref() else {
+            return Ok(());
         };
+        self.bundle.write(path, &self.target_name, &self.profile)?;
+        eprintln!(
+            "{:>12} bundle saved to {}",
+            style("Bundled").green().bold(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// --explain the deduplication of flavors
+    fn explain(&self, artifacts: &Artifacts) {
+        if !self.explain {
+            return;
+        }
+        for verdict in &artifacts.verdicts {
+            self.println(format!("{:>20} {verdict}", style("Explain").cyan()));
+        }
+    }
+
+    /// Binary targets of a package to build.
+    /// Library targets are only built as dependencies, they are never packed.
+    /// A package with several binaries requires an explicit --bin selection.
+    fn select_bins<'a>(&self, package: &'a Package) -> anyhow::Result<Vec<&'a Target>> {
+        let bins: Vec<&Target> = package
+            .targets
+            .iter()
+            .filter(|target| target.is_bin())
+            .collect();
+
+        if !self.bins.is_empty() {
+            return Ok(bins
+                .into_iter()
+                .filter(|target| self.bins.contains(&target.name))
+                .collect());
+        }
+
+        if bins.len() > 1 {
+            anyhow::bail!(
+                "Package `{}` has multiple binaries, select which ones to build with --bin <NAME>\nAvailable binaries: {}",
+                package.name,
+                bins.iter().map(|target| &target.name).join(", ")
+            );
+        }
+        Ok(bins)
+    }
+
+    /// Lists the flavors each selected binary will be built for, including the fallback,
+    /// to gauge the build time before starting.
+    pub fn describe_build_plan(&self) -> anyhow::Result<String> {
+        let (pkgs, _) = self.workspace.partition_packages(&self.metadata);
+        let mut report = format!("Build plan for target '{}'", self.target_name);
+        let mut num_builds = 0;
+        for package in pkgs {
+            let bins = self.select_bins(package)?;
+            if bins.is_empty() {
+                continue;
+            }
+            let cargo_config = self.load_config(package)?;
+            let flavors = cargo_config.get_cpu_features();
+            let num_flavors = flavors.len() + usize::from(!self.no_fallback);
+            num_builds += num_flavors * bins.len();
+
+            report.push_str(&format!(
+                "\n{} v{} ({}): {num_flavors} flavor(s) per binary",
+                package.name,
+                package.version,
+                bins.iter().map(|bin| &bin.name).join(", ")
+            ));
+            if !self.no_fallback {
+                report.push_str("\n  default fallback");
+            }
+            for flavor in &flavors {
+                report.push_str(&format!("\n  {}", flavor.describe()));
+            }
+            for warning in cargo_config.find_redundant_flavors()? {
+                report.push_str(&format!("\n  note: {warning}"));
+            }
+        }
+        report.push_str(&format!("\nTotal: {num_builds} build(s)"));
+        Ok(report)
+    }
+
+    /// Simulates the flavor the dispatcher would select on a host with the CPU features
+    /// of `target_cpu` (the host CPU by default), without building anything.
+    pub fn describe_selected_flavor(&self, target_cpu: Option<&str>) -> anyhow::Result<String> {
+        let host_features: BTreeSet<String> = self
+            .rustc
+            .get_cpufeatures_for_programs(Some(&self.target_name), target_cpu)?
+            .into_iter()
+            .collect();
+
+        let (pkgs, _) = self.workspace.partition_packages(&self.metadata);
+        let mut report = format!(
+            "Selecting flavors for CPU '{}' on '{}'",
+            target_cpu.unwrap_or("native"),
+            self.target_name
+        );
+        for package in pkgs {
+            let cargo_config = self.load_config(package)?;
+
+            // Same order as the flavors embedded by the dispatcher
+            let mut flavors: Vec<(String, Vec<String>, Option<u32>)> = Vec::new();
+            for flavor in cargo_config.get_cpu_features() {
+                let cpufeatures = self.effective_cpufeatures(&flavor.cpufeatures)?;
+                if !cpufeatures.is_empty() {
+                    flavors.push((flavor.describe(), cpufeatures, flavor.priority));
+                }
+            }
+            flavors.sort_by(|(_, features1, _), (_, features2, _)| {
+                ranking::embedded_order(features1, features2)
+            });
+
+            let supported_indices: Vec<usize> = flavors
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, cpufeatures, _))| {
+                    cpufeatures
+                        .iter()
+                        .all(|feature| host_features.contains(feature))
+                })
+                .map(|(index, _)| index)
+                .collect();
+            // Like the dispatcher, only the supported flavors with the highest priority are ranked
+            let top_priority = supported_indices
+                .iter()
+                .filter_map(|&index| flavors[index].2)
+                .max();
+            let (indices, supported): (Vec<usize>, Vec<Vec<&str>>) = supported_indices
+                .iter()
+                .filter(|&&index| top_priority.is_none() || flavors[index].2 == top_priority)
+                .map(|&index| {
+                    let cpufeatures = flavors[index].1.iter().map(String::as_str).collect();
+                    (index, cpufeatures)
+                })
+                .unzip();
+            let supported = supported.iter().map(Vec::as_slice);
+            // The dispatcher falls back to the simple ranking without a curated one
+            let top_ranked = match self.ranking {
+                Ranking::Curated if self.target.architecture == Architecture::X86_64 => {
+                    ranking::top_ranked_curated(supported)
+                }
+                _ => ranking::top_ranked_simple(supported),
+            };
+            let selected = usize::try_from(top_ranked)
+                .ok()
+                .map(|top_ranked| indices[top_ranked]);
+
+            report.push_str(&format!("\n{} v{}:", package.name, package.version));
+            for (index, (desc, _, _)) in flavors.iter().enumerate() {
+                let marker = if selected == Some(index) { "*" } else { " " };
+                let status = if supported_indices.contains(&index) {
+                    ""
+                } else {
+                    " (unsupported)"
+                };
+                report.push_str(&format!("\n  {marker} {desc}{status}"));
+            }
+            let marker = if selected.is_none() { "*" } else { " " };
+            if self.no_fallback {
+                report.push_str(&format!(
+                    "\n  {marker} none, the fat binary exits with an error"
+                ));
+            } else {
+                report.push_str(&format!("\n  {marker} default fallback"));
+            }
+        }
+        Ok(report)
+    }
+
+    /// Package metadata, then the --config file, then the --preset, then command-line overrides
+    fn load_config(&self, package: &Package) -> anyhow::Result<ConfigMultiArch> {
+        ConfigMultiArch::new(
+            self.rustc.clone(),
+            self.config_arch,
+            self.target.operating_system,
+            self.target_name.clone(),
+        )
+        .load_cargo_toml(package)
+        .and_then(|cfg| match self.config_file.as_deref() {
+            Some(path) => cfg.load_file(path),
+            None => Ok(cfg),
+        })
+        .and_then(|cfg| match self.preset {
+            Some(preset) => cfg.load_preset(preset),
+            None => Ok(cfg),
+        })
+        .and_then(|cfg| cfg.override_cpus(self.override_cpus.clone(), self.cpus_mode))
+        .and_then(|cfg| {
+            cfg.override_features_lists(
+                BTreeSet::from([self.override_cpufeatures.clone().into()]),
+                self.cpufeatures_mode,
+            )
+        })
+        .and_then(ConfigMultiArch::check_cpu_features)
+    }
+
+    /// Returns the hash of the fallback of each binary
+    fn compile_pkg(&self, package: &Package, bins: &[&Target]) -> anyhow::Result<PkgBuilds> {
+        let cargo_toml = package.manifest_path.as_std_path();
+        let pkg_features = self.pkg_features.features.join(" ");
+        let rust_flags = self.base_rustflags();
+
+        let cargo_config = self.load_config(package)?;
+
+        for warning in cargo_config.find_redundant_flavors()? {
+            self.warn(16, warning);
+        }
+
+        // Checked before spending time on builds
+        let pkg_dir = cargo_toml.parent().unwrap_or(Path::new("."));
+        let cpu_features: BTreeSet<Flavor> = cargo_config
+            .get_cpu_features()
+            .into_iter()
+            .map(|flavor| flavor.resolve_pgo_profile(pkg_dir))
+            .collect::<anyhow::Result<_>>()?;
+        if cpu_features.is_empty() {
+            let hint = cargo_config.explain_no_flavors();
+            anyhow::ensure!(
+                !self.no_fallback,
+                "{hint}, --no-fallback requires at least one flavor with CPU features"
+            );
+            self.warn(16, format!("{hint}, only the fallback is built"));
+        }
+        // rustc rejects some combinations, e.g. features forbidden by the target spec
+        for flavor in cpu_features
+            .iter()
+            .filter(|flavor| !flavor.cpufeatures.is_empty())
+        {
+            self.rustc
+                .get_effective_cpufeatures(
+                    &self.target_name,
+                    &flavor.cpufeatures.to_compiler_flags(),
+                )
+                .with_context(|| format!("Flavor {} is rejected by rustc", flavor.describe()))?;
+        }
+        let targets_glibc = self.target.operating_system == OperatingSystem::Linux
+            && self.target.environment.to_string().starts_with("gnu");
+        if !targets_glibc && cpu_features.iter().any(|flavor| flavor.min_glibc.is_some()) {
+            self.warn(
+                16,
+                format!(
+                    "`min_glibc` is only checked on glibc hosts, '{}' does not target glibc",
+                    self.target_name
+                ),
+            );
+        }
 
         let mut cfg = CompilationConfig {
             binary_name: "",
             cargo_toml,
             rust_flags: &rust_flags,
             pkg_features: &pkg_features,
+            source_fingerprint: Some(self.source_fingerprint()),
         };
 
-        for bin_target in package.targets.iter().filter(|target| target.is_bin()) {
-            self.progress.println(format!(
+        let mut builds = PkgBuilds::default();
+        for bin_target in bins {
+            self.println(format!(
                 "{:>16} {}",
                 style("Compiling").green(),
                 bin_target.name,
@@ -196,6 +1178,10 @@ impl Multiarch {
             cfg.binary_name = &bin_target.name;
 
             let pkg_multiarch = self.compile_bin_multiarch(&cfg, cpu_features.iter())?;
+            self.explain(&pkg_multiarch);
+            if let Some(hash) = pkg_multiarch.fallback_hash {
+                builds.fallbacks.push((bin_target.name.clone(), hash));
+            }
 
             let original_filename = pkg_multiarch
                 .bins
@@ -205,195 +1191,770 @@ impl Multiarch {
                     format!("multiarch-placeholder{}", std::env::consts::EXE_SUFFIX).into()
                 });
 
-            if let [build] = &pkg_multiarch.bins[..] {
-                self.handle_single_arch(build, original_filename)?
+            if is_wasm(&self.target) {
+                self.handle_wasm(&pkg_multiarch, original_filename, &package.name)?
+            } else if let [build] = &pkg_multiarch.bins[..] {
+                let path =
+                    self.handle_single_arch(&build.path, original_filename.clone(), &package.name)?;
+                builds.executables.push((original_filename, path));
+            } else if self.emit == Emit::Script {
+                self.handle_script(&pkg_multiarch, original_filename, &package.name)?
             } else {
-                self.handle_multi_arch(&pkg_multiarch, original_filename, &bin_target.name)?
+                let path = self.handle_multi_arch(
+                    &pkg_multiarch,
+                    original_filename.clone(),
+                    &bin_target.name,
+                    &package.name,
+                )?;
+                builds.executables.push((original_filename, path));
+            }
+            if self.split_debuginfo {
+                self.copy_debuginfo_to_out_dir(&pkg_multiarch, &package.name)?;
             }
+            self.progress.inc(1);
         }
-        Ok(())
+        Ok(builds)
     }
 
+    /// Moves the only build into place as the final binary and returns its path
     fn handle_single_arch(
         &self,
-        build: &BinaryDesc,
+        build: &Path,
         original_filename: OsString,
-    ) -> anyhow::Result<()> {
-        let output_path = self
+        pkg_name: &str,
+    ) -> anyhow::Result<PathBuf> {
+        let output_dir = self
             .target_dir
-            .join(&self.target.to_string())
-            .join(&self.profile_dir)
-            .join(&original_filename);
+            .join(self.target.to_string())
+            .join(&self.profile_dir);
+        // Nothing was built there when packing prebuilt flavors
+        fs::create_dir_all(&output_dir)
+            .with_context(|| format!("Failed to create directory `{}`", output_dir.display()))?;
+        let output_path = output_dir.join(&original_filename);
 
-        fs::rename(&build.path, &output_path).with_context(|| {
+        fs::rename(build, &output_path).with_context(|| {
             format!(
                 "Failed to rename `{}` to `{}`",
-                build.path.display(),
+                build.display(),
                 output_path.display()
             )
         })?;
 
-        if let Some(out_dir) = self.outdir.as_deref() {
-            fs::create_dir_all(out_dir).with_context(|| {
-                format!("Failed to create output directory `{}`", out_dir.display())
-            })?;
-            let to = out_dir.join(&original_filename);
-            fs::copy(&output_path, &to).with_context(|| {
-                format!(
-                    "Failed to copy `{}` to `{}`",
-                    output_path.display(),
-                    to.display()
-                )
-            })?;
-        }
+        self.post_process(&output_path)?;
+        self.check_fatbin_size(&output_path, &[])?;
+        self.copy_to_out_dir(&output_path, &original_filename, pkg_name)?;
+        self.write_oci_image(&output_path, &original_filename, pkg_name)?;
 
-        self.progress.println(format!(
+        self.println(format!(
             "{:>16} 1 version, no dispatcher needed ({})",
             style("Finished").green(),
             output_path.display()
         ));
 
-        Ok(())
+        Ok(output_path)
     }
 
+    /// Packs the builds into a fat binary and returns its path
     fn handle_multi_arch(
         &self,
         artifacts: &Artifacts,
         original_filename: OsString,
+        bin_name: &str,
         pkg_name: &str,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<PathBuf> {
+        check_same_sources(artifacts)?;
         let serialized =
             serde_json::to_vec_pretty(artifacts).context("Failed to encode the builds")?;
 
-        let pkg_outdir = self.target_dir.join(pkg_name);
+        let pkg_outdir = self.target_dir.join(bin_name);
         fs::create_dir_all(&pkg_outdir).context("Failed to create temporary output directory")?;
 
         let artifacts_json = pkg_outdir.join("multiarch-artifacts.json");
+        let partial = PartialFile::new(artifacts_json.clone());
         std::fs::write(&artifacts_json, serialized)
             .with_context(|| format!("Failed to write to `{}`", artifacts_json.display()))?;
+        drop(partial);
 
-        self.progress.println(format!(
+        self.println(format!(
             "{:>20} {} versions into a fat binary",
             style("Packing").green(),
             artifacts.bins.len(),
         ));
 
-        let fatbin_path = self.fatbin.cargo_build(
-            &self.target.to_string(),
+        let build_info = if self.embed_build_info {
+            Some(self.build_info()?)
+        } else {
+            None
+        };
+
+        let started = Instant::now();
+        let FatbinBuild {
+            path: fatbin_path,
+            sizes,
+        } = self.fatbin.cargo_build(
+            self.dispatcher_target
+                .as_deref()
+                .unwrap_or(&self.target_name),
             &artifacts_json,
             &original_filename,
+            &self.cargo_config,
+            build_info.as_deref(),
+            &mut self.warnings.borrow_mut(),
         )?;
+        self.timings
+            .record_pack(bin_name, artifacts.bins.len(), started.elapsed());
 
-        if let Some(out_dir) = self.outdir.as_deref() {
-            std::fs::create_dir_all(out_dir).with_context(|| {
-                format!("Failed to create output directory `{}`", out_dir.display())
-            })?;
-            let to = out_dir.join(&original_filename);
-            std::fs::copy(&fatbin_path, &to).with_context(|| {
-                format!(
-                    "Failed to copy `{}` to `{}`",
-                    fatbin_path.display(),
-                    to.display()
-                )
-            })?;
+        self.post_process(&fatbin_path)?;
+        self.check_fatbin_size(&fatbin_path, &sizes)?;
+        self.selftest(artifacts, &fatbin_path)?;
+        self.copy_to_out_dir(&fatbin_path, &original_filename, pkg_name)?;
+        self.write_oci_image(&fatbin_path, &original_filename, pkg_name)?;
+        if self.bundle_path.is_some() {
+            let mut manifest_name = PathBuf::from(&original_filename);
+            manifest_name.set_extension("multiarch.json");
+            self.bundle.add(
+                self.out_name(pkg_name, manifest_name.as_os_str()),
+                &artifacts_json,
+            );
         }
 
-        self.progress.println(format!(
+        self.println(format!(
             "{:>16} ({})",
             style("Finished").green(),
             fatbin_path.display()
         ));
 
-        Ok(())
+        Ok(fatbin_path)
     }
 
-    /// Compile a single binary in a single package from the workspace
-    /// for a multiset of CPU features
-    fn compile_bin_multiarch<'a>(
+    /// With --emit oci, packs the final binary into `<bin>.oci` in --out-dir,
+    /// or next to the binary without one
+    fn write_oci_image(
         &self,
-        cfg: &CompilationConfig,
-        cpu_features: impl Iterator<Item = &'a CpuFeatures>,
-    ) -> anyhow::Result<Artifacts> {
-        let mut binaries_desc: Vec<([u8; 32], BinaryDesc)> = Default::default();
-
-        // No features
-        {
-            let desc = self.compile_bin(cfg, &CpuFeatures::default())?;
-            binaries_desc.push(desc);
-        }
-
-        for current_feature_set in cpu_features {
-            let desc = self.compile_bin(cfg, current_feature_set)?;
-            binaries_desc.push(desc);
+        binary: &Path,
+        original_filename: &OsStr,
+        pkg_name: &str,
+    ) -> anyhow::Result<()> {
+        if self.emit != Emit::Oci {
+            return Ok(());
         }
+        let mut image_name = PathBuf::from(original_filename);
+        image_name.set_extension("oci");
+        let image_dir = match (self.outdir.as_deref(), self.out_layout) {
+            (Some(out_dir), OutLayout::Flat) => out_dir.join(&image_name),
+            (Some(out_dir), OutLayout::PerPackage) => out_dir.join(pkg_name).join(&image_name),
+            (None, _) => binary.with_file_name(&image_name),
+        };
+        oci::write_image_layout(
+            binary,
+            &original_filename.to_string_lossy(),
+            &self.target,
+            &image_dir,
+        )?;
+        self.println(format!(
+            "{:>20} OCI image layout {}",
+            style("Packed").green(),
+            image_dir.display()
+        ));
+        Ok(())
+    }
+
+    /// Fails if the final binary exceeds --max-fatbin-size, with the size of each embedded flavor
+    fn check_fatbin_size(&self, binary: &Path, sizes: &[EmbeddedSize]) -> anyhow::Result<()> {
+        let Some(max_size) = self.max_fatbin_size else {
+            return Ok(());
+        };
+        let size = fs::metadata(binary)
+            .with_context(|| format!("Failed to read `{}`", binary.display()))?
+            .len();
+        if size <= max_size {
+            return Ok(());
+        }
+        if sizes.is_empty() {
+            anyhow::bail!(
+                "`{}` is {size} bytes, over --max-fatbin-size {max_size}, it is a single flavor without dispatcher",
+                binary.display()
+            );
+        }
+        // The first one is the compressed base, the others are patches against it
+        let breakdown = sizes
+            .iter()
+            .enumerate()
+            .map(|(index, embedded)| {
+                format!(
+                    "{:>12} {}{}",
+                    embedded.bytes,
+                    if embedded.flavor.is_empty() {
+                        "default fallback"
+                    } else {
+                        &embedded.flavor
+                    },
+                    if index == 0 { " (compressed base)" } else { "" }
+                )
+            })
+            .join("\n");
+        let embedded: u64 = sizes.iter().map(|embedded| embedded.bytes).sum();
+        anyhow::bail!(
+            "`{}` is {size} bytes, over --max-fatbin-size {max_size}. Bytes embedded per flavor:\n{breakdown}\n{:>12} dispatcher and metadata",
+            binary.display(),
+            size.saturating_sub(embedded)
+        )
+    }
+
+    /// Runs the packed fat binary once per flavor with --selftest
+    fn selftest(&self, artifacts: &Artifacts, fatbin: &Path) -> anyhow::Result<()> {
+        let Some(probe) = self.selftest.as_deref() else {
+            return Ok(());
+        };
+        let host = self.rustc.get_host_target()?;
+        let dispatcher_target = self.dispatcher_target.as_deref();
+        if self.target_name != host || dispatcher_target.is_some_and(|target| target != host) {
+            self.warn(
+                20,
+                format!(
+                    "--selftest skipped, '{}' binaries do not run on the host '{host}'",
+                    dispatcher_target.unwrap_or(&self.target_name),
+                ),
+            );
+            return Ok(());
+        }
+
+        let host_features: BTreeSet<String> = self
+            .rustc
+            .get_cpufeatures_for_programs(Some(&self.target_name), None)?
+            .into_iter()
+            .collect();
+        let mut failures = 0;
+        for bin in &artifacts.bins {
+            let flavor = if bin.cpufeatures.is_empty() {
+                "fallback".to_owned()
+            } else {
+                format!("[{}]", bin.cpufeatures.join(", "))
+            };
+            match selftest::probe_flavor(fatbin, &bin.cpufeatures, &host_features, probe)? {
+                Probe::Unsupported => self.println(format!(
+                    "{:>20} {flavor}, the host lacks some of its CPU features",
+                    style("Skipped").cyan(),
+                )),
+                Probe::Launched(status) => self.println(format!(
+                    "{:>20} {flavor} ({status})",
+                    style("Launched").green(),
+                )),
+                Probe::Failed(reason) => {
+                    failures += 1;
+                    self.println(format!(
+                        "{:>20} {flavor}: {reason}",
+                        style("FAILED").red().bold(),
+                    ));
+                }
+            }
+        }
+        anyhow::ensure!(
+            failures == 0,
+            "{failures} flavor(s) of `{}` failed to launch",
+            fatbin.display()
+        );
+        Ok(())
+    }
+
+    /// Generate a launcher script next to the flavors instead of a fat binary
+    fn handle_script(
+        &self,
+        artifacts: &Artifacts,
+        original_filename: OsString,
+        pkg_name: &str,
+    ) -> anyhow::Result<()> {
+        let flavors: Vec<(String, Vec<String>, Option<u32>)> = artifacts
+            .bins
+            .iter()
+            .map(|bin| {
+                let file_name = bin
+                    .path
+                    .file_name()
+                    .map(|file_name| file_name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                (file_name, bin.cpufeatures.clone(), bin.priority)
+            })
+            .collect();
+
+        self.println(format!(
+            "{:>20} {} versions behind a launcher script",
+            style("Packing").green(),
+            artifacts.bins.len(),
+        ));
+
+        let script =
+            gen_launcher_script::posix_launcher(&original_filename.to_string_lossy(), &flavors);
+        let script_path = self
+            .target_dir
+            .join(self.target.to_string())
+            .join(&self.profile_dir)
+            .join(&original_filename);
+        fs::write(&script_path, script)
+            .with_context(|| format!("Failed to write to `{}`", script_path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755)).with_context(
+                || format!("Failed to make `{}` executable", script_path.display()),
+            )?;
+        }
+
+        for bin in &artifacts.bins {
+            if let Some(file_name) = bin.path.file_name() {
+                self.copy_to_out_dir(&bin.path, file_name, pkg_name)?;
+            }
+        }
+        self.copy_to_out_dir(&script_path, &original_filename, pkg_name)?;
+
+        self.println(format!(
+            "{:>16} ({})",
+            style("Finished").green(),
+            script_path.display()
+        ));
 
-        binaries_desc.sort_unstable_by(|(h1, b1), (h2, b2)| {
-            // First, we sort based on the hash to detect duplicate
-            h1.cmp(&h2)
-                // Then, based on the features, to keep those with less.
-                // While some features imply others (avx2 imply avx),
-                // the hashes should be different. There should not be a case
-                // with same number of features lead to same binary hash.
-                .then_with(|| b1.cpufeatures.len().cmp(&b2.cpufeatures.len()))
+        Ok(())
+    }
+
+    /// WebAssembly has no native dispatcher: the flavors are emitted as distinct modules
+    /// next to a `<bin>.multiarch.json` manifest listing them by preference,
+    /// selecting one at runtime is left to the embedder
+    fn handle_wasm(
+        &self,
+        artifacts: &Artifacts,
+        original_filename: OsString,
+        pkg_name: &str,
+    ) -> anyhow::Result<()> {
+        // Highest priority then most features first, the embedder loads the first one
+        // its engine supports
+        let mut flavors: Vec<&BinaryDesc> = artifacts.bins.iter().collect();
+        flavors.sort_by(|bin1, bin2| {
+            (bin2.priority.cmp(&bin1.priority))
+                .then_with(|| bin2.cpufeatures.len().cmp(&bin1.cpufeatures.len()))
+                .then_with(|| bin1.cpufeatures.cmp(&bin2.cpufeatures))
         });
+        let manifest = serde_json::json!({
+            "target": self.target_name,
+            "flavors": flavors
+                .iter()
+                .map(|bin| {
+                    let mut flavor = serde_json::json!({
+                        "file": bin.path.file_name().map(|file_name| file_name.to_string_lossy()),
+                        "cpufeatures": bin.cpufeatures,
+                    });
+                    if let Some(priority) = bin.priority {
+                        flavor["priority"] = priority.into();
+                    }
+                    flavor
+                })
+                .collect::<Vec<_>>(),
+        });
+
+        self.println(format!(
+            "{:>20} {} WebAssembly modules with a selection manifest",
+            style("Packing").green(),
+            flavors.len(),
+        ));
+
+        let mut manifest_name = PathBuf::from(&original_filename);
+        manifest_name.set_extension("multiarch.json");
+        let manifest_path = self
+            .target_dir
+            .join(self.target.to_string())
+            .join(&self.profile_dir)
+            .join(&manifest_name);
+        fs::write(&manifest_path, format!("{manifest:#}\n"))
+            .with_context(|| format!("Failed to write to `{}`", manifest_path.display()))?;
+
+        for bin in &flavors {
+            if let Some(file_name) = bin.path.file_name() {
+                self.copy_to_out_dir(&bin.path, file_name, pkg_name)?;
+            }
+        }
+        self.copy_to_out_dir(&manifest_path, manifest_name.as_os_str(), pkg_name)?;
+
+        self.println(format!(
+            "{:>16} ({})",
+            style("Finished").green(),
+            manifest_path.display()
+        ));
+
+        Ok(())
+    }
+
+    /// `key=value` lines embedded with --embed-build-info
+    fn build_info(&self) -> anyhow::Result<String> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default();
+        let commit = std::env::var("GIT_COMMIT")
+            .ok()
+            .filter(|commit| !commit.is_empty())
+            .or_else(|| {
+                let output = Command::new("git")
+                    .arg("-C")
+                    .arg(&self.metadata.workspace_root)
+                    .args(["rev-parse", "HEAD"])
+                    .output()
+                    .ok()?;
+                output
+                    .status
+                    .success()
+                    .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+            })
+            .unwrap_or_else(|| "unknown".to_owned());
+        Ok(format!(
+            "timestamp={timestamp}\ncommit={commit}\nrustc={}",
+            Rustc::get_version()?
+        ))
+    }
+
+    /// Run the --post-process command on a final binary, if any
+    fn post_process(&self, binary: &Path) -> anyhow::Result<()> {
+        let Some(cmd) = self.post_process.as_deref() else {
+            return Ok(());
+        };
+        let mut args = cmd.split_whitespace();
+        let program = args.next().context("--post-process is empty")?;
+
+        self.println(format!(
+            "{:>20} {cmd} {}",
+            style("Post-processing").green(),
+            binary.display()
+        ));
+        let status = Command::new(program)
+            .args(args)
+            .arg(binary)
+            .status()
+            .with_context(|| format!("Failed to run the post-process command `{cmd}`"))?;
+        anyhow::ensure!(
+            status.success(),
+            "The post-process command `{cmd}` failed on `{}` ({status})",
+            binary.display()
+        );
+
+        let metadata = fs::metadata(binary).with_context(|| {
+            format!(
+                "`{}` is missing after the post-process command `{cmd}`",
+                binary.display()
+            )
+        })?;
+        anyhow::ensure!(
+            metadata.is_file() && metadata.len() > 0,
+            "`{}` is empty after the post-process command `{cmd}`",
+            binary.display()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            anyhow::ensure!(
+                metadata.permissions().mode() & 0o111 != 0,
+                "`{}` is no longer executable after the post-process command `{cmd}`",
+                binary.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Print a status line above the progress bar,
+    /// or as is when the progress bar is hidden
+    fn println(&self, msg: impl AsRef<str>) {
+        if self.interactive {
+            self.progress.println(msg);
+        } else {
+            eprintln!("{}", msg.as_ref());
+        }
+    }
+
+    /// Print a warning like `println`, it is repeated in the summary at the end of the build
+    fn warn(&self, width: usize, warning: String) {
+        self.println(format!(
+            "{:>width$} {warning}",
+            style("Warning").bold().yellow()
+        ));
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    /// Print the warnings of the build again, they may have scrolled past
+    fn print_warnings_summary(&self) {
+        let warnings = self.warnings.borrow();
+        // Cargo warnings are repeated by each flavor build
+        let warnings: Vec<&String> = warnings.iter().unique().collect();
+        if warnings.is_empty() {
+            return;
+        }
+        eprintln!(
+            "{:>12} the build generated {} warning(s):",
+            style("Warning").bold().yellow(),
+            warnings.len()
+        );
+        for warning in warnings {
+            eprintln!("{:>12} - {warning}", "");
+        }
+    }
+
+    /// Copy a final artifact to the output directory, if any
+    fn copy_to_out_dir(
+        &self,
+        artifact: &Path,
+        original_filename: &OsStr,
+        pkg_name: &str,
+    ) -> anyhow::Result<()> {
+        if self.bundle_path.is_some() {
+            self.bundle
+                .add(self.out_name(pkg_name, original_filename), artifact);
+        }
+        let Some(out_dir) = self.outdir.as_deref() else {
+            return Ok(());
+        };
+        let out_dir = match self.out_layout {
+            OutLayout::Flat => out_dir.to_path_buf(),
+            OutLayout::PerPackage => out_dir.join(pkg_name),
+        };
+
+        fs::create_dir_all(&out_dir).with_context(|| {
+            format!("Failed to create output directory `{}`", out_dir.display())
+        })?;
+        let to = out_dir.join(original_filename);
+        let _partial = PartialFile::new(to.clone());
+        fs::copy(artifact, &to).with_context(|| {
+            format!(
+                "Failed to copy `{}` to `{}`",
+                artifact.display(),
+                to.display()
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Path of a final artifact relative to --out-dir and in the --bundle
+    fn out_name(&self, pkg_name: &str, file_name: &OsStr) -> String {
+        let name = file_name.to_string_lossy();
+        match self.out_layout {
+            OutLayout::Flat => name.into_owned(),
+            OutLayout::PerPackage => format!("{pkg_name}/{name}"),
+        }
+    }
+
+    /// Copy the split debug info of the embedded flavors to <out-dir>/debug, if any
+    fn copy_debuginfo_to_out_dir(
+        &self,
+        artifacts: &Artifacts,
+        pkg_name: &str,
+    ) -> anyhow::Result<()> {
+        if self.bundle_path.is_some() {
+            for bin in &artifacts.bins {
+                let debug_path = debuginfo_path(&bin.path);
+                if let Some(file_name) = debug_path.file_name() {
+                    let name = self.out_name(pkg_name, OsStr::new("debug"));
+                    self.bundle.add(
+                        format!("{name}/{}", file_name.to_string_lossy()),
+                        &debug_path,
+                    );
+                }
+            }
+        }
+        let Some(out_dir) = self.outdir.as_deref() else {
+            return Ok(());
+        };
+        let debug_dir = match self.out_layout {
+            OutLayout::Flat => out_dir.join("debug"),
+            OutLayout::PerPackage => out_dir.join(pkg_name).join("debug"),
+        };
+        fs::create_dir_all(&debug_dir).with_context(|| {
+            format!(
+                "Failed to create output directory `{}`",
+                debug_dir.display()
+            )
+        })?;
+        for bin in &artifacts.bins {
+            let debug_path = debuginfo_path(&bin.path);
+            let Some(file_name) = debug_path.file_name() else {
+                continue;
+            };
+            let to = debug_dir.join(file_name);
+            fs::copy(&debug_path, &to).with_context(|| {
+                format!(
+                    "Failed to copy `{}` to `{}`",
+                    debug_path.display(),
+                    to.display()
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Compile a single binary in a single package from the workspace
+    /// for a multiset of CPU features
+    fn compile_bin_multiarch<'a>(
+        &self,
+        cfg: &CompilationConfig,
+        flavors: impl Iterator<Item = &'a Flavor>,
+    ) -> anyhow::Result<Artifacts> {
+        // The default fallback has no features
+        let fallback = Flavor::default();
+        let fallback = (!self.no_fallback).then_some(&fallback);
+        // Reborrowed, the fallback does not live for 'a
+        let flavors: Vec<&Flavor> = fallback
+            .into_iter()
+            .chain(flavors.map(|flavor| flavor as &Flavor))
+            .collect();
+        anyhow::ensure!(
+            !flavors.is_empty(),
+            "--no-fallback requires at least one flavor with CPU features"
+        );
+
+        let mut binaries_desc: Vec<([u8; 32], BinaryDesc)> = Vec::with_capacity(flavors.len());
+        for (step, flavor) in flavors.iter().enumerate() {
+            let desc = self.compile_bin(cfg, flavor, (step + 1, flavors.len()))?;
+            self.emit_asm(&desc.1)?;
+            binaries_desc.push(desc);
+        }
 
-        binaries_desc.dedup_by(|h1, h2| h1.0 == h2.0);
+        Ok(dedup_binaries(binaries_desc))
+    }
+
+    /// Cross-check the requested CPU features with the ones rustc enables,
+    /// warn about those silently dropped,
+    /// and return the features enabled on top of the target baseline, including implied ones.
+    fn effective_cpufeatures(&self, cpu_features: &CpuFeatures) -> anyhow::Result<Vec<String>> {
+        if cpu_features.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let enabled = self
+            .rustc
+            .get_effective_cpufeatures(&self.target_name, &cpu_features.to_compiler_flags())?;
+
+        let dropped = cpu_features
+            .iter()
+            .filter(|&feature| !enabled.contains(feature))
+            .join(", ");
+        if !dropped.is_empty() {
+            self.warn(
+                20,
+                format!("rustc did not enable {dropped}, the flavor will not depend on them"),
+            );
+        }
 
-        let bins = binaries_desc.into_iter().map(|bd| bd.1).collect();
-        Ok(Artifacts { bins })
+        Ok(enabled
+            .difference(&self.baseline_features)
+            .cloned()
+            .collect())
     }
 
     /// Compile a single package from the workspace
-    /// for a single set of CPU features
+    /// for a single set of CPU features and flavor-specific cargo features
     /// returns the hash of a binary for dedup purposes
     /// and a description of it.
     /// We choose SHA256 for its ubiquitous hardware acceleration on CPUs
     fn compile_bin(
         &self,
         cfg: &CompilationConfig<'_>,
-        cpu_features: &CpuFeatures,
+        flavor: &Flavor,
+        (step, num_steps): (usize, usize),
     ) -> anyhow::Result<([u8; 32], BinaryDesc)> {
+        let cpu_features = &flavor.cpufeatures;
         let arch_flags = cpu_features.to_compiler_flags();
         // TODO: pass the name of a CPU if any was specified for example x86-64-v3 (+avx,+avx2,+bmi,+bmi2,...)
-        self.progress.println(format!(
-            "{:>20} {}",
-            style("Compiling").green(),
-            if arch_flags.len() > 0 {
-                &arch_flags
-            } else {
-                "default fallback"
-            }
-        ));
+        let flavor_desc = flavor.describe();
+        if self.interactive {
+            self.println(format!("{:>20} {flavor_desc}", style("Compiling").green()));
+        } else {
+            self.println(format!("Building flavor {step}/{num_steps}: {flavor_desc}"));
+        }
 
         let target_string = self.target.to_string();
 
-        let rust_flags = format!("{} -Ctarget-feature={arch_flags}", { cfg.rust_flags });
+        // Each configured flag is a single argument, like in cargo's `build.rustflags`
+        let mut rust_flags = cfg.rust_flags.to_vec();
+        rust_flags.push(format!("-Ctarget-feature={arch_flags}"));
+        rust_flags.extend(flavor.rustflags.iter().cloned());
+        if let Some(profile) = &flavor.pgo_profile {
+            rust_flags.push(format!("-Cprofile-use={}", profile.display()));
+        }
+        let encoded_rust_flags = Rustc::encode_rustflags(&rust_flags);
         let cargo = CargoBuild::new()
             .arg(format!("--profile={}", self.profile))
             .arg(format!("--bin={}", cfg.binary_name))
-            .target(&target_string)
+            .target(&self.target_name)
             .manifest_path(cfg.cargo_toml)
             .args(&self.cargo_args)
-            .env("RUSTFLAGS", rust_flags);
+            .args(
+                self.cargo_config
+                    .iter()
+                    .flat_map(|config| ["--config", config.as_str()]),
+            )
+            // Encoded so that flags with spaces are not split, it overrides RUSTFLAGS
+            .env("CARGO_ENCODED_RUSTFLAGS", encoded_rust_flags)
+            .env_remove("RUSTFLAGS");
+        let cargo = if self.split_debuginfo {
+            // Full debug info to split, whatever the profile says
+            cargo.args([
+                "--config".to_owned(),
+                format!(r#"profile.{}.debug="full""#, self.profile),
+                "--config".to_owned(),
+                format!(r#"profile.{}.strip="none""#, self.profile),
+            ])
+        } else {
+            cargo
+        };
+
+        let pkg_features = [cfg.pkg_features]
+            .into_iter()
+            .chain(flavor.cargo_features.iter().map(String::as_str))
+            .filter(|features| !features.is_empty())
+            .join(" ");
 
         let cargo = if self.pkg_features.all_features {
             cargo.all_features()
         } else if self.pkg_features.no_default_features {
-            cargo.no_default_features()
+            cargo.no_default_features().features(&pkg_features)
         } else {
-            cargo.features(&cfg.pkg_features)
+            cargo.features(&pkg_features)
         };
 
-        let cargo = cargo.exec()?;
+        let started = Instant::now();
         let bin_path = cargo
-            .find_executable()?
-            .ok_or_else(|| anyhow::anyhow!("Failed to find a binary"))?;
+            .exec_find_executable(&format!("flavor {flavor_desc}"), self.build_timeout, &mut self.warnings.borrow_mut())?
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Failed to find the binary `{}` in the output of cargo with `--profile {}`",
+                    cfg.binary_name,
+                    self.profile
+                )
+            })?;
+
+        self.timings
+            .record_compile(cfg.binary_name, &flavor_desc, started.elapsed());
 
-        let filename = format!("{}-{}", cfg.binary_name, cpu_features.iter().join("_"));
+        let mut filename = format!("{}-{}", cfg.binary_name, cpu_features.iter().join("_"));
+        if !flavor.cargo_features.is_empty() {
+            // Flavors may share CPU features but differ in cargo features
+            filename = format!("{filename}-{}", flavor.cargo_features.iter().join("_"));
+        }
+        if !flavor.rustflags.is_empty() {
+            // Keep the flags recognizable while staying a valid filename
+            let rustflags: String = flavor
+                .rustflags
+                .join("_")
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            filename = format!("{filename}-{rustflags}");
+        }
+        if flavor.pgo_profile.is_some() {
+            filename = format!("{filename}-pgo");
+        }
+        // Keep the extension of the built artifact, e.g. `.wasm` or `.exe`.
+        // Appended, not set: features such as `sse4.1` put a dot in the name.
+        if let Some(extension) = bin_path.extension() {
+            filename = format!("{filename}.{}", extension.to_string_lossy());
+        }
 
         let output_path_parent = self.target_dir.join(&target_string).join(&self.profile_dir);
-        let mut output_path = output_path_parent.join(filename);
-        output_path.set_extension(std::env::consts::EXE_EXTENSION);
+        let output_path = output_path_parent.join(filename);
 
         std::fs::create_dir_all(&output_path_parent).with_context(|| {
             format!(
@@ -401,6 +1962,7 @@ impl Multiarch {
                 output_path_parent.display()
             )
         })?;
+        let partial = PartialFile::new(output_path.clone());
         std::fs::copy(&bin_path, &output_path).with_context(|| {
             format!(
                 "Failed to copy `{}` to `{}`",
@@ -409,14 +1971,508 @@ impl Multiarch {
             )
         })?;
 
+        if self.split_debuginfo {
+            split_debuginfo(&output_path)?;
+        }
+        drop(partial);
+
+        // Hashed after splitting, identical code dedups despite distinct debug info
         let hash = std::fs::read(&output_path).map(Sha256::digest)?;
 
+        // Checked when the flavor is packed later with `pack`
+        if let Some(fingerprint) = cfg.source_fingerprint {
+            let path = fingerprint_path(&output_path);
+            fs::write(&path, fingerprint)
+                .with_context(|| format!("Failed to write `{}`", path.display()))?;
+        }
+
         let desc = BinaryDesc {
             path: output_path,
-            cpufeatures: cpu_features.iter().cloned().collect(),
+            cpufeatures: self.effective_cpufeatures(cpu_features)?,
+            min_glibc: flavor.min_glibc.clone(),
+            priority: flavor.priority,
             original_filename: bin_path.file_name().map(ToOwned::to_owned),
+            description: flavor_desc,
+            fingerprint: cfg.source_fingerprint.map(ToOwned::to_owned),
         };
 
         Ok((hash.into(), desc))
     }
 }
+
+/// The git working tree containing `out_dir`, if the workspace is in one
+/// and `out_dir` is not ignored. Best-effort, None without git.
+fn unignored_in_worktree(out_dir: &Path, workspace_root: &Path) -> Option<PathBuf> {
+    let git = |args: &[&OsStr]| {
+        Command::new("git")
+            .arg("-C")
+            .arg(workspace_root)
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+    };
+    let toplevel = git(&["rev-parse".as_ref(), "--show-toplevel".as_ref()])
+        .filter(|output| output.status.success())?;
+    let worktree = fs::canonicalize(String::from_utf8_lossy(&toplevel.stdout).trim()).ok()?;
+
+    // The directory may not exist yet, resolve its closest existing ancestor
+    let out_dir = std::path::absolute(out_dir).ok()?;
+    let existing = out_dir.ancestors().find(|dir| dir.exists())?;
+    let out_dir = fs::canonicalize(existing)
+        .ok()?
+        .join(out_dir.strip_prefix(existing).ok()?);
+    let relative = out_dir.strip_prefix(&worktree).ok()?;
+    if relative.as_os_str().is_empty() {
+        return Some(worktree);
+    }
+
+    // The trailing slash matches directory patterns like `dist/` for a missing directory
+    let mut pattern = relative.as_os_str().to_owned();
+    pattern.push("/");
+    // 0: ignored, 1: not ignored
+    let ignored = git(&[
+        "check-ignore".as_ref(),
+        "-q".as_ref(),
+        "--".as_ref(),
+        worktree.join(&pattern).as_os_str(),
+    ])?;
+    (ignored.status.code() == Some(1)).then_some(worktree)
+}
+
+/// WebAssembly flavors are packed with a manifest instead of a native dispatcher
+fn is_wasm(target: &Triple) -> bool {
+    matches!(
+        target.architecture,
+        Architecture::Wasm32 | Architecture::Wasm64
+    )
+}
+
+/// `--config` overrides setting the linker of each target.
+/// Cargo resolves a relative linker path against the working directory, it is made absolute
+/// as the dispatcher is built from its own crate; a bare name is looked up in PATH.
+fn linker_config<'a>(
+    linker: &Path,
+    targets: impl Iterator<Item = &'a str>,
+) -> anyhow::Result<Vec<String>> {
+    let linker = if linker.components().count() > 1 {
+        std::path::absolute(linker)
+            .with_context(|| format!("Invalid linker path `{}`", linker.display()))?
+    } else {
+        linker.to_owned()
+    };
+    let linker = toml::Value::String(
+        linker
+            .to_str()
+            .with_context(|| format!("Invalid linker path `{}`", linker.display()))?
+            .to_owned(),
+    );
+    Ok(targets
+        .map(|target| {
+            // Cargo names the config of a custom target spec after its file stem
+            let triple = if is_target_spec(target) {
+                Path::new(target)
+                    .file_stem()
+                    .map_or(target.into(), OsStr::to_string_lossy)
+            } else {
+                target.into()
+            };
+            format!(
+                "target.{}.linker={linker}",
+                toml::Value::String(triple.into_owned())
+            )
+        })
+        .collect())
+}
+
+/// rustc accepts the path to a JSON target specification instead of a target triple
+fn is_target_spec(target: &str) -> bool {
+    target.ends_with(".json") || Path::new(target).is_file()
+}
+
+/// Derives the target triple of a custom target spec from its `llvm-target` or `arch` fields
+fn triple_from_target_spec(path: &Path) -> anyhow::Result<Triple> {
+    let spec = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read target spec `{}`", path.display()))?;
+    let spec: serde_json::Value = serde_json::from_str(&spec)
+        .with_context(|| format!("Failed to parse target spec `{}`", path.display()))?;
+
+    if let Some(Ok(triple)) = spec["llvm-target"].as_str().map(Triple::from_str) {
+        return Ok(triple);
+    }
+    let arch = spec["arch"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Target spec `{}` has no `arch`", path.display()))?;
+    let architecture = Architecture::from_str(arch)
+        .map_err(|_| anyhow!("Unknown architecture '{arch}' in `{}`", path.display()))?;
+    Ok(Triple {
+        architecture,
+        ..Triple::unknown()
+    })
+}
+
+/// `<flavor>.fingerprint` next to the flavor, the source revision it was built from
+fn fingerprint_path(flavor: &Path) -> PathBuf {
+    let mut path = flavor.as_os_str().to_owned();
+    path.push(".fingerprint");
+    path.into()
+}
+
+/// SHA-256 of the `inputs` files and of the files under the `inputs` directories,
+/// their paths relative to `root` included, sorted for stability.
+/// Hidden entries like `.git` and missing inputs, e.g. Cargo.lock before the first build, are skipped.
+/// Cargo.lock is an input so that dependency updates change the fingerprint.
+/// Returns the fingerprint and the paths that could not be read, left out of it.
+fn source_fingerprint(root: &Path, inputs: Vec<PathBuf>) -> (String, Vec<PathBuf>) {
+    let mut files = BTreeSet::new();
+    let mut unreadable = Vec::new();
+    let mut dirs = Vec::new();
+    for input in inputs {
+        if input.is_dir() {
+            dirs.push(input);
+        } else if input.exists() {
+            files.insert(input);
+        }
+    }
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            unreadable.push(dir);
+            continue;
+        };
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => dirs.push(entry.path()),
+                Ok(file_type) if file_type.is_file() => {
+                    files.insert(entry.path());
+                }
+                // Sockets, fifos, ...
+                _ => (),
+            }
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    for file in files {
+        let Ok(content) = fs::read(&file) else {
+            unreadable.push(file);
+            continue;
+        };
+        let relative = file.strip_prefix(root).unwrap_or(&file);
+        hasher.update(relative.to_string_lossy().as_bytes());
+        hasher.update(Sha256::digest(content));
+    }
+    (format!("{:x}", hasher.finalize()), unreadable)
+}
+
+/// Flavors built from different source revisions would behave inconsistently,
+/// e.g. built, edited, then more flavors built and packed together with `pack`.
+/// Flavors without a recorded fingerprint, built outside of cargo-multiarch, are not checked.
+fn check_same_sources(artifacts: &Artifacts) -> anyhow::Result<()> {
+    let fingerprinted: Vec<(&str, &str)> = artifacts
+        .bins
+        .iter()
+        .filter_map(|bin| Some((bin.description.as_str(), bin.fingerprint.as_deref()?)))
+        .collect();
+    let revisions: BTreeSet<&str> = fingerprinted
+        .iter()
+        .map(|(_, fingerprint)| *fingerprint)
+        .collect();
+    anyhow::ensure!(
+        revisions.len() <= 1,
+        "The flavors were built from different source revisions, rebuild them from the same sources:\n{}",
+        fingerprinted
+            .iter()
+            .map(|(flavor, fingerprint)| {
+                let short = fingerprint.get(..12).unwrap_or(fingerprint);
+                format!("  {flavor}: sources {short}")
+            })
+            .join("\n")
+    );
+    Ok(())
+}
+
+/// `<flavor>.debug` next to the flavor, named by its feature set
+fn debuginfo_path(flavor: &Path) -> PathBuf {
+    let mut path = flavor.as_os_str().to_owned();
+    path.push(".debug");
+    path.into()
+}
+
+/// Moves the debug info of an ELF flavor to `<flavor>.debug`.
+/// Debuggers find it by the build ID, a debuglink would name the flavor
+/// and prevent identical flavors from being deduplicated.
+fn split_debuginfo(flavor: &Path) -> anyhow::Result<()> {
+    let objcopy = std::env::var_os("OBJCOPY").unwrap_or_else(|| "objcopy".into());
+    let debug_path = debuginfo_path(flavor);
+    let run = |args: &[&OsStr]| -> anyhow::Result<()> {
+        let status = Command::new(&objcopy)
+            .args(args)
+            .status()
+            .with_context(|| {
+                format!(
+                    "Failed to run `{}`, install binutils or set OBJCOPY",
+                    objcopy.to_string_lossy()
+                )
+            })?;
+        anyhow::ensure!(
+            status.success(),
+            "`{}` failed to split the debug info of `{}` ({status})",
+            objcopy.to_string_lossy(),
+            flavor.display()
+        );
+        Ok(())
+    };
+    run(&[
+        OsStr::new("--only-keep-debug"),
+        flavor.as_os_str(),
+        debug_path.as_os_str(),
+    ])?;
+    run(&[OsStr::new("--strip-debug"), flavor.as_os_str()])
+}
+
+/// Reads the CPU names of a fleet inventory, skipping those unknown to rustc for the target
+fn read_cpu_inventory(
+    rustc: &dyn RustcQueries,
+    path: &Path,
+    target_name: &str,
+    warnings: &mut Vec<String>,
+) -> anyhow::Result<BTreeSet<String>> {
+    let inventory = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the CPU inventory `{}`", path.display()))?;
+    let known = rustc.get_cpu_names(target_name)?;
+
+    let mut cpus = BTreeSet::new();
+    for line in inventory.lines() {
+        let cpu = line.split('#').next().unwrap_or_default().trim();
+        if cpu.is_empty() {
+            continue;
+        }
+        if known.contains(cpu) {
+            cpus.insert(cpu.to_owned());
+        } else {
+            let warning = format!(
+                "unknown CPU `{cpu}` in `{}` for target '{target_name}', skipped",
+                path.display()
+            );
+            eprintln!("{:>12} {warning}", style("Warning").bold().yellow());
+            warnings.push(warning);
+        }
+    }
+    anyhow::ensure!(
+        !cpus.is_empty(),
+        "No CPU known to rustc for target '{target_name}' in `{}`, see `--print target-cpus`",
+        path.display()
+    );
+    Ok(cpus)
+}
+
+/// Keeps a single binary per hash, the one with the least features,
+/// and records why each binary was kept or dropped
+fn dedup_binaries(binaries_desc: Vec<([u8; 32], BinaryDesc)>) -> Artifacts {
+    let num_binaries = binaries_desc.len();
+    let mut binaries_desc: Vec<(usize, [u8; 32], BinaryDesc)> = binaries_desc
+        .into_iter()
+        .enumerate()
+        .map(|(index, (hash, desc))| (index, hash, desc))
+        .collect();
+    let max_level =
+        |desc: &BinaryDesc| ranking::x86_max_level(desc.cpufeatures.iter().map(String::as_str));
+    binaries_desc.sort_unstable_by(|(_, h1, b1), (_, h2, b2)| {
+        // First, we sort based on the hash to detect duplicate
+        h1.cmp(h2)
+            // Then, to keep the flavor that runs on the most hosts.
+            // Feature sets that do not imply each other (aes and sha) can build
+            // the same binary, the one with the lowest microarchitecture level wins,
+            .then_with(|| max_level(b1).cmp(&max_level(b2)))
+            // then the one with less features, a subset of the other ones if any.
+            .then_with(|| b1.cpufeatures.len().cmp(&b2.cpufeatures.len()))
+            // Total order for reproducible fat binaries
+            .then_with(|| b1.cpufeatures.cmp(&b2.cpufeatures))
+    });
+
+    let mut verdicts = vec![String::new(); num_binaries];
+    let mut fallback_hash = None;
+    let mut bins = Vec::with_capacity(num_binaries);
+    let identical_groups = binaries_desc.into_iter().chunk_by(|(_, hash, _)| *hash);
+    for (hash, mut identical) in &identical_groups {
+        let Some((index, _, mut kept)) = identical.next() else {
+            continue;
+        };
+        verdicts[index] = format!("{}: kept (unique hash)", kept.description);
+        for (index, _, dropped) in identical {
+            // The same executable, selected as eagerly as the dropped flavor
+            kept.priority = kept.priority.max(dropped.priority);
+            verdicts[index] = if kept.cpufeatures.is_empty() {
+                format!(
+                    "{}: dropped (identical to the fallback)",
+                    dropped.description
+                )
+            } else {
+                format!(
+                    "{}: dropped (identical to flavor {})",
+                    dropped.description, kept.description
+                )
+            };
+        }
+        if kept.cpufeatures.is_empty() {
+            fallback_hash = Some(hash);
+        }
+        bins.push(kept);
+    }
+
+    Artifacts {
+        bins,
+        fallback_hash,
+        verdicts,
+    }
+}
+
+/// Identifies the executable format and machine from the file header,
+/// binaries packed together must match.
+fn executable_format(path: &Path) -> anyhow::Result<String> {
+    let read_header = || -> std::io::Result<String> {
+        let mut file = File::open(path)?;
+        let mut header = Vec::with_capacity(64);
+        file.by_ref().take(64).read_to_end(&mut header)?;
+
+        Ok(match header.as_slice() {
+            [0x7f, b'E', b'L', b'F', class, data, ..] if header.len() >= 20 => {
+                let machine = [header[18], header[19]];
+                let machine = if *data == 2 {
+                    u16::from_be_bytes(machine)
+                } else {
+                    u16::from_le_bytes(machine)
+                };
+                let bits = if *class == 2 { 64 } else { 32 };
+                format!("ELF{bits} (machine {machine:#x})")
+            }
+            [b'M', b'Z', ..] if header.len() >= 0x40 => {
+                // The PE header offset is at 0x3c, followed by "PE\0\0" and the machine
+                let offset =
+                    u32::from_le_bytes([header[0x3c], header[0x3d], header[0x3e], header[0x3f]]);
+                let mut pe = [0u8; 6];
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.read_exact(&mut pe)?;
+                format!("PE (machine {:#x})", u16::from_le_bytes([pe[4], pe[5]]))
+            }
+            [0xcf | 0xce, 0xfa, 0xed, 0xfe, c0, c1, c2, c3, ..] => {
+                format!(
+                    "Mach-O (cpu {:#x})",
+                    u32::from_le_bytes([*c0, *c1, *c2, *c3])
+                )
+            }
+            [0xfe, 0xed, 0xfa, 0xcf | 0xce, c0, c1, c2, c3, ..] => {
+                format!(
+                    "Mach-O (cpu {:#x})",
+                    u32::from_be_bytes([*c0, *c1, *c2, *c3])
+                )
+            }
+            _ => String::new(),
+        })
+    };
+
+    let format = read_header().with_context(|| format!("Failed to read `{}`", path.display()))?;
+    anyhow::ensure!(
+        !format.is_empty(),
+        "`{}` is not an ELF, PE or Mach-O executable",
+        path.display()
+    );
+    Ok(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(features: &[&str]) -> BinaryDesc {
+        BinaryDesc {
+            path: PathBuf::new(),
+            cpufeatures: features.iter().map(|f| f.to_string()).collect(),
+            min_glibc: None,
+            priority: None,
+            original_filename: None,
+            description: features.join(","),
+            fingerprint: None,
+        }
+    }
+
+    /// Features of the flavors kept when all the given builds are identical
+    fn kept(flavors: &[&[&str]]) -> Vec<Vec<String>> {
+        let binaries = flavors
+            .iter()
+            .map(|features| ([0; 32], desc(features)))
+            .collect();
+        dedup_binaries(binaries)
+            .bins
+            .into_iter()
+            .map(|bin| bin.cpufeatures)
+            .collect()
+    }
+
+    #[test]
+    fn unrelated_features_keep_a_deterministic_flavor() {
+        assert_eq!(kept(&[&["sha"], &["aes"]]), [["aes"]]);
+        assert_eq!(kept(&[&["aes"], &["sha"]]), [["aes"]]);
+    }
+
+    #[test]
+    fn subset_is_kept_within_a_level() {
+        assert_eq!(kept(&[&["avx2", "bmi2"], &["avx2"]]), [["avx2"]]);
+    }
+
+    #[test]
+    fn lowest_level_is_kept_regardless_of_weight() {
+        assert_eq!(kept(&[&["avx"], &["sse4.2"]]), [["sse4.2"]]);
+        // Both level 3, avx weighs less than avx2 but the flavor needs two features
+        assert_eq!(kept(&[&["avx", "aes"], &["avx2"]]), [["avx2"]]);
+    }
+
+    #[test]
+    fn linker_is_configured_for_each_target() {
+        let config = linker_config(
+            Path::new("rust-lld"),
+            ["x86_64-unknown-linux-musl", "specs/x86_64-custom.json"].into_iter(),
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            [
+                r#"target."x86_64-unknown-linux-musl".linker="rust-lld""#,
+                r#"target."x86_64-custom".linker="rust-lld""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_linker_path_is_made_absolute() {
+        let config = linker_config(
+            Path::new("tools/ld.lld"),
+            ["x86_64-unknown-linux-musl"].into_iter(),
+        )
+        .unwrap();
+        let linker = std::env::current_dir().unwrap().join("tools/ld.lld");
+        assert_eq!(
+            config,
+            [format!(
+                r#"target."x86_64-unknown-linux-musl".linker={}"#,
+                toml::Value::String(linker.to_str().unwrap().to_owned())
+            )]
+        );
+    }
+
+    #[test]
+    fn fallback_wins_over_identical_flavors() {
+        let artifacts = dedup_binaries(vec![([1; 32], desc(&["avx2"])), ([1; 32], desc(&[]))]);
+        assert_eq!(artifacts.bins.len(), 1);
+        assert!(artifacts.bins[0].cpufeatures.is_empty());
+        assert_eq!(artifacts.fallback_hash, Some([1; 32]));
+        assert_eq!(
+            artifacts.verdicts[0],
+            "avx2: dropped (identical to the fallback)"
+        );
+    }
+}
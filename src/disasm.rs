@@ -0,0 +1,124 @@
+//! `cargo multiarch disasm`: diffs the disassembly of two flavors,
+//! to check what a CPU feature changes in the generated code.
+//! `--emit-asm` writes the disassembly of a function for each flavor of a build.
+//!
+//! Addresses are stripped from the disassembly, they shift as soon as
+//! one function changes size and would drown the actual differences.
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::Context;
+use itertools::Itertools;
+
+/// OBJDUMP, else llvm-objdump, else binutils objdump
+pub(crate) fn find_disassembler() -> anyhow::Result<OsString> {
+    let candidates: Vec<OsString> = match std::env::var_os("OBJDUMP") {
+        Some(objdump) => vec![objdump],
+        None => vec!["llvm-objdump".into(), "objdump".into()],
+    };
+    candidates
+        .into_iter()
+        .find(|tool| {
+            Command::new(tool)
+                .arg("--version")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        })
+        .context(
+            "`disasm` and --emit-asm require llvm-objdump or objdump in PATH, install binutils, or set OBJDUMP to the llvm-objdump of `rustup component add llvm-tools`",
+        )
+}
+
+/// Disassembly of the executable sections, restricted to the functions
+/// whose demangled name contains `function` if given
+pub(crate) fn disassemble(
+    tool: &OsString,
+    executable: &Path,
+    function: Option<&str>,
+) -> anyhow::Result<String> {
+    let output = Command::new(tool)
+        .args(["-d", "-C", "--no-show-raw-insn"])
+        .arg(executable)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run `{}`", tool.to_string_lossy()))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "`{}` failed to disassemble `{}` ({})",
+        tool.to_string_lossy(),
+        executable.display(),
+        output.status
+    );
+    let disassembly = String::from_utf8_lossy(&output.stdout);
+
+    // Functions are separated by blank lines and start with `<address> <name>:`,
+    // the other blocks name the file and the sections
+    let functions: Vec<String> = disassembly
+        .split("\n\n")
+        .filter(|block| {
+            block.lines().next().is_some_and(|header| {
+                header.trim_end().ends_with(">:")
+                    && function.is_none_or(|function| header.contains(function))
+            })
+        })
+        .map(strip_addresses)
+        .collect();
+    anyhow::ensure!(
+        !functions.is_empty(),
+        "No function matching `{}` in `{}`",
+        function.unwrap_or_default(),
+        executable.display()
+    );
+    Ok(functions.join("\n\n") + "\n")
+}
+
+/// `0000000000401000 <main>:` becomes `<main>:`, `  401004:\tcall   401230 <foo>`
+/// becomes `\tcall   <foo>`
+fn strip_addresses(block: &str) -> String {
+    block
+        .lines()
+        .map(|line| {
+            let line = match line.trim_start().split_once([':', ' ']) {
+                Some((address, rest))
+                    if !address.is_empty() && address.chars().all(|c| c.is_ascii_hexdigit()) =>
+                {
+                    rest.trim_start_matches(' ')
+                }
+                _ => line,
+            };
+            // Branch targets: `<hex> <symbol+offset>`, llvm-objdump prefixes them with 0x
+            line.split(' ')
+                .filter(|word| {
+                    let hex = word.trim_start_matches("0x");
+                    !(!hex.is_empty()
+                        && hex.chars().all(|c| c.is_ascii_hexdigit())
+                        && line.contains(&format!("{word} <")))
+                })
+                .join(" ")
+        })
+        .join("\n")
+}
+
+/// `diff -u` of two disassembly files, empty if they are identical
+pub(crate) fn unified_diff(a: &Path, b: &Path) -> anyhow::Result<String> {
+    let output = Command::new("diff")
+        .arg("-u")
+        .args([a, b])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to run `diff`, install diffutils")?;
+    // 0: identical, 1: different, 2: trouble
+    anyhow::ensure!(
+        matches!(output.status.code(), Some(0 | 1)),
+        "`diff` failed to compare `{}` and `{}` ({})",
+        a.display(),
+        b.display(),
+        output.status
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -1,21 +1,240 @@
 use std::ffi::OsStr;
-use std::fs;
+use std::fs::{self, File, TryLockError};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use anyhow::Context;
 use escargot::CargoBuild;
 use indoc::formatdoc;
+use serde::Deserialize;
+use target_lexicon::Triple;
 
-use crate::cargo_msg_parser::CommandMessagesExt;
+use crate::cargo_msg_parser::CargoBuildExt;
+use crate::cli::{DispatcherAlloc, Ranking, StripFatbin};
+use crate::format;
+use crate::interrupt::PartialFile;
+use crate::rustc_queries::Rustc;
+
+/// Options of the generated dispatcher crate
+#[derive(Clone)]
+pub(crate) struct DispatcherOptions {
+    pub(crate) ranking: Ranking,
+    pub(crate) alloc: DispatcherAlloc,
+    // Faster patch generation for slightly larger patches
+    pub(crate) bsdiff_fast: bool,
+    // Each flavor is diffed against the next lower one instead of the fallback
+    pub(crate) delta_chain: bool,
+    // Flavors using these are skipped in virtual machines
+    pub(crate) hypervisor_ignore_features: Vec<String>,
+    pub(crate) strip: StripFatbin,
+    pub(crate) static_pie: bool,
+}
+
+impl DispatcherOptions {
+    /// Value of the cargo profile `strip` setting
+    fn strip_setting(&self) -> &'static str {
+        match self.strip {
+            StripFatbin::None => "none",
+            StripFatbin::Symbols => "symbols",
+            StripFatbin::Debuginfo => "debuginfo",
+        }
+    }
+}
 
 pub struct FatbinCrate {
     outdir: PathBuf,
     cargo_toml: PathBuf,
+    options: DispatcherOptions,
+    locked: bool, // Built with a vetted lockfile
+    // Per-user target directory shared across projects, keyed by target in `cargo_build`
+    shared_cache: Option<PathBuf>,
+    // --emit-dispatcher-src directory, none if not requested
+    emit_src: Option<PathBuf>,
+}
+
+/// Unstable features enabled by the dispatcher crate, they churn across nightlies
+const DISPATCHER_FEATURE_GATES: &[&str] = &["stdarch_internal"];
+
+/// Fails early with an actionable message, rather than an opaque error
+/// deep in the dispatcher build, if the nightly lacks a feature gate of the dispatcher.
+/// Probed once per invocation.
+fn check_dispatcher_toolchain(probe_dir: &Path) -> anyhow::Result<()> {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    let supported = match SUPPORTED.get() {
+        Some(supported) => *supported,
+        None => {
+            fs::create_dir_all(probe_dir)?;
+            let supported = Rustc::supports_feature_gates(DISPATCHER_FEATURE_GATES, probe_dir)?;
+            *SUPPORTED.get_or_init(|| supported)
+        }
+    };
+    anyhow::ensure!(
+        supported,
+        "Your nightly ({}) does not support the unstable feature(s) {} used by the dispatcher, \
+        it is too old or too recent for this version of multiarch-dispatch. \
+        Pin a nightly that supports them, e.g. `cargo +nightly-YYYY-MM-DD multiarch`",
+        Rustc::get_version().unwrap_or_else(|_| "unknown version".to_owned()),
+        DISPATCHER_FEATURE_GATES.join(", ")
+    );
+    Ok(())
+}
+
+/// Static PIE needs the target to link a self-relocating static executable
+/// and to honor `+crt-static`, otherwise the flags would be ignored or the binary broken.
+fn check_static_pie(target: &str) -> anyhow::Result<()> {
+    let spec: serde_json::Value = serde_json::from_str(&Rustc::get_target_spec_json(Some(target))?)
+        .with_context(|| format!("Failed to parse the target spec of '{target}'"))?;
+    let supported = |key: &str| spec[key].as_bool().unwrap_or(false);
+    anyhow::ensure!(
+        supported("static-position-independent-executables") && supported("crt-static-respected"),
+        "--dispatcher-static-pie is not supported for '{target}', \
+        its target spec does not allow static position-independent executables"
+    );
+    Ok(())
+}
+
+/// Per-user cache directory of this cargo-multiarch version
+fn user_cache_dir() -> anyhow::Result<PathBuf> {
+    let base = if let Some(dir) = std::env::var_os("CARGO_MULTIARCH_CACHE_DIR") {
+        PathBuf::from(dir)
+    } else if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA")
+            .map(PathBuf::from)
+            .context("LOCALAPPDATA is not set, set CARGO_MULTIARCH_CACHE_DIR instead")?
+            .join(clap::crate_name!())
+    } else if cfg!(target_os = "macos") {
+        std::env::home_dir()
+            .context("The home directory is unknown, set CARGO_MULTIARCH_CACHE_DIR instead")?
+            .join("Library/Caches")
+            .join(clap::crate_name!())
+    } else {
+        std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::home_dir().map(|home| home.join(".cache")))
+            .context("The home directory is unknown, set CARGO_MULTIARCH_CACHE_DIR instead")?
+            .join(clap::crate_name!())
+    };
+    Ok(base.join(format!("dispatcher-{}", clap::crate_version!())))
+}
+
+/// Exclusive lock on a shared dispatcher target directory.
+/// Cargo only locks the directory during the build, the fat binary must be copied out before
+/// another invocation rebuilds it with its own flavors.
+fn lock_shared_target_dir(dir: &Path) -> anyhow::Result<File> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create the dispatcher cache `{}`", dir.display()))?;
+    let lock_path = dir.join(".multiarch-lock");
+    let lock = File::create(&lock_path)
+        .with_context(|| format!("Failed to create `{}`", lock_path.display()))?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            eprintln!(
+                "{:>20} waiting for the dispatcher cache `{}`",
+                console::style("Blocking").cyan(),
+                dir.display()
+            );
+            lock.lock()
+                .with_context(|| format!("Failed to lock `{}`", lock_path.display()))?;
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("Failed to lock `{}`", lock_path.display()));
+        }
+    }
+    Ok(lock)
+}
+
+/// The [`format::ARTIFACTS_HASH_ENV`] hash of the artifacts description and the builds it lists
+fn artifacts_hash(artifacts_json_path: &Path) -> anyhow::Result<u64> {
+    let description = fs::read(artifacts_json_path)
+        .with_context(|| format!("Failed to read `{}`", artifacts_json_path.display()))?;
+    let artifacts: serde_json::Value = serde_json::from_slice(&description)
+        .with_context(|| format!("Failed to parse `{}`", artifacts_json_path.display()))?;
+    let paths = artifacts["bins"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|bin| bin["path"].as_str());
+    let mut hash = format::fnv1a(format::FNV_OFFSET, &description);
+    for path in paths {
+        let exe = fs::read(path).with_context(|| format!("Failed to read `{path}`"))?;
+        hash = format::fnv1a(hash, &exe);
+    }
+    Ok(hash)
+}
+
+/// Bytes embedded in a fat binary for a flavor, reported by the build script of the dispatcher
+#[derive(Deserialize)]
+pub(crate) struct EmbeddedSize {
+    pub(crate) flavor: String, // Comma-separated CPU features, empty for the fallback
+    pub(crate) bytes: u64,
+}
+
+/// A fat binary and the size of its embedded flavors, the compressed base first
+pub(crate) struct FatbinBuild {
+    pub(crate) path: PathBuf,
+    pub(crate) sizes: Vec<EmbeddedSize>,
+}
+
+/// The OUT_DIR of the last multiarch-dispatch build under `build_dir`, e.g. `<target>/release`
+fn dispatch_out_dir(build_dir: &Path) -> anyhow::Result<PathBuf> {
+    let build_scripts_dir = build_dir.join("build");
+    fs::read_dir(&build_scripts_dir)
+        .with_context(|| format!("Failed to read `{}`", build_scripts_dir.display()))?
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("multiarch-dispatch-")
+        })
+        .map(|entry| entry.path().join("out"))
+        .filter_map(|out_dir| {
+            let generated = fs::metadata(out_dir.join("fatbin.rs"))
+                .ok()?
+                .modified()
+                .ok()?;
+            Some((generated, out_dir))
+        })
+        .max()
+        .map(|(_, out_dir)| out_dir)
+        .context("The build script of multiarch-dispatch generated no `fatbin.rs`")
 }
 
 impl FatbinCrate {
-    pub(crate) fn generate(outdir: PathBuf) -> anyhow::Result<Self> {
-        let name = &format!("multiarch-dispatch-autogen");
+    pub(crate) fn options(&self) -> &DispatcherOptions {
+        &self.options
+    }
+
+    /// The dispatcher may target another ABI or libc than the flavors
+    /// but it must run on the same OS and CPU to load and execute them.
+    pub(crate) fn check_dispatcher_target(
+        dispatcher: &Triple,
+        flavors: &Triple,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            dispatcher.architecture == flavors.architecture
+                && dispatcher.pointer_width().ok() == flavors.pointer_width().ok()
+                && dispatcher.operating_system == flavors.operating_system,
+            "The dispatcher target '{dispatcher}' cannot execute flavors built for '{flavors}', \
+            the architecture, pointer width and operating system must match"
+        );
+        Ok(())
+    }
+
+    /// Generates the dispatcher crate in `outdir`.
+    /// If a `lockfile` is given, the dispatcher dependencies are locked to it.
+    /// With `shared_cache`, it is built in a per-user target directory instead of `outdir`.
+    /// With `emit_src`, the sources of each build are copied there.
+    pub(crate) fn generate(
+        outdir: PathBuf,
+        options: DispatcherOptions,
+        lockfile: Option<&Path>,
+        shared_cache: bool,
+        emit_src: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let name = "multiarch-dispatch-autogen";
         let root_dir = outdir.join(name);
         let srcdir = root_dir.join("src");
         let cargo_toml = root_dir.join("Cargo.toml");
@@ -24,9 +243,27 @@ impl FatbinCrate {
             .join("src")
             .join("multiarch-dispatch");
 
+        let mut features = Vec::new();
+        if options.ranking == Ranking::Simple {
+            features.push(r#""simple-ranking""#);
+        }
+        let global_allocator = match options.alloc {
+            DispatcherAlloc::Default => "",
+            DispatcherAlloc::System => {
+                "#[global_allocator]\nstatic GLOBAL: std::alloc::System = std::alloc::System;"
+            }
+            DispatcherAlloc::Minimal => {
+                features.push(r#""bump-alloc""#);
+                "#[global_allocator]\nstatic GLOBAL: multiarch_dispatch::BumpAlloc = multiarch_dispatch::BumpAlloc::new();"
+            }
+        };
+
+        let strip = options.strip_setting();
+
         let dispatcher = format!(
-            r#"multiarch-dispatch = {{ path = "{}" }}"#,
-            local_dispatcher.to_string_lossy().replace('\\', "/")
+            r#"multiarch-dispatch = {{ path = "{}", features = [{}] }}"#,
+            local_dispatcher.to_string_lossy().replace('\\', "/"),
+            features.join(", ")
         );
 
         let manifest = formatdoc!(
@@ -41,7 +278,7 @@ impl FatbinCrate {
 
             [profile.release]
             lto = true
-            strip = "symbols"
+            strip = "{strip}"
             opt-level = "z"
             codegen-units = 1
             panic = "abort"
@@ -54,14 +291,35 @@ impl FatbinCrate {
             r#"
             #![no_main]
             pub use multiarch_dispatch::main;
+
+            {global_allocator}
         "#
         );
 
         std::fs::create_dir_all(&srcdir)?;
         std::fs::write(&cargo_toml, manifest)?;
         std::fs::write(main_rs, main)?;
+        if let Some(lockfile) = lockfile {
+            fs::copy(lockfile, root_dir.join("Cargo.lock")).with_context(|| {
+                format!(
+                    "Failed to copy the dispatcher lockfile `{}`",
+                    lockfile.display()
+                )
+            })?;
+        }
 
-        Ok(Self { outdir, cargo_toml })
+        Ok(Self {
+            outdir,
+            cargo_toml,
+            options,
+            locked: lockfile.is_some(),
+            shared_cache: if shared_cache {
+                Some(user_cache_dir()?)
+            } else {
+                None
+            },
+            emit_src,
+        })
     }
 
     pub(crate) fn cargo_build(
@@ -69,31 +327,178 @@ impl FatbinCrate {
         target: &str,
         artifacts_json_path: &Path,
         original_filename: &OsStr,
-    ) -> anyhow::Result<PathBuf> {
+        cargo_config: &[String],
+        build_info: Option<&str>,
+        warnings: &mut Vec<String>,
+    ) -> anyhow::Result<FatbinBuild> {
+        check_dispatcher_toolchain(&self.outdir.join("multiarch-toolchain-probe"))?;
+
         // We do not propagate `CARGO_UNSTABLE_BUILD_STD` since if `panic_abort` is not
         // specified, the build of the runner will fail (since its profile specifies `panic=abort`).
         // A proper fix could be to clear the whole environment before spawning this `cargo build`,
         // but until `CargoBuild` exposes the `Command` or this function, we can only do this.
+        // Cargo names the output directory of a custom target spec after its file stem
+        let target_subdir = Path::new(target).file_stem().unwrap_or(OsStr::new(target));
+        let (target_dir, _lock) = match &self.shared_cache {
+            Some(cache) => {
+                let dir = cache.join(target_subdir);
+                let lock = lock_shared_target_dir(&dir)?;
+                (dir, Some(lock))
+            }
+            None => (self.outdir.clone(), None),
+        };
         let cargo = CargoBuild::new()
             .release()
             .target(target)
-            .target_dir(&self.outdir)
+            .target_dir(&target_dir)
             .manifest_path(&self.cargo_toml)
+            .args(
+                cargo_config
+                    .iter()
+                    .flat_map(|config| ["--config", config.as_str()]),
+            )
+            // Config files and --config overrides take precedence over the manifest profile
+            .args([
+                "--config".to_owned(),
+                format!(
+                    r#"profile.release.strip="{}""#,
+                    self.options.strip_setting()
+                ),
+            ])
             .env_remove("CARGO_UNSTABLE_BUILD_STD")
-            .env("MULTIARCH_ARTIFACTS", artifacts_json_path);
-
-        let cargo = cargo
-            .exec()
-            .context("Failed to execute cargo to build the fatbin")?;
+            .env("MULTIARCH_ARTIFACTS", artifacts_json_path)
+            .env(
+                format::ARTIFACTS_HASH_ENV,
+                format!("{:016x}", artifacts_hash(artifacts_json_path)?),
+            );
+        let cargo = if self.locked {
+            // Fail rather than silently update a vetted lockfile
+            cargo.arg("--locked")
+        } else {
+            cargo
+        };
+        let cargo = if self.options.static_pie {
+            check_static_pie(target)?;
+            // Only the dispatcher is affected, the embedded flavors are data
+            let mut rust_flags = Rustc::get_env_rustflags();
+            rust_flags.extend([
+                "-Ctarget-feature=+crt-static".to_owned(),
+                "-Crelocation-model=pie".to_owned(),
+            ]);
+            let encoded_rust_flags = Rustc::encode_rustflags(&rust_flags);
+            cargo
+                .env("CARGO_ENCODED_RUSTFLAGS", encoded_rust_flags)
+                .env_remove("RUSTFLAGS")
+        } else {
+            cargo
+        };
+        let cargo = match build_info {
+            Some(build_info) => cargo.env("MULTIARCH_BUILD_INFO", build_info),
+            None => cargo.env_remove("MULTIARCH_BUILD_INFO"),
+        };
+        let cargo = if self.options.delta_chain {
+            cargo.env("MULTIARCH_DELTA_CHAIN", "1")
+        } else {
+            cargo.env_remove("MULTIARCH_DELTA_CHAIN")
+        };
+        let cargo = match self.options.hypervisor_ignore_features.as_slice() {
+            [] => cargo.env_remove("MULTIARCH_HYPERVISOR_IGNORE"),
+            features => cargo.env("MULTIARCH_HYPERVISOR_IGNORE", features.join(",")),
+        };
+        let cargo = if self.options.bsdiff_fast {
+            cargo.env("MULTIARCH_BSDIFF_FAST", "1")
+        } else {
+            cargo.env_remove("MULTIARCH_BSDIFF_FAST")
+        };
 
         let bin_path = cargo
-            .find_executable()?
-            .ok_or_else(|| anyhow::anyhow!("Failed to build the runner"))?;
+            .exec_find_executable("the dispatcher", None, warnings)
+            .with_context(|| match Rustc::get_host_target() {
+                // The usual culprit is the linker, cargo defaults to the host `cc`
+                Ok(host) if host != target => format!(
+                    "Failed to cross-build the fatbin for '{target}' from '{host}', \
+                    check that a linker for '{target}' is installed and configured, \
+                    for example with `--linker <LINKER>`"
+                ),
+                _ => format!(
+                    "Failed to build the dispatcher crate `{}`, it requires a recent nightly toolchain \
+                    and the standard library for '{target}'",
+                    self.cargo_toml.display()
+                ),
+            })?
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "cargo built the dispatcher crate `{}` but reported no executable",
+                    self.cargo_toml.display()
+                )
+            })?;
+
+        // Read while locked, the shared cache would hold the build of the next project
+        let out_dir = dispatch_out_dir(&target_dir.join(target_subdir).join("release"))?;
+        let sizes_path = out_dir.join("fatbin-sizes.json");
+        let sizes: Vec<EmbeddedSize> = fs::read(&sizes_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|sizes| Ok(serde_json::from_slice(&sizes)?))
+            .with_context(|| format!("Failed to read `{}`", sizes_path.display()))?;
+        if let Some(emit_src) = &self.emit_src {
+            self.copy_sources(
+                &out_dir,
+                artifacts_json_path,
+                &emit_src.join(original_filename),
+            )?;
+        }
 
-        let mut output_path = bin_path.clone();
-        output_path.set_file_name(original_filename);
-        fs::rename(&bin_path, &output_path)?;
+        let output_path = if self.shared_cache.is_some() {
+            // Copied while locked, the cached binary is overwritten by the next project
+            let output_dir = self.outdir.join(target_subdir).join("release");
+            fs::create_dir_all(&output_dir)?;
+            let output_path = output_dir.join(original_filename);
+            let _partial = PartialFile::new(output_path.clone());
+            fs::copy(&bin_path, &output_path)?;
+            output_path
+        } else {
+            let mut output_path = bin_path.clone();
+            output_path.set_file_name(original_filename);
+            fs::rename(&bin_path, &output_path)?;
+            output_path
+        };
+
+        Ok(FatbinBuild {
+            path: output_path,
+            sizes,
+        })
+    }
+
+    /// Copies the generated crate, the artifacts it embeds and the `fatbin.rs` of its build
+    /// from the OUT_DIR of multiarch-dispatch, to `dir`
+    fn copy_sources(
+        &self,
+        out_dir: &Path,
+        artifacts_json_path: &Path,
+        dir: &Path,
+    ) -> anyhow::Result<()> {
+        let root_dir = self.cargo_toml.parent().unwrap_or(Path::new("."));
 
-        Ok(output_path)
+        fs::create_dir_all(dir.join("src"))
+            .with_context(|| format!("Failed to create directory `{}`", dir.display()))?;
+        let copies = [
+            (root_dir.join("Cargo.toml"), dir.join("Cargo.toml")),
+            (root_dir.join("Cargo.lock"), dir.join("Cargo.lock")),
+            (
+                root_dir.join("src").join("main.rs"),
+                dir.join("src").join("main.rs"),
+            ),
+            (
+                artifacts_json_path.to_owned(),
+                dir.join("multiarch-artifacts.json"),
+            ),
+            (out_dir.join("fatbin.rs"), dir.join("fatbin.rs")),
+        ];
+        for (from, to) in copies {
+            fs::copy(&from, &to).with_context(|| {
+                format!("Failed to copy `{}` to `{}`", from.display(), to.display())
+            })?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,222 @@
+use indoc::formatdoc;
+use itertools::Itertools;
+
+/// Generates a POSIX shell launcher that runs the best flavor for the host CPU.
+/// `flavors` are the file names of the flavors, located next to the script,
+/// the CPU features they require and their configured priority.
+///
+/// CPU features are read from /proc/cpuinfo, so the launcher only selects optimized flavors on Linux.
+/// The flavor with the highest priority wins, then the one requiring the most features,
+/// like `--ranking simple`.
+pub(crate) fn posix_launcher(
+    bin_name: &str,
+    flavors: &[(String, Vec<String>, Option<u32>)],
+) -> String {
+    let (fallbacks, optimized): (Vec<_>, Vec<_>) = flavors
+        .iter()
+        .partition(|(_, cpufeatures, _)| cpufeatures.is_empty());
+
+    let candidates = optimized
+        .iter()
+        .sorted_by(|(_, f1, p1), (_, f2, p2)| p2.cmp(p1).then_with(|| f2.len().cmp(&f1.len())))
+        .map(|(file_name, cpufeatures, _)| {
+            let condition = cpufeatures
+                .iter()
+                .filter_map(|feature| cpuinfo_flag(feature))
+                .map(|flag| format!("has {flag}"))
+                .join(" && ");
+            // Only features invisible in /proc/cpuinfo
+            let condition = if condition.is_empty() {
+                "true".to_owned()
+            } else {
+                condition
+            };
+            format!(r#"if {condition}; then exec "$dir/{file_name}" "$@"; fi"#)
+        })
+        .join("\n");
+
+    let fallback = fallbacks
+        .first()
+        .map(|(file_name, _, _)| format!(r#"exec "$dir/{file_name}" "$@""#))
+        .unwrap_or_else(|| {
+            r#"echo "No flavor of this program supports this CPU" >&2; exit 69"#.to_owned()
+        });
+
+    formatdoc!(
+        r#"
+        #!/bin/sh
+        # Generated by cargo-multiarch, runs the best flavor of `{bin_name}` for this CPU
+        dir=$(dirname "$0")
+        flags=" $(grep -m1 -E '^(flags|Features)' /proc/cpuinfo 2>/dev/null | cut -d: -f2) "
+        has() {{ case "$flags" in *" $1 "*) return 0;; esac; return 1; }}
+        {candidates}
+        {fallback}
+        "#
+    )
+}
+
+/// Linux /proc/cpuinfo names the features differently from rustc for some of them.
+/// `cpufeatures` include the implied features, e.g. `cmpxchg16b` and `sse3` for x86-64-v2,
+/// a flag spelled like rustc where the kernel does not would never match.
+/// `None` for features the kernel does not report: the aarch64 architecture versions,
+/// checked through the features they imply, and system features unused by programs.
+fn cpuinfo_flag(feature: &str) -> Option<&str> {
+    let is_aarch64_version = feature
+        .strip_prefix('v')
+        .and_then(|version| version.strip_suffix('a'))
+        .is_some_and(|version| version.chars().all(|c| c.is_ascii_digit() || c == '.'));
+    if is_aarch64_version || matches!(feature, "lor" | "pan" | "pmuv3" | "ras" | "spe" | "vh") {
+        return None;
+    }
+    Some(match feature {
+        // x86
+        "sse3" => "pni",
+        "sse4.1" => "sse4_1",
+        "sse4.2" => "sse4_2",
+        "lzcnt" => "abm",
+        "cmpxchg16b" => "cx16",
+        "lahfsahf" => "lahf_lm",
+        "ermsb" => "erms",
+        "prfchw" => "3dnowprefetch",
+        "sha" => "sha_ni",
+        "x87" => "fpu",
+        "avx512bf16" => "avx512_bf16",
+        "avx512bitalg" => "avx512_bitalg",
+        "avx512fp16" => "avx512_fp16",
+        "avx512vbmi2" => "avx512_vbmi2",
+        "avx512vnni" => "avx512_vnni",
+        "avx512vp2intersect" => "avx512_vp2intersect",
+        "avx512vpopcntdq" => "avx512_vpopcntdq",
+        "avxifma" => "avx_ifma",
+        "avxneconvert" => "avx_ne_convert",
+        "avxvnni" => "avx_vnni",
+        "avxvnniint8" => "avx_vnni_int8",
+        "avxvnniint16" => "avx_vnni_int16",
+        "amx-bf16" => "amx_bf16",
+        "amx-complex" => "amx_complex",
+        "amx-fp16" => "amx_fp16",
+        "amx-int8" => "amx_int8",
+        "amx-tile" => "amx_tile",
+        // aarch64
+        "neon" => "asimd",
+        "fp16" => "fphp",
+        "fhm" => "asimdfhm",
+        "dotprod" => "asimddp",
+        "rdm" => "asimdrdm",
+        "rcpc" => "lrcpc",
+        "rcpc2" => "ilrcpc",
+        "crc" => "crc32",
+        "lse" => "atomics",
+        "lse2" => "uscat",
+        "jsconv" => "jscvt",
+        "frintts" => "frint",
+        "dpb" => "dcpop",
+        "dpb2" => "dcpodp",
+        "rand" => "rng",
+        "f32mm" => "svef32mm",
+        "f64mm" => "svef64mm",
+        "sve2-aes" => "sveaes",
+        "sve2-bitperm" => "svebitperm",
+        "sve2-sha3" => "svesha3",
+        "sve2-sm4" => "svesm4",
+        other => other,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flags of a Haswell, the first x86-64-v3 CPU
+    const HASWELL_FLAGS: &str =
+        "fpu vme de pse tsc msr pae mce cx8 apic sep mtrr pge mca cmov pat pse36 \
+        clflush dts acpi mmx fxsr sse sse2 ss ht tm pbe syscall nx pdpe1gb rdtscp lm constant_tsc \
+        arch_perfmon pebs bts rep_good nopl xtopology nonstop_tsc cpuid aperfmperf pni pclmulqdq \
+        dtes64 monitor ds_cpl vmx smx est tm2 ssse3 sdbg fma cx16 xtpr pdcm pcid sse4_1 sse4_2 \
+        x2apic movbe popcnt tsc_deadline_timer aes xsave avx f16c rdrand lahf_lm abm cpuid_fault \
+        epb invpcid_single pti ssbd ibrs ibpb stibp tpr_shadow vnmi flexpriority ept vpid ept_ad \
+        fsgsbase tsc_adjust bmi1 avx2 smep bmi2 erms invpcid xsaveopt dtherm ida arat pln pts";
+
+    /// The flags checked by the launcher for the flavor with `features`
+    fn checked_flags(features: &[&str]) -> Vec<String> {
+        let flavors = [
+            ("app-fallback".to_owned(), vec![], None),
+            (
+                "app-flavor".to_owned(),
+                features.iter().map(|f| f.to_string()).collect(),
+                None,
+            ),
+        ];
+        let script = posix_launcher("app", &flavors);
+        let condition = script
+            .lines()
+            .find_map(|line| {
+                line.strip_prefix("if ")?
+                    .split_once("; then")
+                    .map(|(cond, _)| cond)
+            })
+            .expect("no flavor condition in the launcher");
+        condition
+            .split(" && ")
+            .map(|check| check.trim_start_matches("has ").to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn v2_and_v3_flavors_match_a_haswell() {
+        // rustc's effective features on top of x86-64, implied ones included
+        let v2 = [
+            "cmpxchg16b",
+            "lahfsahf",
+            "popcnt",
+            "sse3",
+            "sse4.1",
+            "sse4.2",
+            "ssse3",
+        ];
+        let v3 = [
+            "avx",
+            "avx2",
+            "bmi1",
+            "bmi2",
+            "cmpxchg16b",
+            "f16c",
+            "fma",
+            "lahfsahf",
+            "lzcnt",
+            "movbe",
+            "popcnt",
+            "sse3",
+            "sse4.1",
+            "sse4.2",
+            "ssse3",
+            "xsave",
+        ];
+        let host: Vec<&str> = HASWELL_FLAGS.split_whitespace().collect();
+        for features in [&v2[..], &v3[..], &["ermsb", "fxsr", "pclmulqdq", "aes"][..]] {
+            for flag in checked_flags(features) {
+                assert!(
+                    host.contains(&flag.as_str()),
+                    "`{flag}` is not a cpuinfo flag"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn avx512_flavor_is_not_selected_on_a_haswell() {
+        let host: Vec<&str> = HASWELL_FLAGS.split_whitespace().collect();
+        let flags = checked_flags(&["avx2", "avx512f", "avx512vnni"]);
+        assert!(flags.contains(&"avx512_vnni".to_owned()));
+        assert!(!flags.iter().all(|flag| host.contains(&flag.as_str())));
+    }
+
+    #[test]
+    fn aarch64_versions_are_checked_through_their_features() {
+        assert_eq!(
+            checked_flags(&["crc", "lse", "rdm", "v8.1a"]),
+            ["crc32", "atomics", "asimdrdm"]
+        );
+        assert_eq!(checked_flags(&["vaes"]), ["vaes"]);
+    }
+}
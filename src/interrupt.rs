@@ -0,0 +1,84 @@
+//! Ctrl-C during a build: the cargo builds in flight are killed, the progress bar is cleared
+//! and the files being written are removed before exiting with the conventional 130 status,
+//! so that the next run does not pick up a truncated flavor or artifacts description.
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use anyhow::Context;
+use indicatif::ProgressBar;
+
+use crate::cargo_msg_parser::kill_process_tree;
+
+struct State {
+    progress: Option<ProgressBar>,
+    // Cargo processes, each leading its own process group
+    children: Vec<u32>,
+    partial_files: Vec<PathBuf>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    progress: None,
+    children: Vec::new(),
+    partial_files: Vec::new(),
+});
+
+fn state() -> MutexGuard<'static, State> {
+    // The handler exits the process, the state is never left inconsistent
+    STATE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Installs the handler once, `progress` is cleared on interrupt
+pub(crate) fn install(progress: &ProgressBar) -> anyhow::Result<()> {
+    let installed = state().progress.replace(progress.clone()).is_some();
+    if !installed {
+        ctrlc::set_handler(on_interrupt).context("Failed to install the Ctrl-C handler")?;
+    }
+    Ok(())
+}
+
+fn on_interrupt() {
+    // Held until exit, the build loop blocks instead of writing more files
+    let state = state();
+    if let Some(progress) = &state.progress {
+        progress.finish_and_clear();
+    }
+    for &pid in &state.children {
+        kill_process_tree(pid);
+    }
+    for path in &state.partial_files {
+        let _ = std::fs::remove_file(path);
+    }
+    std::process::exit(130);
+}
+
+/// A cargo process killed on interrupt until dropped
+pub(crate) struct ChildGuard(u32);
+
+impl ChildGuard {
+    pub(crate) fn new(pid: u32) -> Self {
+        state().children.push(pid);
+        Self(pid)
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        state().children.retain(|&pid| pid != self.0);
+    }
+}
+
+/// A file removed on interrupt until dropped, once it is complete
+pub(crate) struct PartialFile(PathBuf);
+
+impl PartialFile {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        state().partial_files.push(path.clone());
+        Self(path)
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        state().partial_files.retain(|path| *path != self.0);
+    }
+}
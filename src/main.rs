@@ -1,18 +1,48 @@
 use anyhow::{self, Ok};
 use clap::Parser;
 use compile_multiarch::Multiarch;
+use serde_json::json;
 
 use crate::rustc_queries::Rustc;
 
+mod bundle;
 mod cargo_config_loader;
 mod cargo_msg_parser;
 mod cli;
 mod compile_multiarch;
+mod disasm;
+#[path = "multiarch-format/src/format.rs"]
+#[allow(dead_code)] // The fat binary is generated and read by the dispatcher crate
+mod format;
 mod gen_fatbin_pkg;
+mod gen_launcher_script;
+mod interrupt;
+mod oci;
+#[path = "multiarch-dispatch/src/ranking.rs"]
+mod ranking;
 mod rustc_queries;
+mod selftest;
+mod timings;
+mod unpack_fatbin;
+mod verify_fatbin;
 
 fn main() -> anyhow::Result<()> {
-    let cli::Cargo::Multiarch(args) = cli::Cargo::parse();
+    let cli::Cargo::Multiarch(mut args) = cli::Cargo::parse();
+
+    // Auto is console's default detection
+    if args.color != cli::Color::Auto {
+        let enabled = args.color == cli::Color::Always;
+        console::set_colors_enabled(enabled);
+        console::set_colors_enabled_stderr(enabled);
+    }
+
+    if let Some(query) = args
+        .print
+        .filter(|_| args.print_format == cli::PrintFormat::Json)
+    {
+        println!("{:#}", query_json(query, &args)?);
+        return Ok(());
+    }
 
     if let Some(query) = args.print {
         let info = match query {
@@ -22,15 +52,79 @@ fn main() -> anyhow::Result<()> {
                 args.target.as_deref(),
                 args.target_cpu.as_deref(),
             ),
+            cli::Print::TargetSpecJson => Rustc::get_target_spec_json(args.target.as_deref()),
+            // Queries on the workspace configuration
+            cli::Print::SelectedFlavor => {
+                let target_cpu = args.target_cpu.clone();
+                Multiarch::from_args(args)
+                    .and_then(|multiarch| multiarch.describe_selected_flavor(target_cpu.as_deref()))
+            }
+            cli::Print::BuildPlan => {
+                Multiarch::from_args(args).and_then(|multiarch| multiarch.describe_build_plan())
+            }
+            cli::Print::ConfigSchema => Ok(cargo_config_loader::CONFIG_SCHEMA.to_owned()),
+            cli::Print::RustcVersion => Ok(Rustc::get_toolchain_summary().for_humans()),
         }?;
         println!("{}", info);
         return Ok(());
     }
 
+    // Runs the fat binary, no build involved
+    if let Some(cli::Command::Verify(verify)) = &args.command {
+        return verify_fatbin::verify(&verify.fatbin);
+    }
+
     anyhow::ensure!(
         Rustc::is_nightly(),
         "You must run cargo multivers with Rust nightly channel. For example, you can run: `cargo +nightly multivers`"
     );
 
-    Multiarch::from_args(args)?.compile_workspace()
+    if args.macho_universal {
+        return Multiarch::compile_macho_universal(args);
+    }
+
+    match args.command.take() {
+        Some(cli::Command::Pack(pack)) => return Multiarch::from_args(args)?.pack(pack),
+        Some(cli::Command::Disasm(disasm)) => return Multiarch::from_args(args)?.disasm(disasm),
+        Some(cli::Command::AddFlavor(add)) => return Multiarch::from_args(args)?.add_flavor(add),
+        _ => {}
+    }
+
+    Multiarch::from_args(args)?.compile_workspace()?;
+    Ok(())
+}
+
+/// --print queries for scripts
+fn query_json(query: cli::Print, args: &cli::Args) -> anyhow::Result<serde_json::Value> {
+    let target = Rustc::target_triple_or_host(args.target.as_deref())?;
+    Ok(match query {
+        cli::Print::TargetList => {
+            let targets = Rustc::get_target_list()?;
+            json!(targets
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>())
+        }
+        cli::Print::TargetCpus => json!({
+            "target": target,
+            "cpus": Rustc::list_cpus(&target)?,
+        }),
+        cli::Print::TargetCpuFeatures => {
+            let cpu = Rustc::target_cpu_or_host(args.target_cpu.as_deref())?;
+            json!({
+                "target": target,
+                "cpu": cpu,
+                "features": Rustc::list_features(&target, &cpu)?,
+            })
+        }
+        cli::Print::TargetSpecJson => {
+            serde_json::from_str(&Rustc::get_target_spec_json(Some(&target))?)?
+        }
+        cli::Print::ConfigSchema => cargo_config_loader::config_json_schema(),
+        cli::Print::RustcVersion => serde_json::to_value(Rustc::get_toolchain_summary())?,
+        cli::Print::SelectedFlavor | cli::Print::BuildPlan => {
+            anyhow::bail!("`--print-format json` is not available for this query")
+        }
+    })
 }
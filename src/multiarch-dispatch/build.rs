@@ -3,33 +3,110 @@
 //! It reads a JSON file that contains a set of paths to executables and their dependency on CPU features
 //! from the environment variable `MULTIARCH_ARTIFACTS`.
 //! Then, it generates a Rust file that contains the source and the patches.
-use std::fs::File;
-use std::io::{BufReader, Write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use proc_exit::sysexits::io_to_sysexists;
-use zstd;
 use qbsdiff::Bsdiff;
 use quote::quote;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use proc_exit::Exit;
 
+#[path = "../multiarch-format/src/format.rs"]
+#[allow(dead_code)] // The verification is done by the dispatcher
+mod format;
+use format::{check_executable, fnv1a, parse_glibc_version, ARTIFACTS_HASH_ENV, BUILD_INFO_MARKER, FNV_OFFSET, FORMAT_VERSION, FORMAT_VERSION_MARKER};
+#[path = "src/ranking.rs"]
+#[allow(dead_code)] // The ranking is done by the dispatcher
+mod ranking;
+
 #[derive(Default, Deserialize)]
 struct BinaryDesc {
     path: PathBuf,
     // Empty for the default fallback binary
     cpufeatures: Vec<String>,
+    // Minimum glibc version of the host, e.g. "2.34"
+    #[serde(default)]
+    min_glibc: Option<String>,
+    // Position in the artifacts file, the builds are then sorted
+    #[serde(skip)]
+    order: usize,
+    // Preferred over the ranking, the highest first
+    #[serde(default)]
+    priority: Option<u32>,
+}
+
+impl BinaryDesc {
+    /// (0, 0) without requirement
+    fn min_glibc(&self) -> Result<(u32, u32), Exit> {
+        let Some(version) = &self.min_glibc else {
+            return Ok((0, 0));
+        };
+        parse_glibc_version(version).ok_or_else(|| {
+            proc_exit::sysexits::DATA_ERR.with_message(format!(
+                "Invalid min_glibc {version:?} of {}, expected <major>.<minor>",
+                self.path.display()
+            ))
+        })
+    }
+}
+
+/// Bytes embedded for a flavor, written next to the generated sources
+/// for the size breakdown of `cargo multiarch --max-fatbin-size`
+#[derive(Serialize)]
+struct EmbeddedSize {
+    flavor: String, // Comma-separated CPU features, empty for the fallback
+    bytes: usize,
 }
 
 #[derive(Default, Deserialize)]
 struct Artifacts {
     bins: Vec<BinaryDesc>,
+    // FNV-1a of the artifacts file, the start of the `ARTIFACTS_HASH_ENV` hash
+    #[serde(skip)]
+    description_hash: u64,
+}
+
+/// zstd with the content size in the frame header,
+/// so that the dispatcher can allocate the executable upfront
+fn compress(source: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = zstd::stream::Encoder::new(Vec::new(), 3)?;
+    encoder.include_contentsize(true)?;
+    encoder.set_pledged_src_size(Some(source.len() as u64))?;
+    encoder.write_all(source)?;
+    encoder.finish()
 }
 
-fn bsdiff(source: &[u8], target: &[u8]) -> Result<Vec<u8>, Exit> {
+/// Reads a build to embed, see [`check_executable`]
+fn read_executable(path: &Path, what: &str) -> Result<Vec<u8>, Exit> {
+    let content = std::fs::read(path).map_err(|e| {
+        proc_exit::sysexits::IO_ERR.with_message(format!(
+            "Failed to read {what} {}: {e}",
+            path.display(),
+        ))
+    })?;
+    check_executable(what, path, &content).map_err(|e| proc_exit::sysexits::DATA_ERR.with_message(e))?;
+    Ok(content)
+}
+
+/// Matches shorter than this are skipped when searching, the default is 12.
+/// Skipping more speeds up diffing at the cost of larger patches.
+const FAST_SMALL_MATCH: usize = 32;
+
+/// Magic number of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Patches are computed between the uncompressed executables, the dispatcher applies them
+/// to the decoded fallback. Diffing compressed data would give patches as large as the flavor.
+fn bsdiff(source: &[u8], target: &[u8], fast: bool) -> Result<Vec<u8>, Exit> {
+    if source.starts_with(&ZSTD_MAGIC) || target.starts_with(&ZSTD_MAGIC) {
+        return Err(proc_exit::sysexits::SOFTWARE_ERR
+            .with_message("Refusing to generate a patch between zstd-compressed executables"));
+    }
     let mut patch = Vec::new();
-    Bsdiff::new(source, target)
-        .compare(std::io::Cursor::new(&mut patch))
+    let diff = Bsdiff::new(source, target);
+    let diff = if fast { diff.small_match(FAST_SMALL_MATCH) } else { diff };
+    diff.compare(std::io::Cursor::new(&mut patch))
         .map_err(|_| proc_exit::sysexits::IO_ERR.with_message("Failed to generate a patch"))?;
     Ok(patch)
 }
@@ -46,19 +123,23 @@ impl Artifacts {
 
     fn from_path(path: impl AsRef<Path>) -> Result<Self, Exit> {
         let path = path.as_ref();
-        let file = File::open(path).map_err(|_| {
+        let description = std::fs::read(path).map_err(|_| {
             proc_exit::sysexits::IO_ERR.with_message(format!(
                 "Failed to open the build artifacts file {}",
                 path.display()
             ))
         })?;
         let mut bins: Self =
-            serde_json::from_reader(BufReader::new(file)).map_err(|_| {
+            serde_json::from_slice(&description).map_err(|_| {
                 proc_exit::sysexits::DATA_ERR.with_message(format!(
                     "Failed to parse the artifacts description file {}",
                     path.display(),
                 ))
             })?;
+        bins.description_hash = fnv1a(FNV_OFFSET, &description);
+        for (order, bin) in bins.bins.iter_mut().enumerate() {
+            bin.order = order;
+        }
 
         bins.sort_by_features();
         bins.print_rerun();
@@ -67,9 +148,12 @@ impl Artifacts {
     }
 
     /// Sort the builds to put the ones requiring more features at the head
+    /// The order is total so that the fat binary is reproducible,
+    /// it doesn't depend on the order of the artifacts file.
     fn sort_by_features(&mut self) {
         self.bins.sort_unstable_by(|build1, build2| {
-            build1.cpufeatures.len().cmp(&build2.cpufeatures.len()).reverse()
+            ranking::embedded_order(&build1.cpufeatures, &build2.cpufeatures)
+                .then_with(|| build1.path.cmp(&build2.path))
         });
     }
 
@@ -80,8 +164,16 @@ impl Artifacts {
         }
     }
 
-    pub fn generate_sources(mut self, dest_path: &Path) -> Result<(), Exit> {
-        let fallback_desc = self.bins.pop(); // Binaries are sorted, the one with no features is the fallback
+    pub fn generate_sources(
+        mut self,
+        dest_path: &Path,
+        bsdiff_fast: bool,
+        delta_chain: bool,
+        hypervisor_ignored_features: &[String],
+    ) -> Result<(), Exit> {
+        // Binaries are sorted, the one with no features is the fallback.
+        // Built with --no-fallback, the one with the fewest features is the base and the host must support them.
+        let fallback_desc = self.bins.pop();
 
         if fallback_desc.is_none() {
             println!("cargo:warning=The JSON file loaded from the environment variable MULTIARCH_ARTIFACTS is empty.");
@@ -89,43 +181,98 @@ impl Artifacts {
 
         let fallback = fallback_desc
             .as_ref()
-            .map(|fallback| {
-                std::fs::read(&fallback.path).map_err(|_| {
-                    proc_exit::sysexits::IO_ERR.with_message(format!(
-                        "Failed to read fallback build {}",
-                        fallback.path.display(),
-                    ))
-                })
-            })
+            .map(|fallback| read_executable(&fallback.path, "fallback build"))
             .transpose()?
             .unwrap_or_default();
+        let default_exe_hash = fnv1a(FNV_OFFSET, &fallback);
+        let fallback_order = fallback_desc.as_ref().map_or(0, |fallback| fallback.order);
 
-        let (patches, features_lists): (Vec<_>, Vec<_>) = self
+        let targets = self
             .bins
+            .iter()
+            .map(|bin| read_executable(&bin.path, "binary"))
+            .collect::<Result<Vec<_>, Exit>>()?;
+        // Hash of what is embedded, the builds in the order of the artifacts file
+        let mut embedded: Vec<(usize, &[u8])> = self
+            .bins
+            .iter()
+            .map(|bin| bin.order)
+            .zip(targets.iter().map(Vec::as_slice))
+            .collect();
+        if fallback_desc.is_some() {
+            embedded.push((fallback_order, &fallback));
+        }
+        embedded.sort_unstable_by_key(|(order, _)| *order);
+        let artifacts_hash = embedded
             .into_iter()
-            .map(|bin| {
-                let target = std::fs::read(&bin.path).map_err(|_| {
-                    proc_exit::sysexits::IO_ERR
-                        .with_message(format!("Failed to read binary {}", bin.path.display(),))
-                }).unwrap(); // TODO: fix the error bubble up
-                let patch = bsdiff(&fallback, &target).unwrap(); // TODO: fix the error bubble up
-                let features = bin.cpufeatures;
-                let patch_raw = quote! {&[#(#patch),*]};
-                let features_raw = quote! {&[#(#features),*]};
-                (patch_raw, features_raw)
-            })
-            .unzip();
-        let source = zstd::stream::encode_all(&fallback[..], 3).map_err(|e| io_to_sysexists(e.kind()).unwrap()).map_err(|code| code.as_exit())?;
+            .fold(self.description_hash, |hash, (_, exe)| fnv1a(hash, exe));
+        let default_exe_features = fallback_desc
+            .map(|fallback| fallback.cpufeatures)
+            .unwrap_or_default();
+        let mut sizes = Vec::with_capacity(self.bins.len() + 1);
+        // The default executable is the last resort, its glibc requirement is not checked
+        let patches_min_glibc = self
+            .bins
+            .iter()
+            .map(|bin| bin.min_glibc().map(|(major, minor)| quote! {(#major, #minor)}))
+            .collect::<Result<Vec<_>, Exit>>()?;
+
+        let mut patches = Vec::with_capacity(targets.len());
+        let mut features_lists = Vec::with_capacity(targets.len());
+        let mut patches_hashes = Vec::with_capacity(targets.len());
+        let mut patches_bases = Vec::with_capacity(targets.len());
+        let mut patches_priorities = Vec::with_capacity(targets.len());
+        for (id, (bin, target)) in self.bins.into_iter().zip(&targets).enumerate() {
+            // Builds are sorted by decreasing features, a delta chain diffs each flavor
+            // against the next lower one and the last against the fallback
+            let lower = id + 1;
+            let (base, base_id) = match targets.get(lower) {
+                Some(lower_target) if delta_chain => (lower_target, quote! {Some(#lower)}),
+                _ => (&fallback, quote! {None}),
+            };
+            let patch = bsdiff(base, target, bsdiff_fast)?;
+            sizes.push(EmbeddedSize {
+                flavor: bin.cpufeatures.join(","),
+                bytes: patch.len(),
+            });
+            let features = bin.cpufeatures;
+            patches.push(quote! {&[#(#patch),*]});
+            features_lists.push(quote! {&[#(#features),*]});
+            patches_hashes.push(fnv1a(FNV_OFFSET, target));
+            patches_bases.push(base_id);
+            patches_priorities.push(match bin.priority {
+                Some(priority) => quote! {Some(#priority)},
+                None => quote! {None},
+            });
+        }
+        // Compressed after diffing, the patches are against the uncompressed fallback
+        let source = compress(&fallback).map_err(|e| io_to_sysexists(e.kind()).unwrap()).map_err(|code| code.as_exit())?;
+        sizes.insert(0, EmbeddedSize {
+            flavor: default_exe_features.join(","),
+            bytes: source.len(),
+        });
 
         let source = &source;
         let features_lists = &features_lists;
         let patches = &patches;
+        let patches_min_glibc = &patches_min_glibc;
+        let patches_bases = &patches_bases;
+        let patches_priorities = &patches_priorities;
 
         let fatbin_raw = quote! {
             FatBin {
+                format_version: #FORMAT_VERSION,
+                artifacts_hash: #artifacts_hash,
                 default_exe: &[#(#source),*],
+                default_exe_hash: #default_exe_hash,
+                default_exe_features: CpuFeatList(&[#(#default_exe_features),*]),
                 patches_features_lists: &[#(CpuFeatList(#features_lists)),*],
                 patches: &[#(#patches),*],
+                patches_hashes: &[#(#patches_hashes),*],
+                patches_min_glibc: &[#(#patches_min_glibc),*],
+                patches_bases: &[#(#patches_bases),*],
+                patches_priorities: &[#(#patches_priorities),*],
+                hypervisor_ignored_features: CpuFeatList(&[#(#hypervisor_ignored_features),*]),
             }
         };
 
@@ -136,10 +283,41 @@ impl Artifacts {
             ))
         })?;
 
+        let sizes_path = dest_path.with_file_name("fatbin-sizes.json");
+        let sizes = serde_json::to_vec(&sizes).map_err(|_| {
+            proc_exit::sysexits::SOFTWARE_ERR.with_message("Failed to encode the embedded sizes")
+        })?;
+        std::fs::write(&sizes_path, sizes).map_err(|_| {
+            proc_exit::sysexits::IO_ERR.with_message(format!(
+                "Failed to write {}",
+                sizes_path.display(),
+            ))
+        })?;
+
         Ok(())
     }
 }
 
+/// Generates the `BUILD_INFO` static from `MULTIARCH_BUILD_INFO`, empty unless set,
+/// and the `FORMAT_VERSION_TAG` static
+fn generate_build_info(dest_path: &Path) -> Result<(), Exit> {
+    println!("cargo:rerun-if-env-changed=MULTIARCH_BUILD_INFO");
+    let info = match std::env::var("MULTIARCH_BUILD_INFO") {
+        Ok(info) if !info.is_empty() => format!("{BUILD_INFO_MARKER}{info}\0"),
+        _ => String::new(),
+    };
+    let format_version = format!("{FORMAT_VERSION_MARKER}{FORMAT_VERSION}\0");
+    let statics = format!(
+        "static BUILD_INFO: &str = {info:?};\nstatic FORMAT_VERSION_TAG: &str = {format_version:?};\n"
+    );
+    std::fs::write(dest_path, statics).map_err(|_| {
+        proc_exit::sysexits::IO_ERR.with_message(format!(
+            "Failed to write generated Rust file to {}",
+            dest_path.display(),
+        ))
+    })
+}
+
 fn main() -> Result<(), Exit> {
     println!("cargo:rerun-if-changed=build.rs");
 
@@ -148,11 +326,115 @@ fn main() -> Result<(), Exit> {
     })?;
     let raw_fatbin = Path::new(&out_dir).join("fatbin.rs");
 
+    // Checked by the dispatcher against the embedded artifacts, rebuilt when it changes
+    println!("cargo:rerun-if-env-changed={ARTIFACTS_HASH_ENV}");
     let artifacts = Artifacts::from_env()
         .transpose()?
         .unwrap_or_default();
 
-        artifacts.generate_sources(&raw_fatbin)?;
+    println!("cargo:rerun-if-env-changed=MULTIARCH_BSDIFF_FAST");
+    let bsdiff_fast = std::env::var_os("MULTIARCH_BSDIFF_FAST").is_some();
+
+    println!("cargo:rerun-if-env-changed=MULTIARCH_DELTA_CHAIN");
+    let delta_chain = std::env::var_os("MULTIARCH_DELTA_CHAIN").is_some();
+
+    println!("cargo:rerun-if-env-changed=MULTIARCH_HYPERVISOR_IGNORE");
+    let hypervisor_ignored_features: Vec<String> = std::env::var("MULTIARCH_HYPERVISOR_IGNORE")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .map(ToOwned::to_owned)
+        .collect();
+
+    artifacts.generate_sources(&raw_fatbin, bsdiff_fast, delta_chain, &hypervisor_ignored_features)?;
+    generate_build_info(&Path::new(&out_dir).join("build_info.rs"))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the executables and their artifacts file to a fresh directory,
+    /// returns the path of the artifacts file
+    fn write_artifacts(name: &str, bins: &[(&str, &[&str], Vec<u8>)]) -> PathBuf {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let bins: Vec<_> = bins
+            .iter()
+            .map(|(file, features, content)| {
+                let path = dir.join(file);
+                std::fs::write(&path, content).unwrap();
+                serde_json::json!({ "path": path, "cpufeatures": features })
+            })
+            .collect();
+        let path = dir.join("artifacts.json");
+        std::fs::write(&path, serde_json::json!({ "bins": bins }).to_string()).unwrap();
+        path
+    }
+
+    /// Deterministic bytes standing in for machine code
+    fn pseudo_code(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn generate(artifacts: &Path, dest: &Path) -> Vec<u8> {
+        Artifacts::from_path(artifacts)
+            .unwrap()
+            .generate_sources(dest, false, false, &[])
+            .unwrap();
+        std::fs::read(dest).unwrap()
+    }
+
+    #[test]
+    fn same_inputs_generate_identical_sources() {
+        let fallback = pseudo_code(4096, 1);
+        let mut avx2 = fallback.clone();
+        avx2[1000..1100].copy_from_slice(&pseudo_code(100, 2));
+        let mut avx512 = avx2.clone();
+        avx512[3000..3100].copy_from_slice(&pseudo_code(100, 3));
+        let artifacts = write_artifacts(
+            "reproducible",
+            &[
+                ("app-avx2", &["avx", "avx2"], avx2),
+                ("app", &[], fallback),
+                ("app-avx512", &["avx2", "avx512f"], avx512),
+            ],
+        );
+        let dir = artifacts.parent().unwrap();
+
+        let first = generate(&artifacts, &dir.join("first.rs"));
+        let second = generate(&artifacts, &dir.join("second.rs"));
+        assert!(first == second, "the generated fat binary differs between two runs");
+    }
+
+    #[test]
+    fn one_function_change_gives_a_small_patch() {
+        let fallback = pseudo_code(256 * 1024, 1);
+        // A function in the middle is recompiled 16 bytes longer, the code after it moves
+        let mut flavor = fallback[..100_000].to_vec();
+        flavor.extend(pseudo_code(216, 2));
+        flavor.extend(&fallback[100_200..]);
+
+        for fast in [false, true] {
+            let patch = bsdiff(&fallback, &flavor, fast).unwrap();
+            assert!(patch.len() < 4096, "{} bytes patch for a one-function change", patch.len());
+        }
+    }
+
+    #[test]
+    fn compressed_executables_are_not_diffed() {
+        let fallback = compress(&pseudo_code(4096, 1)).unwrap();
+        let flavor = pseudo_code(4096, 2);
+        assert!(bsdiff(&fallback, &flavor, false).is_err());
+        assert!(bsdiff(&flavor, &fallback, false).is_err());
+    }
+}
@@ -1,16 +1,27 @@
 use std::collections::HashSet;
 use std::fs::File;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use notstd_detect::detect; // std::detect uses removed feature const_fn and no release since https://github.com/rust-lang/stdarch/issues/1526
-use qbsdiff::Bspatch;
-use zstd;
-use cfg_if;
 use proc_exit::Exit;
 
-#[cfg(target_arch = "x86_64")]
-mod features_x86;
+use crate::format;
+use crate::selection_log;
 
+cfg_if::cfg_if! {
+if #[cfg(all(target_arch = "x86_64", not(feature = "simple-ranking")))] {
+        mod features_x86;
+    } else {
+        // Also the default for architectures without a curated ranking
+        mod ranking_simple;
+    }
+  }
+
+mod exec_tempfile;
+#[cfg(unix)]
+mod exec_spawn;
 cfg_if::cfg_if! {
 if #[cfg(any(
     target_os = "android",
@@ -23,7 +34,22 @@ if #[cfg(any(
 ))] {
         mod exec_memory;
     } else {
-        mod exec_tempfile;
+        impl Executable for Binary {
+            fn create_writable(name: &str) -> Result<Self, io::Error> {
+                exec_tempfile::create_writable(name)
+            }
+
+            unsafe fn exec(
+                self,
+                _mode: ExecMode,
+                argc: i32,
+                argv: *const *const i8,
+                envp: *const *const i8,
+            ) -> Result<(), Exit> {
+                // Without fexecve the flavor always runs as a child
+                exec_tempfile::spawn(self, argc, argv, envp)
+            }
+        }
     }
   }
 
@@ -36,6 +62,12 @@ pub(crate) struct CpuFeatList<'a>(pub &'a [&'a str]);
 
 pub(crate) trait Features<'a> {
     fn get_features_lists(&'a self) -> &'a [CpuFeatList<'a>];
+    /// Minimum glibc version of each flavor, (0, 0) without requirement
+    fn get_min_glibc(&'a self) -> &'a [(u32, u32)];
+    /// Priority configured for each flavor, selected over the ranking
+    fn get_priorities(&'a self) -> &'a [Option<u32>];
+    /// CPU features not trusted in virtual machines
+    fn get_hypervisor_ignored_features(&'a self) -> &'a [&'a str];
 }
 pub(crate) trait FlavorsRank<'a>: Features<'a> {
 
@@ -44,36 +76,226 @@ pub(crate) trait FlavorsRank<'a>: Features<'a> {
     /// Returns -1 if empty
     fn get_top_ranked(supported_feat_lists: impl Iterator<Item = CpuFeatList<'a>>) -> isize;
 
-    /// Filters the binaries that can run on this CPU
+    /// Filters the binaries that can run on this CPU and C library
     /// and return a tuple of their original index and features
     fn get_supported_binaries(&'a self) -> (Vec<usize>, Vec<CpuFeatList<'a>>)
     {
-        let host_features: HashSet<&str> = detect::features()
-            .filter_map(|(name, is_available)| is_available.then_some(name))
-            .collect();
+        let host_features = host_features();
+        let min_glibc = self.get_min_glibc();
+        let distrusted: &[&str] = if under_hypervisor() {
+            self.get_hypervisor_ignored_features()
+        } else {
+            &[]
+        };
 
         self.get_features_lists()
             .iter()
             .cloned()
             .enumerate()
             .filter(|(_, patch_feats)| {
-                host_features.is_superset(
-                    &HashSet::from_iter(patch_feats.0.iter().cloned())
-                )
+                patch_feats.0.iter().all(|feature| host_features.contains(*feature))
+            })
+            .filter(|(id, patch_feats)| {
+                let (major, minor) = min_glibc[*id];
+                let supported = meets_min_glibc((major, minor));
+                if !supported && log_enabled() {
+                    eprintln!(
+                        "multiarch: flavor [{}] skipped: requires glibc {major}.{minor}, host has {}",
+                        patch_feats.0.join(","),
+                        host_glibc().map_or("none".to_owned(), |(major, minor)| format!("{major}.{minor}"))
+                    );
+                }
+                supported
+            })
+            .filter(|(_, patch_feats)| {
+                let emulated: Vec<&str> = patch_feats.0.iter().filter(|feature| distrusted.contains(*feature)).cloned().collect();
+                if !emulated.is_empty() && log_enabled() {
+                    eprintln!(
+                        "multiarch: flavor [{}] skipped: {} not trusted under a hypervisor",
+                        patch_feats.0.join(","),
+                        emulated.join(",")
+                    );
+                }
+                emulated.is_empty()
             })
             .unzip()
     }
 
     fn get_best_flavor_id(&'a self) -> Option<usize> {
-        let (indices, feat_lists) = self.get_supported_binaries();
-        if indices.len() == 0 {
+        let (mut indices, mut feat_lists) = self.get_supported_binaries();
+        // Flavors with the highest explicit priority win, the ranking only
+        // decides between them or when none of the supported ones has a priority
+        let priorities = self.get_priorities();
+        let top_priority = indices.iter().filter_map(|&id| priorities[id]).max();
+        if top_priority.is_some() {
+            (indices, feat_lists) = indices
+                .into_iter()
+                .zip(feat_lists)
+                .filter(|(id, _)| priorities[*id] == top_priority)
+                .unzip();
+        }
+        if indices.is_empty() {
+            if log_enabled() {
+                self.log_missing_features();
+            }
             None
         } else {
             let top_compatible_index = Self::get_top_ranked(feat_lists.into_iter());
-            // top_compatible_index != -1  due to the previous indices.len() == 0 check
+            // top_compatible_index != -1  due to the previous indices.is_empty() check
             Some(indices[top_compatible_index as usize])
         }
     }
+
+    /// Explains why the generic fallback runs:
+    /// the features the best flavor requires that the host lacks
+    fn log_missing_features(&'a self) {
+        let all_lists = self.get_features_lists();
+        let Ok(best) = usize::try_from(Self::get_top_ranked(all_lists.iter().cloned())) else {
+            return;
+        };
+        let host_features = host_features();
+        let best = all_lists[best].0;
+        let missing: Vec<&str> = best
+            .iter()
+            .filter(|feature| !host_features.contains(**feature))
+            .cloned()
+            .collect();
+        eprintln!(
+            "multiarch: running the generic flavor, flavor [{}] skipped: host missing {}",
+            best.join(","),
+            missing.join(",")
+        );
+    }
+}
+
+/// Comma-separated CPU features of an environment variable
+fn env_features(var: &str) -> HashSet<String> {
+    std::env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|feature| !feature.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// CPU features of the host, minus the comma-separated `CARGO_MULTIARCH_DISABLE_FEATURES`
+/// to test the selection on a less capable CPU.
+/// When detection finds nothing (unsupported architecture or failure)
+/// `CARGO_MULTIARCH_ASSUME_FEATURES` supplies them instead.
+fn host_features() -> &'static HashSet<String> {
+    static HOST_FEATURES: OnceLock<HashSet<String>> = OnceLock::new();
+    HOST_FEATURES.get_or_init(|| {
+        let mut features: HashSet<String> = detect::features()
+            .filter(|&(_, is_available)| is_available)
+            .map(|(name, _)| name.to_owned())
+            .collect();
+        if features.is_empty() {
+            features = env_features("CARGO_MULTIARCH_ASSUME_FEATURES");
+            if log_enabled() {
+                eprintln!(
+                    "multiarch: CPU feature detection returned no features, {}",
+                    if features.is_empty() {
+                        "only the generic flavor can run, set CARGO_MULTIARCH_ASSUME_FEATURES to supply them"
+                    } else {
+                        "using CARGO_MULTIARCH_ASSUME_FEATURES"
+                    }
+                );
+            }
+        }
+        for disabled in env_features("CARGO_MULTIARCH_DISABLE_FEATURES") {
+            features.remove(&disabled);
+        }
+        features
+    })
+}
+
+/// glibc version of the host, None with another C library.
+/// A dispatcher built for musl cannot tell, flavors requiring glibc are then skipped.
+fn host_glibc() -> Option<(u32, u32)> {
+    cfg_if::cfg_if! {
+    if #[cfg(all(target_os = "linux", target_env = "gnu"))] {
+            static HOST_GLIBC: OnceLock<Option<(u32, u32)>> = OnceLock::new();
+            *HOST_GLIBC.get_or_init(|| {
+                // SAFETY: glibc returns a static NUL-terminated string
+                let version = unsafe { std::ffi::CStr::from_ptr(libc::gnu_get_libc_version()) };
+                version.to_str().ok().and_then(crate::format::parse_glibc_version)
+            })
+        } else {
+            None
+        }
+      }
+}
+
+/// A flavor that CPU-matches but requires a newer glibc would fail to load on a missing symbol version
+fn meets_min_glibc(required: (u32, u32)) -> bool {
+    required == (0, 0) || host_glibc().is_some_and(|host| host >= required)
+}
+
+/// The CPUID hypervisor bit, set by hypervisors for their guests.
+/// Other architectures have no standard way to tell.
+fn under_hypervisor() -> bool {
+    cfg_if::cfg_if! {
+    if #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] {
+            #[cfg(target_arch = "x86")]
+            use std::arch::x86::__cpuid;
+            #[cfg(target_arch = "x86_64")]
+            use std::arch::x86_64::__cpuid;
+
+            // CPUID leaf 1 is available on every CPU that runs the dispatcher
+            #[allow(unused_unsafe)]
+            let ecx = unsafe { __cpuid(1) }.ecx;
+            ecx & (1 << 31) != 0
+        } else {
+            false
+        }
+      }
+}
+
+/// Flavor pinned by the first line of a `<fat binary>.multiarch` file next to the fat binary:
+/// comma-separated CPU features as configured, or `generic` for the default executable,
+/// matched by [`find_pinned`].
+/// Read before feature detection, unlike environment variables it persists across
+/// invocations and survives environments that scrub them.
+/// Ignored under `CARGO_MULTIARCH_DISABLE_FEATURES`, which simulates another host, e.g. for `--selftest`.
+fn read_pin() -> Option<(PathBuf, String)> {
+    if std::env::var_os("CARGO_MULTIARCH_DISABLE_FEATURES").is_some() {
+        return None;
+    }
+    // The fat binary itself, with symlinks resolved
+    let mut path = std::env::current_exe().ok()?.into_os_string();
+    path.push(".multiarch");
+    let path = PathBuf::from(path);
+    let content = std::fs::read_to_string(&path).ok()?;
+    let pinned = content.lines().map(str::trim).find(|line| !line.is_empty())?;
+    Some((path, pinned.to_owned()))
+}
+
+/// The flavor named by a pin, `Some(None)` for the default executable.
+/// Embedded features include those implied by the configured ones, `avx2` brings `avx`,
+/// `fma`, `sse4.2`, ... so the pin selects the flavor with all the pinned features,
+/// the one with the fewest features if several have them.
+fn find_pinned(pinned: &str, default_exe: &CpuFeatList, patches: &[CpuFeatList]) -> Option<Option<usize>> {
+    if pinned == "generic" {
+        return Some(None);
+    }
+    let pinned_features: HashSet<&str> = pinned
+        .split(',')
+        .map(str::trim)
+        .filter(|feature| !feature.is_empty())
+        .collect();
+    let includes_pinned = |list: &CpuFeatList| pinned_features.iter().all(|feature| list.0.contains(feature));
+    std::iter::once((None, default_exe))
+        .chain(patches.iter().enumerate().map(|(id, list)| (Some(id), list)))
+        .filter(|(_, list)| includes_pinned(list))
+        // The first of the smallest, e.g. `avx2` pins the avx2 flavor, not avx2 + avx512f
+        .min_by_key(|(_, list)| list.0.len())
+        .map(|(id, _)| id)
+}
+
+/// Diagnostics on stderr are enabled by a non-empty `CARGO_MULTIARCH_LOG`
+pub(crate) fn log_enabled() -> bool {
+    std::env::var_os("CARGO_MULTIARCH_LOG").is_some_and(|log| !log.is_empty())
 }
 pub(crate) trait Executable: Sized {
     /// Create an executable in a temporary location
@@ -87,6 +309,7 @@ pub(crate) trait Executable: Sized {
     /// it is consumed on success and file is closed.
     unsafe fn exec(
         self,
+        mode: ExecMode,
         argc: i32,
         argv: *const *const i8,
         envp: *const *const i8,
@@ -96,22 +319,66 @@ pub(crate) trait Executable: Sized {
 // Types
 // ---------------------------------------------------------------
 
+/// How the selected flavor is run, from `CARGO_MULTIARCH_EXEC_MODE`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ExecMode {
+    /// The dispatcher process is replaced by the flavor, the default
+    Replace,
+    /// The dispatcher stays as the parent of the flavor,
+    /// forwards termination signals to it and exits with its status.
+    /// For supervisors that need to observe a child.
+    Spawn,
+}
+
+impl ExecMode {
+    pub fn from_env() -> Result<Self, Exit> {
+        match std::env::var_os("CARGO_MULTIARCH_EXEC_MODE") {
+            None => Ok(ExecMode::Replace),
+            Some(mode) if mode == "replace" => Ok(ExecMode::Replace),
+            Some(mode) if mode == "spawn" => Ok(ExecMode::Spawn),
+            Some(mode) => Err(proc_exit::sysexits::USAGE_ERR.with_message(format!(
+                "multiarch: unknown CARGO_MULTIARCH_EXEC_MODE {mode:?}, expected replace or spawn"
+            ))),
+        }
+    }
+}
+
 /// A fat binary type that contains a default executable
-/// with no features
+/// with no features, or the fewest when built without fallback,
 /// and may contain patches and a description of
 /// corresponding CPU features
 // This data structure should be kept as simple as possible
 // to reduce compile-time.
 // Furthermore, it should allow zero-copy views for memory efficiency.
 pub(crate) struct FatBin<'a> {
+    pub format_version: u32,
+    // Of the artifacts the build script embedded, see `ARTIFACTS_HASH_ENV`
+    pub artifacts_hash: u64,
     pub default_exe: &'a [u8],
+    // FNV-1a of the executables, to check their reconstruction
+    pub default_exe_hash: u64,
+    // Empty unless built without fallback, the host must support them
+    pub default_exe_features: CpuFeatList<'a>,
     pub patches_features_lists: &'a [CpuFeatList<'a>],
     pub patches: &'a [&'a [u8]],
+    pub patches_hashes: &'a [u64],
+    pub patches_min_glibc: &'a [(u32, u32)],
+    // Set in the configuration, a supported flavor with a priority is selected over the ranked ones
+    pub patches_priorities: &'a [Option<u32>],
+    // Flavor each patch applies to, None for the default executable.
+    // Set with --delta-chain, a flavor is then rebuilt through the lower flavors.
+    pub patches_bases: &'a [Option<usize>],
+    // Flavors using these are skipped in virtual machines, where they may be emulated incorrectly
+    pub hypervisor_ignored_features: CpuFeatList<'a>,
 }
 
 /// A binary unbundled from a fat binary
 pub(crate) struct Binary {
     file: File,
+    // Set when backed by a temporary file instead of memory
+    path: Option<PathBuf>,
+    // Stable path the temporary file is renamed to and kept at, with `CARGO_MULTIARCH_EXEC_PATH`
+    persist_as: Option<PathBuf>,
 }
 
 // Impl
@@ -123,21 +390,168 @@ impl<'a> Features<'a> for FatBin<'a> {
     fn get_features_lists(&self) -> &[CpuFeatList<'_>] {
         self.patches_features_lists
     }
+
+    #[inline(always)]
+    fn get_min_glibc(&self) -> &[(u32, u32)] {
+        self.patches_min_glibc
+    }
+
+    #[inline(always)]
+    fn get_priorities(&self) -> &[Option<u32>] {
+        self.patches_priorities
+    }
+
+    #[inline(always)]
+    fn get_hypervisor_ignored_features(&self) -> &[&str] {
+        self.hypervisor_ignored_features.0
+    }
 }
 
 impl<'a> FatBin<'a> {
-    fn extract_flavor_into(&self, mut output: impl io::Write, id: Option<usize>) -> io::Result<()> {
+    /// Features of the default executable joined by `sep`, "generic" for the fallback
+    fn default_exe_name(&self, sep: &str) -> String {
+        if self.default_exe_features.0.is_empty() {
+            "generic".to_owned()
+        } else {
+            self.default_exe_features.0.join(sep)
+        }
+    }
+
+    /// The flavor pinned by the sidecar file of [`read_pin`], `Some(None)` for the default
+    /// executable. A flavor that is not embedded or does not run on this host is ignored
+    /// with a warning, the flavor is then selected as usual.
+    fn get_pinned_flavor_id(&'a self) -> Option<Option<usize>>
+    where
+        Self: FlavorsRank<'a>,
+    {
+        let (path, pinned) = read_pin()?;
+        let Some(pinned_id) = find_pinned(&pinned, &self.default_exe_features, self.patches_features_lists) else {
+            eprintln!(
+                "multiarch: pinned flavor [{pinned}] of {} is not in this fat binary, selecting the best flavor",
+                path.display()
+            );
+            return None;
+        };
+        let runs = match pinned_id {
+            Some(id) => self.get_supported_binaries().0.contains(&id),
+            None => self.check_default_exe_supported().is_ok(),
+        };
+        if !runs {
+            eprintln!(
+                "multiarch: pinned flavor [{pinned}] of {} does not run on this host, selecting the best flavor",
+                path.display()
+            );
+            return None;
+        }
+        if log_enabled() {
+            eprintln!("multiarch: flavor [{pinned}] pinned by {}", path.display());
+        }
+        Some(pinned_id)
+    }
+
+    /// Without fallback, the default executable is the last resort
+    /// and the host must support its features
+    fn check_default_exe_supported(&self) -> io::Result<()> {
+        let host_features = host_features();
+        let missing: Vec<&str> = self
+            .default_exe_features
+            .0
+            .iter()
+            .filter(|feature| !host_features.contains(**feature))
+            .cloned()
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "host does not meet minimum feature requirements, missing {}",
+                    missing.join(",")
+                ),
+            ))
+        }
+    }
+
+    /// Reconstructs a flavor, returning the hash of the executable
+    fn extract_flavor_into(&self, output: impl io::Write, id: Option<usize>) -> io::Result<u64> {
         // Prepare the binary flavor for execution,
         // Pass None for the default executable
-        match id {
-            None => zstd::stream::copy_decode(self.default_exe, &mut output),
-            Some(id) => {
-                let base = zstd::decode_all(self.default_exe)?;
-                let patcher = Bspatch::new(self.patches[id])?;
-                patcher.apply(&base, output)?;
-                Ok(())
-            }
+        // Errors are tagged with the failing stage and the flavor for bug reports.
+        let flavor = match id {
+            None => self.default_exe_name(","),
+            Some(id) => self.patches_features_lists[id].0.join(","),
+        };
+        let stage_err = |stage: &dyn std::fmt::Display, e: io::Error| {
+            io::Error::new(e.kind(), format!("{stage} failed for flavor [{flavor}]: {e}"))
+        };
+
+        let chain = format::patch_chain(self.patches, self.patches_bases, id)
+            .map_err(|e| stage_err(&"patch chain", e))?;
+        format::reconstruct(self.default_exe, &chain, output).map_err(|e| stage_err(&e.stage, e.error))
+    }
+
+    /// Reconstructs every flavor and checks it against its embedded hash,
+    /// each flavor is reported on stdout in the format documented by `VERIFY_ENV`.
+    /// Returns whether all flavors are intact.
+    pub fn verify(&self) -> bool {
+        let ids = std::iter::once(None).chain((0..self.patches.len()).map(Some));
+        let mut intact = true;
+        for id in ids {
+            let (flavor, expected) = match id {
+                None => (self.default_exe_name(","), self.default_exe_hash),
+                Some(id) => (self.patches_features_lists[id].0.join(","), self.patches_hashes[id]),
+            };
+            let mut exe = Vec::new();
+            let (status, detail) = match self.extract_flavor_into(&mut exe, id) {
+                Err(e) => ("failed", e.to_string()),
+                Ok(hash) if hash == expected => ("ok", String::new()),
+                Ok(hash) => ("failed", format!("hash {hash:016x}, expected {expected:016x}")),
+            };
+            intact &= status == "ok";
+            println!("{status}\t{flavor}\t{}\t{detail}", exe.len());
+        }
+        intact
+    }
+
+    /// Reconstructs every flavor into `dir`, the default executable first,
+    /// each reported on stdout in the format documented by `EXTRACT_ENV`
+    /// after the options the fat binary was built with.
+    pub fn extract_all(&self, dir: &Path, build_info: bool) -> io::Result<()> {
+        let ranking = if cfg!(feature = "simple-ranking") { "simple" } else { "curated" };
+        let delta_chain = match self.patches_bases.iter().any(Option::is_some) {
+            true => "1",
+            // A single flavor is diffed against the default executable either way
+            false if self.patches.len() < 2 => "",
+            false => "0",
+        };
+        println!(
+            "options\t{ranking}\t{delta_chain}\t{}\t{}",
+            self.hypervisor_ignored_features.0.join(","),
+            if build_info { "1" } else { "0" }
+        );
+        let ids = std::iter::once(None).chain((0..self.patches.len()).map(Some));
+        for (index, id) in ids.enumerate() {
+            let (features, min_glibc, priority) = match id {
+                None => (self.default_exe_features.0.join(","), (0, 0), None),
+                Some(id) => (
+                    self.patches_features_lists[id].0.join(","),
+                    self.patches_min_glibc[id],
+                    self.patches_priorities[id],
+                ),
+            };
+            let path = dir.join(format!("flavor-{index}"));
+            let file = File::create(&path)
+                .map_err(|e| io::Error::new(e.kind(), format!("failed to create {}: {e}", path.display())))?;
+            self.extract_flavor_into(file, id)?;
+            let min_glibc = match min_glibc {
+                (0, 0) => String::new(),
+                (major, minor) => format!("{major}.{minor}"),
+            };
+            let priority = priority.map(|priority| priority.to_string()).unwrap_or_default();
+            println!("{features}\t{min_glibc}\t{priority}\t{}", path.display());
         }
+        Ok(())
     }
 
     /// Load the best binary flavor
@@ -148,13 +562,143 @@ impl<'a> FatBin<'a> {
         Self: FlavorsRank<'a>,
         Binary: Executable,
     {
-        let best_id = self.get_best_flavor_id();
+        let best_id = match self.get_pinned_flavor_id() {
+            Some(pinned_id) => pinned_id,
+            None => self.get_best_flavor_id(),
+        };
+        if best_id.is_none() {
+            self.check_default_exe_supported()?;
+        }
         let suffix = if let Some(id) = best_id {
             self.patches_features_lists[id].0.join("_")
-        } else {"generic".to_owned()};
-        let bin_name = format!("{}_{}", name_prefix, &suffix);
-        let mut bin: Binary = Executable::create_writable(&bin_name)?;
+        } else {
+            self.default_exe_name("_")
+        };
+        let flavor = match best_id {
+            Some(id) => self.patches_features_lists[id].0.join(","),
+            None => self.default_exe_name(","),
+        };
+        selection_log::record(&flavor, host_features());
+        let bin_name = format!("{}_{}", name_prefix, suffix);
+        // A stable path for profilers, named after the features of the flavor
+        let exec_path = std::env::var_os("CARGO_MULTIARCH_EXEC_PATH").filter(|dir| !dir.is_empty());
+        let mut bin: Binary = match exec_path {
+            Some(dir) => exec_tempfile::create_persistent(Path::new(&dir), &bin_name)?,
+            None => Executable::create_writable(&bin_name)?,
+        };
         self.extract_flavor_into(&mut bin.file, best_id)?;
         Ok(bin)
     }
 }
+
+// CPU identity
+// ---------------------------------------------------------------
+
+/// Returns a stable identifier of the host CPU model,
+/// e.g. `x86_64-AuthenticAMD-f19-m21` for Zen 3 or `aarch64-midr-0x413fd0c1`.
+/// Flavor selections remembered across runs must be keyed by it and not only by CPU features:
+/// CPUs with the same features, like successive Zen generations,
+/// may perform best with different flavors.
+pub(crate) fn cpu_model_id() -> String {
+    cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+            use std::arch::x86_64::__cpuid;
+
+            // Leaf 0: vendor string in EBX, EDX, ECX
+            #[allow(unused_unsafe)]
+            let leaf0 = unsafe { __cpuid(0) };
+            let vendor: Vec<u8> = [leaf0.ebx, leaf0.edx, leaf0.ecx]
+                .iter()
+                .flat_map(|reg| reg.to_le_bytes())
+                .collect();
+            // Leaf 1: signature in EAX, family and model include their extended part
+            #[allow(unused_unsafe)]
+            let signature = unsafe { __cpuid(1) }.eax;
+            let base_family = (signature >> 8) & 0xf;
+            let base_model = (signature >> 4) & 0xf;
+            let family = if base_family == 0xf {
+                base_family + ((signature >> 20) & 0xff)
+            } else {
+                base_family
+            };
+            let model = if base_family == 0x6 || base_family == 0xf {
+                (((signature >> 16) & 0xf) << 4) | base_model
+            } else {
+                base_model
+            };
+            format!("x86_64-{}-f{family:x}-m{model:x}", String::from_utf8_lossy(&vendor))
+        } else if #[cfg(all(target_arch = "aarch64", target_os = "linux"))] {
+            // The kernel exposes the Main ID Register, implementer, part number and revision
+            std::fs::read_to_string("/sys/devices/system/cpu/cpu0/regs/identification/midr_el1")
+                .map(|midr| format!("aarch64-midr-{}", midr.trim()))
+                .unwrap_or_else(|_| "aarch64-unknown".to_owned())
+        } else {
+            format!("{}-unknown", std::env::consts::ARCH)
+        }
+      }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FullDisk;
+
+    impl io::Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::StorageFull, "no space left"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streamed_base_reports_the_failing_stage() {
+        let exe: Vec<u8> = (0..1 << 20).map(|i: u32| (i * 7 % 251) as u8).collect();
+        let compressed = zstd::encode_all(&exe[..], 3).unwrap();
+        let base_only = |default_exe| FatBin {
+            format_version: 0,
+            artifacts_hash: 0,
+            default_exe,
+            default_exe_hash: 0,
+            default_exe_features: CpuFeatList(&[]),
+            patches_features_lists: &[],
+            patches: &[],
+            patches_hashes: &[],
+            patches_min_glibc: &[],
+            patches_bases: &[],
+            patches_priorities: &[],
+            hypervisor_ignored_features: CpuFeatList(&[]),
+        };
+
+        let mut output = Vec::new();
+        let hash = base_only(&compressed).extract_flavor_into(&mut output, None).unwrap();
+        assert!(output == exe);
+        assert_eq!(hash, format::fnv1a(format::FNV_OFFSET, &exe));
+
+        let e = base_only(&compressed).extract_flavor_into(FullDisk, None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+        assert!(e.to_string().starts_with("write to the executable file failed for flavor [generic]"), "{e}");
+
+        let corrupt = [&compressed[..compressed.len() / 2], &[0xff; 64]].concat();
+        let e = base_only(&corrupt).extract_flavor_into(&mut Vec::new(), None).unwrap_err();
+        assert!(e.to_string().starts_with("zstd decode of the base executable failed"), "{e}");
+    }
+
+    #[test]
+    fn pin_matches_the_flavor_with_implied_features() {
+        let default_exe = CpuFeatList(&[]);
+        let patches = [
+            CpuFeatList(&["avx", "avx2", "avx512f", "bmi1", "bmi2", "fma", "sse3", "sse4.1", "sse4.2", "ssse3"]),
+            CpuFeatList(&["avx", "avx2", "bmi1", "bmi2", "fma", "sse3", "sse4.1", "sse4.2", "ssse3"]),
+            CpuFeatList(&["sse3", "sse4.1", "sse4.2", "ssse3"]),
+        ];
+        assert_eq!(find_pinned("avx2,bmi2,fma", &default_exe, &patches), Some(Some(1)));
+        assert_eq!(find_pinned("avx512f", &default_exe, &patches), Some(Some(0)));
+        assert_eq!(find_pinned(" sse4.2 ", &default_exe, &patches), Some(Some(2)));
+        assert_eq!(find_pinned("generic", &default_exe, &patches), Some(None));
+        assert_eq!(find_pinned("avx512vl", &default_exe, &patches), None);
+    }
+}
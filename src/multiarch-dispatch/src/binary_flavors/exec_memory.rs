@@ -1,16 +1,23 @@
 use std::fs::File;
 use std::io;
+use std::sync::Once;
 
 use libc::fexecve;
-use proc_exit::{Code, Exit};
+use proc_exit::sysexits::{self, io_to_sysexists};
+use proc_exit::Exit;
 use rustix::fd::{IntoRawFd, OwnedFd, FromRawFd};
 use rustix::fs::{memfd_create, MemfdFlags};
+use rustix::io::Errno;
 
-use super::{Executable, Binary};
+use super::{exec_spawn, exec_tempfile, ExecMode, Executable, Binary};
+
+static MEMFD_DENIED: Once = Once::new();
 
 /// A simple memfd + fexecve for fileless execution on Linux, BSDs and Solaris
 /// See https://github.com/rust-lang/libc/pull/733/files for OS supported
-
+///
+/// memfd_create may be denied at runtime (seccomp filters, hardened or old kernels),
+/// in that case we fall back to a temporary file that is unlinked right before fexecve.
 impl Executable for Binary {
     fn create_writable(name: &str) -> Result<Self, io::Error> {
         // Note, there are permissions on the memory
@@ -20,19 +27,86 @@ impl Executable for Binary {
         //   but it mentions kernel 6.3, is missing from BSDs,
         //   and even Linux docs: https://man7.org/linux/man-pages/man2/memfd_create.2.html
         // The file descriptor is writable by default.
-        let file = memfd_create(name, MemfdFlags::CLOEXEC) // Close on exec
-            .map(OwnedFd::into_raw_fd)
-            .map(|fd| unsafe { File::from_raw_fd(fd)})?;
-        Ok(Binary { file })
+        match memfd_create(name, MemfdFlags::CLOEXEC) { // Close on exec
+            Ok(fd) => {
+                let file = unsafe { File::from_raw_fd(OwnedFd::into_raw_fd(fd)) };
+                Ok(Binary { file, path: None, persist_as: None })
+            }
+            Err(errno) if errno == Errno::PERM || errno == Errno::NOSYS => {
+                MEMFD_DENIED.call_once(|| {
+                    eprintln!(
+                        "multiarch: memfd_create is unavailable ({errno}), falling back to a temporary file in {}",
+                        std::env::temp_dir().display()
+                    );
+                });
+                exec_tempfile::create_writable(name)
+            }
+            Err(errno) => Err(errno.into()),
+        }
     }
 
     unsafe fn exec(
         self,
+        mode: ExecMode,
         _argc: i32,
         argv: *const *const i8,
         envp: *const *const i8,
     ) -> Result<(), Exit> {
-        let status = unsafe { fexecve(self.file.into_raw_fd(), argv, envp) };
-        Code::new(status).ok()
+        let file = match (self.path, self.persist_as) {
+            (None, _) => self.file,
+            (Some(path), Some(stable)) => exec_tempfile::reopen_persisted(self.file, &path, &stable)
+                .map_err(|_| proc_exit::sysexits::IO_ERR.with_message(format!(
+                    "Failed to move the executable to {}",
+                    stable.display()
+                )))?,
+            (Some(path), None) => exec_tempfile::reopen_unlinked(self.file, &path)
+                .map_err(|_| proc_exit::sysexits::IO_ERR.with_message(format!(
+                    "Failed to prepare temporary executable {}",
+                    path.display()
+                )))?,
+        };
+        let fd = file.into_raw_fd();
+        if mode == ExecMode::Spawn {
+            exec_spawn::install_forwarding();
+            match unsafe { libc::fork() } {
+                -1 => return Err(exec_error(io::Error::last_os_error())),
+                0 => {
+                    let _ = unsafe { fexecve(fd, argv, envp) };
+                    // Like shells when a command can't be executed
+                    unsafe { libc::_exit(126) };
+                }
+                pid => {
+                    let status = exec_spawn::wait_forwarding_signals(pid)?;
+                    return exec_spawn::exit_like(status);
+                }
+            }
+        }
+        // fexecve only returns on failure, with -1 and errno set
+        let _ = unsafe { fexecve(fd, argv, envp) };
+        Err(exec_error(io::Error::last_os_error()))
     }
 }
+
+fn exec_error(err: io::Error) -> Exit {
+    let (code, reason) = match err.raw_os_error() {
+        Some(libc::ENOEXEC) => (
+            sysexits::SOFTWARE_ERR,
+            "the extracted binary is not executable on this platform",
+        ),
+        Some(libc::EACCES) | Some(libc::EPERM) => (
+            sysexits::NO_PERM,
+            "execution denied, memory or the temporary directory may be mounted noexec",
+        ),
+        Some(libc::ENOENT) => (
+            sysexits::OS_FILE_ERR,
+            "the dynamic loader of the flavor was not found",
+        ),
+        Some(libc::E2BIG) => (sysexits::USAGE_ERR, "argument list too long"),
+        Some(libc::ENOMEM) => (sysexits::OS_ERR, "out of memory"),
+        _ => (
+            io_to_sysexists(err.kind()).unwrap_or(sysexits::OS_ERR),
+            "exec failed",
+        ),
+    };
+    code.with_message(format!("multiarch: {reason} ({err})"))
+}
@@ -0,0 +1,184 @@
+//! Child-spawn execution, see `ExecMode::Spawn`.
+//! The dispatcher stays as a thin parent: termination signals are forwarded to the flavor
+//! and the dispatcher exits like the flavor, with the same exit code or signal.
+//! The flavor stays in the process group and session of the dispatcher, so it is in the
+//! foreground of the controlling terminal like an exec'd flavor would be: it inherits the
+//! terminal through stdin/stdout/stderr, can read from it and gets its job control signals.
+
+use std::io;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use libc::{c_int, c_void, pid_t, siginfo_t};
+use proc_exit::{sysexits, Code, Exit};
+
+/// Pid of the flavor, 0 until it is spawned
+static CHILD: AtomicI32 = AtomicI32::new(0);
+
+/// A standard stream on the controlling terminal, -1 if none
+static TERMINAL: AtomicI32 = AtomicI32::new(-1);
+
+const FORWARDED_SIGNALS: [c_int; 4] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGQUIT];
+
+/// Whether the signal was generated by the kernel, as the terminal does for Ctrl-C,
+/// rather than sent by a process with kill
+fn from_kernel(info: &siginfo_t) -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            // From <asm-generic/siginfo.h>, missing in the libc versions we support
+            const SI_KERNEL: c_int = 0x80;
+            info.si_code == SI_KERNEL
+        } else {
+            // SI_NOINFO on the BSDs and macOS, SI_USER and SI_QUEUE are positive
+            info.si_code == 0
+        }
+    }
+}
+
+extern "C" fn forward_signal(signal: c_int, info: *mut siginfo_t, _context: *mut c_void) {
+    // Only async-signal-safe calls here
+    // Ctrl-C and Ctrl-\ are sent by the terminal to the whole foreground group, the flavor
+    // already got them when it is in that group: forwarding would deliver them twice,
+    // e.g. aborting a readline prompt instead of clearing it. They are still caught so that
+    // the dispatcher outlives the flavor. A `kill -INT` from a supervisor is forwarded.
+    if signal == libc::SIGINT || signal == libc::SIGQUIT {
+        let terminal = TERMINAL.load(Ordering::Relaxed);
+        // The flavor shares the process group of the dispatcher
+        let flavor_in_foreground =
+            terminal >= 0 && unsafe { libc::tcgetpgrp(terminal) == libc::getpgrp() };
+        if flavor_in_foreground && !info.is_null() && from_kernel(unsafe { &*info }) {
+            return;
+        }
+    }
+    let pid = CHILD.load(Ordering::Relaxed);
+    if pid > 0 {
+        unsafe { libc::kill(pid, signal) };
+    }
+}
+
+/// Forward termination signals to the child once its pid is known.
+/// Must be called before forking, the child gets the default handlers back on exec.
+/// Signals received before the pid is known are dropped.
+pub(super) fn install_forwarding() {
+    // Any of the standard streams may be the terminal, the others redirected
+    let terminal = [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO]
+        .into_iter()
+        .find(|&fd| unsafe { libc::isatty(fd) } == 1)
+        .unwrap_or(-1);
+    TERMINAL.store(terminal, Ordering::Relaxed);
+    for signal in FORWARDED_SIGNALS {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = forward_signal as *const () as libc::sighandler_t;
+            // No SA_RESTART, waitpid is interrupted and retried
+            action.sa_flags = libc::SA_SIGINFO;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+/// Wait for the child while forwarding signals to it, returns its raw wait status
+pub(super) fn wait_forwarding_signals(pid: pid_t) -> Result<c_int, Exit> {
+    CHILD.store(pid, Ordering::Relaxed);
+    let mut status: c_int = 0;
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } == pid {
+            return Ok(status);
+        }
+        let err = io::Error::last_os_error();
+        // Interrupted by a forwarded signal
+        if err.raw_os_error() != Some(libc::EINTR) {
+            return Err(sysexits::OS_ERR.with_message(format!("multiarch: failed to wait for the flavor ({err})")));
+        }
+    }
+}
+
+/// Exit like the child did: same exit code, or same signal so that supervisors see it
+pub(super) fn exit_like(status: c_int) -> Result<(), Exit> {
+    if libc::WIFSIGNALED(status) {
+        let signal = libc::WTERMSIG(status);
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+        // Not reached for deadly signals, follow the shell convention otherwise
+        return Code::new(128 + signal).ok();
+    }
+    Code::new(libc::WEXITSTATUS(status)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::io::Write;
+
+    use super::super::{exec_tempfile, Binary, ExecMode, Executable};
+
+    /// Runs `run` in a child process whose controlling terminal is a new pseudo-terminal
+    /// on stdin, stdout and stderr, returns its exit code
+    fn under_pty(run: impl FnOnce() -> bool) -> i32 {
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(master >= 0, "no pseudo-terminal available");
+            assert_eq!(libc::grantpt(master), 0);
+            assert_eq!(libc::unlockpt(master), 0);
+            let slave = CString::from(std::ffi::CStr::from_ptr(libc::ptsname(master)));
+            match libc::fork() {
+                -1 => panic!("fork failed"),
+                0 => {
+                    // A new session without terminal, the first one opened becomes its controlling one
+                    libc::setsid();
+                    let tty = libc::open(slave.as_ptr(), libc::O_RDWR);
+                    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                        libc::dup2(tty, fd);
+                    }
+                    libc::_exit(if run() { 0 } else { 1 });
+                }
+                pid => {
+                    let mut status = 0;
+                    assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                    libc::close(master);
+                    assert!(libc::WIFEXITED(status), "killed by signal {}", libc::WTERMSIG(status));
+                    libc::WEXITSTATUS(status)
+                }
+            }
+        }
+    }
+
+    /// A flavor that succeeds only if its stdin and stdout are a terminal
+    fn isatty_flavor(bin: &mut Binary) -> Vec<CString> {
+        bin.file.write_all(&std::fs::read("/bin/sh").unwrap()).unwrap();
+        ["sh", "-c", "test -t 0 && test -t 1"].map(|arg| CString::new(arg).unwrap()).into()
+    }
+
+    fn spawn(bin: Binary, args: &[CString], through_exec: bool) -> bool {
+        let mut argv: Vec<*const i8> = args.iter().map(|arg| arg.as_ptr()).collect();
+        argv.push(std::ptr::null());
+        let envp = [std::ptr::null()];
+        let result = unsafe {
+            if through_exec {
+                bin.exec(ExecMode::Spawn, args.len() as i32, argv.as_ptr(), envp.as_ptr())
+            } else {
+                exec_tempfile::spawn(bin, args.len() as i32, argv.as_ptr(), envp.as_ptr())
+            }
+        };
+        result.is_ok()
+    }
+
+    #[test]
+    fn spawned_flavor_is_on_the_terminal() {
+        let code = under_pty(|| {
+            let mut bin = exec_tempfile::create_writable("multiarch-pty-test").unwrap();
+            let args = isatty_flavor(&mut bin);
+            spawn(bin, &args, false)
+        });
+        assert_eq!(code, 0, "isatty(0) is false in the flavor spawned from a temporary file");
+
+        let code = under_pty(|| {
+            let mut bin = Binary::create_writable("multiarch-pty-test").unwrap();
+            let args = isatty_flavor(&mut bin);
+            spawn(bin, &args, true)
+        });
+        assert_eq!(code, 0, "isatty(0) is false in the spawned flavor");
+    }
+}
@@ -1 +1,188 @@
+//! Temporary file based execution.
+//! Used on platforms without memfd (MacOS, Haiku, ...)
+//! and as a runtime fallback when memfd_create is denied.
+//! The temporary directory must not be mounted noexec.
 
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use proc_exit::Exit;
+
+use super::Binary;
+
+const MAX_ATTEMPTS: u32 = 16;
+
+pub(super) fn create_writable(name: &str) -> Result<Binary, io::Error> {
+    // `name` is derived from argv[0] and may contain path separators
+    let stem = Path::new(name)
+        .file_name()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "multiarch".to_owned());
+    let tmpdir = std::env::temp_dir();
+
+    for attempt in 0..MAX_ATTEMPTS {
+        let path = tmpdir.join(format!("{stem}.{}.{attempt}", std::process::id()));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o700);
+        }
+        match options.open(&path) {
+            Ok(file) => return Ok(Binary { file, path: Some(path), persist_as: None }),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "Failed to create a unique temporary executable",
+    ))
+}
+
+/// Executable for profilers, from `CARGO_MULTIARCH_EXEC_PATH`: perf or valgrind show
+/// `/memfd:...` for an executable in memory and cannot read its symbols.
+/// The flavor is written next to `<dir>/<name>`, named after its features by the caller,
+/// and renamed to it before running, so concurrent launches never run a partial file.
+/// It is left on disk for the profiler.
+pub(super) fn create_persistent(dir: &Path, name: &str) -> Result<Binary, io::Error> {
+    let stem = Path::new(name)
+        .file_name()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "multiarch".to_owned());
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{stem}.{}.tmp", std::process::id()));
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o755);
+    }
+    let file = options.open(&path)?;
+    Ok(Binary { file, path: Some(path), persist_as: Some(dir.join(stem)) })
+}
+
+/// Close the writable handle and move the executable to its stable path,
+/// a running copy of a previous launch keeps its own file
+fn persist(file: File, path: &Path, stable: &Path) -> Result<(), io::Error> {
+    drop(file);
+    fs::rename(path, stable).inspect_err(|_| {
+        let _ = fs::remove_file(path);
+    })
+}
+
+/// Like `reopen_unlinked`, but the executable is kept at its stable path
+#[allow(dead_code)]
+pub(super) fn reopen_persisted(file: File, path: &Path, stable: &Path) -> Result<File, io::Error> {
+    persist(file, path, stable)?;
+    File::open(stable)
+}
+
+/// Close the writable handle, an executable cannot be run while open for writing (ETXTBSY),
+/// then reopen it read-only and unlink it so that nothing is left behind after fexecve.
+#[allow(dead_code)]
+pub(super) fn reopen_unlinked(file: File, path: &Path) -> Result<File, io::Error> {
+    drop(file);
+    let file = File::open(path)?;
+    fs::remove_file(path)?;
+    Ok(file)
+}
+
+/// Run the temporary executable as a child process, then clean it up.
+/// Without fexecve the file must stay on disk while running,
+/// so we can't replace the current process.
+/// Termination signals are forwarded and the exit status is propagated.
+#[allow(dead_code)]
+pub(super) unsafe fn spawn(
+    bin: Binary,
+    argc: i32,
+    argv: *const *const i8,
+    envp: *const *const i8,
+) -> Result<(), Exit> {
+    let Binary { file, path, persist_as } = bin;
+    let path = path.ok_or_else(|| {
+        proc_exit::sysexits::SOFTWARE_ERR.with_message("Temporary executable has no path")
+    })?;
+    // Kept for profilers, the temporary file is removed otherwise
+    let (path, keep) = match persist_as {
+        Some(stable) => {
+            persist(file, &path, &stable).map_err(|_| {
+                proc_exit::sysexits::IO_ERR
+                    .with_message(format!("Failed to move the executable to {}", stable.display()))
+            })?;
+            (stable, true)
+        }
+        None => {
+            drop(file);
+            (path, false)
+        }
+    };
+
+    let args: Vec<String> = (1..argc.max(0) as usize)
+        .map(|i| unsafe { CStr::from_ptr(*argv.add(i)) }.to_string_lossy().into_owned())
+        .collect();
+    let mut command = Command::new(&path);
+    #[cfg(unix)]
+    if argc > 0 {
+        // Like fexecve, the flavor sees the argv[0] of the fat binary, not the temporary path
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::process::CommandExt;
+        command.arg0(std::ffi::OsStr::from_bytes(unsafe { CStr::from_ptr(*argv) }.to_bytes()));
+    }
+    // The default already, spelled out as interactive flavors rely on it: the terminal is
+    // shared, not piped, so isatty, terminal size and settings are those of the caller.
+    // No setsid nor setpgid, the flavor stays in the foreground group of the terminal.
+    command.args(args).stdin(Stdio::inherit()).stdout(Stdio::inherit()).stderr(Stdio::inherit());
+    if !envp.is_null() {
+        // Forward envp rather than our own environment, it may have been filtered
+        command.env_clear();
+        let mut cursor = envp;
+        while !(*cursor).is_null() {
+            let entry = unsafe { CStr::from_ptr(*cursor) }.to_string_lossy();
+            if let Some((name, value)) = entry.split_once('=') {
+                command.env(name, value);
+            }
+            cursor = cursor.add(1);
+        }
+    }
+    #[cfg(unix)]
+    super::exec_spawn::install_forwarding();
+    let child = command.spawn();
+    let child = match child {
+        Ok(child) => child,
+        Err(_) => {
+            if !keep {
+                let _ = fs::remove_file(&path);
+            }
+            return Err(proc_exit::sysexits::OS_ERR
+                .with_message(format!("Failed to launch {}", path.display())));
+        }
+    };
+
+    // Clean up before exiting like the flavor, which may raise a signal
+    #[cfg(unix)]
+    {
+        let status = super::exec_spawn::wait_forwarding_signals(child.id() as libc::pid_t);
+        if !keep {
+            let _ = fs::remove_file(&path);
+        }
+        super::exec_spawn::exit_like(status?)
+    }
+    #[cfg(not(unix))]
+    {
+        let mut child = child;
+        let status = child.wait();
+        if !keep {
+            let _ = fs::remove_file(&path);
+        }
+        let status = status.map_err(|_| {
+            proc_exit::sysexits::OS_ERR.with_message(format!("Failed to wait for {}", path.display()))
+        })?;
+        proc_exit::Code::from_status(status).ok()
+    }
+}
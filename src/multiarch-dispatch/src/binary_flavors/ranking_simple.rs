@@ -0,0 +1,11 @@
+use super::{CpuFeatList, FatBin, FlavorsRank};
+use crate::ranking;
+
+impl<'a> FlavorsRank<'a> for FatBin<'a> {
+    /// Returns the index of the flavor with the most features,
+    /// or -1 if the list is empty
+    fn get_top_ranked(patches_features: impl Iterator<Item = CpuFeatList<'a>>) -> isize
+    {
+        ranking::top_ranked_simple(patches_features.map(|list| list.0))
+    }
+}
@@ -0,0 +1,121 @@
+//! A minimal bump allocator for the dispatcher.
+//!
+//! The dispatcher allocates a few large buffers (zstd decoding, bspatch) then replaces itself with the flavor,
+//! so small allocations are never freed, except for the last one which can be popped or grown in place,
+//! and one that cannot grow in place leaks its old block.
+//! Large buffers are grown repeatedly while decoding, they are left to the system allocator.
+//! Chunks are taken from the system allocator, so the size of the executable is not guaranteed to shrink.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const CHUNK_SIZE: usize = 1 << 20;
+/// Allocations from this size are served by the system allocator
+const LARGE_SIZE: usize = CHUNK_SIZE / 4;
+
+struct Arena {
+    cursor: usize, // Next free address
+    end: usize,    // End of the current chunk
+    last: usize,   // Start of the last allocation, 0 if none
+}
+
+pub struct BumpAlloc {
+    locked: AtomicBool,
+    arena: UnsafeCell<Arena>,
+}
+
+impl Default for BumpAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The arena is only accessed while holding the spin lock
+unsafe impl Sync for BumpAlloc {}
+
+impl BumpAlloc {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            arena: UnsafeCell::new(Arena { cursor: 0, end: 0, last: 0 }),
+        }
+    }
+
+    fn with_arena<R>(&self, f: impl FnOnce(&mut Arena) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+        let result = f(unsafe { &mut *self.arena.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+unsafe impl GlobalAlloc for BumpAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() >= LARGE_SIZE {
+            return System.alloc(layout);
+        }
+        self.with_arena(|arena| {
+            let mut start = arena.cursor.next_multiple_of(layout.align());
+            if arena.cursor == 0 || start + layout.size() > arena.end {
+                let size = CHUNK_SIZE.max(layout.size() + layout.align());
+                let chunk = System.alloc(Layout::from_size_align_unchecked(size, 16));
+                if chunk.is_null() {
+                    return ptr::null_mut();
+                }
+                arena.cursor = chunk as usize;
+                arena.end = chunk as usize + size;
+                start = arena.cursor.next_multiple_of(layout.align());
+            }
+            arena.cursor = start + layout.size();
+            arena.last = start;
+            start as *mut u8
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // The layout is the one of the allocation, so the size tells where it comes from
+        if layout.size() >= LARGE_SIZE {
+            return System.dealloc(ptr, layout);
+        }
+        self.with_arena(|arena| {
+            if ptr as usize == arena.last {
+                arena.cursor = arena.last;
+                arena.last = 0;
+            }
+        })
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if layout.size() >= LARGE_SIZE && new_size >= LARGE_SIZE {
+            return System.realloc(ptr, layout, new_size);
+        }
+        let grown_in_place = self.with_arena(|arena| {
+            let fits = layout.size() < LARGE_SIZE
+                && new_size < LARGE_SIZE
+                && ptr as usize == arena.last
+                && arena.last + new_size <= arena.end;
+            if fits {
+                arena.cursor = arena.last + new_size;
+            }
+            fits
+        });
+        if grown_in_place {
+            return ptr;
+        }
+
+        // The old block is only reclaimed if it is the last one and the new one is not in the arena
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
@@ -0,0 +1,62 @@
+//! Filtering of the environment forwarded to the selected flavor.
+//!
+//! By default the environment is forwarded verbatim.
+//! Operators can restrict it with comma-separated lists of variable names:
+//! - `CARGO_MULTIARCH_ENV_ALLOW`: only these variables are forwarded
+//! - `CARGO_MULTIARCH_ENV_DENY`: these variables are removed
+use std::ffi::{c_char, CStr};
+use std::ptr;
+
+const ALLOW_VAR: &[u8] = b"CARGO_MULTIARCH_ENV_ALLOW";
+const DENY_VAR: &[u8] = b"CARGO_MULTIARCH_ENV_DENY";
+
+/// Splits a `NAME=value` entry
+fn split_entry(entry: &[u8]) -> (&[u8], &[u8]) {
+    match entry.iter().position(|&b| b == b'=') {
+        Some(eq) => (&entry[..eq], &entry[eq + 1..]),
+        None => (entry, &[]),
+    }
+}
+
+fn contains(list: Option<&[u8]>, name: &[u8]) -> bool {
+    list.is_some_and(|list| list.split(|&b| b == b',').any(|item| item.trim_ascii() == name))
+}
+
+/// Returns a null-terminated copy of `envp` with the filtered out variables removed,
+/// or None if no filter is configured.
+/// The returned pointers borrow the strings of `envp`.
+pub(crate) unsafe fn filter_envp(envp: *const *const c_char) -> Option<Vec<*const c_char>> {
+    if envp.is_null() {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    let mut cursor = envp;
+    while !(*cursor).is_null() {
+        entries.push((*cursor, CStr::from_ptr(*cursor).to_bytes()));
+        cursor = cursor.add(1);
+    }
+
+    let lookup = |var: &[u8]| {
+        entries
+            .iter()
+            .map(|(_, entry)| split_entry(entry))
+            .find_map(|(name, value)| (name == var).then_some(value))
+    };
+    let allow = lookup(ALLOW_VAR);
+    let deny = lookup(DENY_VAR);
+    if allow.is_none() && deny.is_none() {
+        return None;
+    }
+
+    let mut filtered: Vec<*const c_char> = entries
+        .iter()
+        .filter(|(_, entry)| {
+            let (name, _) = split_entry(entry);
+            (allow.is_none() || contains(allow, name)) && !contains(deny, name)
+        })
+        .map(|&(ptr, _)| ptr)
+        .collect();
+    filtered.push(ptr::null());
+    Some(filtered)
+}
@@ -1,14 +1,25 @@
-#![no_main]
-#![feature(stdarch_internal)]
-#![allow(internal_features)]
+// The unit tests keep the test harness entry point, the dispatcher code is then unused
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code))]
 
 use libc::c_char;
 use std::ffi::CStr;
+use std::path::Path;
 
-use binary_flavors::{FatBin, Executable, CpuFeatList};
-use proc_exit::{exit, Exit, sysexits::io_to_sysexists};
+use binary_flavors::{FatBin, Executable, ExecMode, CpuFeatList};
+use proc_exit::{Exit, sysexits::io_to_sysexists};
 
 mod binary_flavors;
+#[cfg(feature = "bump-alloc")]
+mod bump_alloc;
+mod env_filter;
+use multiarch_format as format;
+#[allow(dead_code)] // A single strategy is compiled in, cargo-multiarch uses both
+mod ranking;
+mod selection_log;
+
+#[cfg(feature = "bump-alloc")]
+pub use bump_alloc::BumpAlloc;
 
 const FATBIN: FatBin<'static> = include_fatbin();
 
@@ -16,6 +27,21 @@ const fn include_fatbin<'a>() -> FatBin<'a> {
     include!(concat!(env!("OUT_DIR"), "/fatbin.rs"))
 }
 
+// cargo-multiarch passes the hash of the artifacts it wrote, see `ARTIFACTS_HASH_ENV`.
+// A fatbin.rs generated for other artifacts, by a build script that did not rerun
+// or a concurrent build sharing the target directory, would embed stale flavors.
+const _: () = if let Some(expected) = option_env!("MULTIARCH_ARTIFACTS_HASH") {
+    match u64::from_str_radix(expected, 16) {
+        Ok(expected) => assert!(
+            FATBIN.artifacts_hash == expected,
+            "The generated fatbin.rs does not embed the artifacts of MULTIARCH_ARTIFACTS, rebuild the dispatcher"
+        ),
+        Err(_) => panic!("MULTIARCH_ARTIFACTS_HASH is not a hexadecimal hash"),
+    }
+};
+
+include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
 /// Entry point of the fat binary
 /// This does
 /// 1. CPU feature detection
@@ -23,10 +49,15 @@ const fn include_fatbin<'a>() -> FatBin<'a> {
 /// 3. Launch it, forwarding arguments and environment
 ///
 /// This should be imported by the fat binary package that is auto-generated.
+///
+/// # Safety
+///
+/// `argv` and `envp` must be the null-terminated arrays that the C runtime passes to `main`.
+#[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "C" fn main(argc: i32, argv: *const *const c_char, envp: *const *const c_char) {
     let status = dispatch(argc, argv, envp);
-    exit(status);
+    proc_exit::exit(status);
 }
 
 unsafe fn dispatch(
@@ -34,12 +65,43 @@ unsafe fn dispatch(
     argv: *const *const c_char,
     envp: *const *const c_char,
 ) -> Result<(), Exit> {
+    // Referenced so that the linker keeps them for `cargo multiarch verify`
+    std::hint::black_box(BUILD_INFO);
+    std::hint::black_box(FORMAT_VERSION_TAG);
     let name_prefix = if argc > 0 {
         CStr::from_ptr(*argv).to_str().unwrap()
     } else {
         "unnamed_multiarch"
     };
-    // Pretty sure the error can be handled in a simpler manner
-    let bin = FATBIN.get_best_flavor(name_prefix).map_err(|e| io_to_sysexists(e.kind()).unwrap()).map_err(|code| code.as_exit())?;
-    bin.exec(argc, argv, envp)
+    if FATBIN.format_version != format::FORMAT_VERSION {
+        return Err(proc_exit::sysexits::SOFTWARE_ERR.with_message(format!(
+            "Fat binary format version {} is not supported by this dispatcher (expected {}), rebuild with a matching cargo-multiarch",
+            FATBIN.format_version,
+            format::FORMAT_VERSION,
+        )));
+    }
+    if std::env::var_os(format::VERIFY_ENV).is_some_and(|verify| !verify.is_empty()) {
+        return if FATBIN.verify() {
+            Ok(())
+        } else {
+            Err(proc_exit::sysexits::DATA_ERR.as_exit())
+        };
+    }
+    if let Some(dir) = std::env::var_os(format::EXTRACT_ENV).filter(|dir| !dir.is_empty()) {
+        return FATBIN.extract_all(Path::new(&dir), !BUILD_INFO.is_empty()).map_err(|e| {
+            io_to_sysexists(e.kind())
+                .unwrap_or(proc_exit::sysexits::IO_ERR)
+                .with_message(format!("multiarch: {e}"))
+        });
+    }
+    let bin = FATBIN.get_best_flavor(name_prefix).map_err(|e| {
+        io_to_sysexists(e.kind())
+            .unwrap_or(proc_exit::sysexits::IO_ERR)
+            .with_message(format!("multiarch: {e}"))
+    })?;
+    // Must outlive exec as it borrows envp
+    let filtered_envp = env_filter::filter_envp(envp);
+    let envp = filtered_envp.as_ref().map_or(envp, |filtered| filtered.as_ptr());
+    let mode = ExecMode::from_env()?;
+    bin.exec(mode, argc, argv, envp)
 }
@@ -0,0 +1,133 @@
+//! Flavor ranking strategies of the dispatcher.
+//! This module only depends on core, cargo-multiarch includes it
+//! to simulate the selection of the dispatcher with `--print selected-flavor`.
+//!
+//! Both strategies take the features lists of the flavors supported by the host CPU
+//! in the embedded order and return the index of the top ranked one,
+//! or -1 if no flavor is ranked.
+
+/// Curated ranking
+/// - We first map flavor instructions to a certain level
+/// - then we pick the highest weight
+/// - and if there are multiple features in the sam weight,
+///   we pick the flavor with the highest count of top features
+///
+/// Example:
+///   Bigint/elliptic curves code may be compiled with
+///   - generic
+///   - or BM1 (MULX)
+///   - or BMI1 + BMI2 (ADOX, ADCX)
+///
+///   and all have significant performance profile (10~15% and 30% compared to baseline)
+///   See table 2, p13 of https://raw.githubusercontent.com/wiki/intel/intel-ipsec-mb/doc/ia-large-integer-arithmetic-paper.pdf
+///
+///   Note: this is a contrived example as BMI1 and BMI2 shipped at the same time on Intel
+///         and AMD CPUs had a small market share
+///
+/// The levels are provided by
+///   https://en.wikipedia.org/wiki/X86-64#Microarchitecture_levels
+/// The features can be listed with
+///   rustc --print=target-features
+pub fn top_ranked_curated<'a>(features_lists: impl Iterator<Item = &'a [&'a str]>) -> isize {
+    let (top_idx, _, _, _) = features_lists.enumerate().fold(
+        (-1isize, 0, 0, 0),
+        |(top_index, top_level, top_weight, top_count), (index, patch_feats)| {
+            let (bin_level, bin_weight, bin_count) =
+                patch_feats.iter().fold((0, 0, 0), |max, feature| {
+                    let (max_level, max_weight, count) = max;
+                    if let Some((level, weight)) = x86_rank(feature) {
+                        if (level, weight) > (max_level, max_weight) {
+                            (level, weight, 1)
+                        } else if (level, weight) == (max_level, max_weight) {
+                            (level, weight, count + 1)
+                        } else {
+                            (level, weight, count)
+                        }
+                    } else {
+                        (max_level, max_weight, count)
+                    }
+                });
+            if (bin_level, bin_weight, bin_count) > (top_level, top_weight, top_count) {
+                (index as isize, bin_level, bin_weight, bin_count)
+            } else {
+                (top_index, top_level, top_weight, top_count)
+            }
+        },
+    );
+    top_idx
+}
+
+/// Simple ranking
+/// - The flavor requiring the most CPU features wins
+/// - ties are broken by the embedded order, flavors are embedded by decreasing number of features
+///
+/// This ignores the microarchitecture levels of the curated ranking,
+/// it is predictable and architecture-agnostic but may pick a flavor
+/// with many minor features over one with a single major feature (e.g. AVX2).
+pub fn top_ranked_simple<'a>(features_lists: impl Iterator<Item = &'a [&'a str]>) -> isize {
+    let (top_idx, _) = features_lists.enumerate().fold(
+        (-1isize, 0),
+        |(top_index, top_count), (index, patch_feats)| {
+            let count = patch_feats.len();
+            if top_index == -1 || count > top_count {
+                (index as isize, count)
+            } else {
+                (top_index, top_count)
+            }
+        },
+    );
+    top_idx
+}
+
+/// Order in which the flavors are embedded: most CPU features first,
+/// then by features so that it does not depend on the order of the configuration.
+/// The build script sorts the flavors with it, cargo-multiarch to simulate the selection.
+#[allow(dead_code)] // Used by the build script and cargo-multiarch only
+pub fn embedded_order<T: Ord>(features1: &[T], features2: &[T]) -> core::cmp::Ordering {
+    features1
+        .len()
+        .cmp(&features2.len())
+        .reverse()
+        .then_with(|| features1.cmp(features2))
+}
+
+/// Highest microarchitecture level of x86 features, 0 if none is ranked.
+/// A flavor with a lower level runs on more CPUs, weights within a level say nothing about that.
+#[allow(dead_code)] // Used by cargo-multiarch only
+pub fn x86_max_level<'a>(features: impl Iterator<Item = &'a str>) -> usize {
+    features
+        .filter_map(x86_rank)
+        .map(|(level, _)| level)
+        .max()
+        .unwrap_or(0)
+}
+
+/// (level, weight) of x86 features
+#[rustfmt::skip]
+fn x86_rank(feature: &str) -> Option<(usize, usize)> {
+    let rank = match feature {
+        "sse3"      => (2, 1), // Intel Q1 2004 Pentium 4,    AMD Q2 2005 Athlon 64 (Venice, San Diego)
+        "ssse3"     => (2, 2), // Intel Q2 2006,              AMD Q4 2011 Bulldozer
+        "sse4.1"    => (2, 3), // Intel Q4 2007 Penryn,       AMD Q4 2011 Bulldozer (note: AMD had SSE4a with part of 4.1)
+        "popcnt"    => (2, 4), // Intel Q4 2008 Nehalem,      AMD Q4 2007 K10
+        "sse4.2"    => (2, 5), // Intel Q4 2008 Nehalem,      AMD Q4 2011 Bulldozer (required by Windows 11 24H2)
+        "avx"       => (3, 1), // Intel Q1 2011 Sandy Bridge, AMD Q4 2011 Bulldozer
+        "avx2"      => (3, 2), // Intel Q2 2013 Haswell,      AMD Q2 2015 Excavator
+        "lzcnt"     => (3, 2), // Intel Q2 2013 Haswell,      AMD Q2 2015 Excavator (2014, low-power Jaguar)
+        "bmi1"      => (3, 2), // Intel Q2 2013 Haswell,      AMD Q2 2015 Excavator (2014, low-power Jaguar)
+        "bmi2"      => (3, 2), // Intel Q2 2013 Haswell,      AMD Q2 2015 Excavator
+        // TODO: AVX-512 is a mess
+        "avx512f"   => (4, 1),
+        "avx512cd"  => (4, 1),
+        "avx512vl"  => (4, 2),
+        "avx512dq"  => (4, 2),
+        "avx512bw"  => (4, 2),
+        // TODO: AVX256 IFMA are supported on Intel Alder lake or later, while AVX512 is not
+        // TODO: where to put accelerators like:
+        //   - AES, SHA256,
+        //   - GFNI (Galois field new instructions for binary polynomial multiplication),
+        //   - VPCLMULQDQ (vectorized Carryless mul)
+        _ => return None,
+    };
+    Some(rank)
+}
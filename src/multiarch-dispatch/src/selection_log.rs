@@ -0,0 +1,49 @@
+//! Opt-in record of the selected flavors for fleet telemetry.
+//! `CARGO_MULTIARCH_SELECTION_LOG=<path>` appends a JSON line per launch:
+//! `{"flavor":"avx2,bmi2","host_features":["avx","avx2",..],"cpu":"x86_64-AuthenticAMD-f19-m21"}`
+//! with `generic` for the fallback and the CPU identified by [`cpu_model_id`].
+//!
+//! The file is opened in append mode and each line written at once,
+//! so concurrent launches on a host do not interleave their lines.
+//! Failures are ignored: telemetry must not prevent the program from running.
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use crate::binary_flavors::cpu_model_id;
+
+const LOG_VAR: &str = "CARGO_MULTIARCH_SELECTION_LOG";
+
+pub(crate) fn record(flavor: &str, host_features: &HashSet<String>) {
+    // Cheap when disabled, nothing else is computed
+    let Some(path) = std::env::var_os(LOG_VAR).filter(|path| !path.is_empty()) else {
+        return;
+    };
+    let mut features: Vec<&str> = host_features.iter().map(String::as_str).collect();
+    features.sort_unstable();
+    let features: Vec<String> = features.into_iter().map(json_string).collect();
+    let line = format!(
+        "{{\"flavor\":{},\"host_features\":[{}],\"cpu\":{}}}\n",
+        json_string(flavor),
+        features.join(","),
+        json_string(&cpu_model_id()),
+    );
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = file.write_all(line.as_bytes());
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
@@ -0,0 +1,5 @@
+//! Runs the unit tests of the build script, which cargo does not test on its own.
+
+#[path = "../build.rs"]
+#[allow(dead_code)] // main and the environment handling are not tested
+mod build;
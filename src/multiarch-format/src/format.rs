@@ -0,0 +1,166 @@
+//! Fat binary format shared between the build script that generates it, the dispatcher that reads it
+//! and cargo-multiarch. Dependency-free, included by path where the crate cannot be a dependency.
+
+/// Version of the generated fat binary layout.
+/// Bump on any change to the compression scheme, the patch format, the `FatBin` fields
+/// or the output of [`VERIFY_ENV`] and [`EXTRACT_ENV`].
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Environment variable that makes the dispatcher reconstruct and check every flavor
+/// instead of running one, used by `cargo multiarch verify`.
+/// Each flavor is reported on a stdout line `<ok|failed>\t<features>\t<size>\t<detail>`
+pub const VERIFY_ENV: &str = "CARGO_MULTIARCH_VERIFY";
+
+/// Environment variable that makes the dispatcher reconstruct every flavor into the directory
+/// it names instead of running one, used by `cargo multiarch add-flavor` to repack them.
+/// The first stdout line holds the dispatcher options the fat binary was built with,
+/// `options\t<curated|simple>\t<delta_chain>\t<hypervisor_ignored_features>\t<build_info>`,
+/// delta_chain `1`, `0` or empty when it cannot be told with fewer than two flavors,
+/// build_info `1` or `0`. Each flavor is then reported on a line
+/// `<features>\t<min_glibc>\t<priority>\t<path>`,
+/// features comma-separated and min_glibc `<major>.<minor>`, all three empty if none
+pub const EXTRACT_ENV: &str = "CARGO_MULTIARCH_EXTRACT";
+
+/// Prefix of the [`FORMAT_VERSION`] embedded in every fat binary, in decimal and terminated
+/// by a NUL byte, checked by cargo-multiarch before running a fat binary
+#[allow(dead_code)] // Written by the build script, read by cargo-multiarch
+pub const FORMAT_VERSION_MARKER: &str = "multiarch-format-version:";
+
+/// The NUL-terminated value following `marker` in the content of a fat binary, if any
+#[allow(dead_code)] // Read by cargo-multiarch
+pub fn find_marked<'a>(content: &'a [u8], marker: &str) -> Option<&'a [u8]> {
+    let marker = marker.as_bytes();
+    let start = content
+        .windows(marker.len())
+        .position(|window| window == marker)?
+        + marker.len();
+    let len = content[start..].iter().position(|&byte| byte == 0)?;
+    Some(&content[start..start + len])
+}
+
+/// Checks that the content of `path` is a fat binary of this [`FORMAT_VERSION`],
+/// before running it to read its flavors
+#[allow(dead_code)] // Used by cargo-multiarch
+pub fn check_format_version(path: &std::path::Path, content: &[u8]) -> Result<(), String> {
+    let version = find_marked(content, FORMAT_VERSION_MARKER)
+        .and_then(|version| std::str::from_utf8(version).ok()?.parse::<u32>().ok())
+        .ok_or_else(|| {
+            format!(
+                "{} is not a fat binary, or was built by an older cargo-multiarch",
+                path.display()
+            )
+        })?;
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "{} has fat binary format version {version}, this version of cargo-multiarch reads version {FORMAT_VERSION}, use the cargo-multiarch it was built with",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a build before it is embedded. An empty file, e.g. truncated by an interrupted copy,
+/// would be embedded as is and every patch against it would be a full rewrite of its flavor.
+#[allow(dead_code)] // Only the build script embeds executables
+pub fn check_executable(what: &str, path: &std::path::Path, content: &[u8]) -> Result<(), String> {
+    if content.is_empty() {
+        return Err(format!(
+            "The {what} {} is empty, rebuild it before packing",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Prefix of the build information embedded with `--embed-build-info`,
+/// `key=value` lines terminated by a NUL byte, found by scanning the fat binary
+#[allow(dead_code)] // Written by the build script, read by cargo-multiarch
+pub const BUILD_INFO_MARKER: &str = "multiarch-build-info:";
+
+/// Environment variable of the dispatcher build with the hash of the artifacts to embed:
+/// [`fnv1a`] from [`FNV_OFFSET`] over the artifacts description file, then over each
+/// executable it lists, in its order, as 16 hexadecimal digits. The dispatcher does not
+/// compile if the generated sources it includes embed other artifacts, e.g. stale ones.
+#[allow(dead_code)] // Set by cargo-multiarch, read by the build script
+pub const ARTIFACTS_HASH_ENV: &str = "MULTIARCH_ARTIFACTS_HASH";
+
+/// `<major>.<minor>` glibc version, as configured by `min_glibc`
+/// and returned by `gnu_get_libc_version`. Distributions may append components
+/// and suffixes, e.g. `2.31.9000` or `2.35-0ubuntu3`, only the leading major and minor count.
+#[allow(dead_code)] // The dispatcher only checks it on glibc hosts
+pub fn parse_glibc_version(version: &str) -> Option<(u32, u32)> {
+    let (major, rest) = version.split_once('.')?;
+    let minor_len = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    Some((major.parse().ok()?, rest[..minor_len].parse().ok()?))
+}
+
+/// FNV-1a, hashes the embedded executables so that their reconstruction can be checked
+pub const fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    hash
+}
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glibc_versions_with_distribution_suffixes() {
+        assert_eq!(parse_glibc_version("2.34"), Some((2, 34)));
+        assert_eq!(parse_glibc_version("2.31.9000"), Some((2, 31)));
+        assert_eq!(parse_glibc_version("2.35-0ubuntu3"), Some((2, 35)));
+        assert_eq!(parse_glibc_version("2"), None);
+        assert_eq!(parse_glibc_version("2."), None);
+        assert_eq!(parse_glibc_version("x.31"), None);
+    }
+
+    #[test]
+    fn format_version_is_checked() {
+        let path = std::path::Path::new("app");
+        let fatbin = |version: &str| {
+            [
+                b"\x7fELF\0",
+                FORMAT_VERSION_MARKER.as_bytes(),
+                version.as_bytes(),
+                b"\0\x01",
+            ]
+            .concat()
+        };
+        assert_eq!(
+            check_format_version(path, &fatbin(&FORMAT_VERSION.to_string())),
+            Ok(())
+        );
+        assert_eq!(
+            check_format_version(path, &fatbin("1")),
+            Err(format!(
+                "app has fat binary format version 1, this version of cargo-multiarch reads version {FORMAT_VERSION}, use the cargo-multiarch it was built with"
+            ))
+        );
+        // Not a fat binary, or one from before the version was embedded
+        assert_eq!(
+            check_format_version(path, b"\x7fELF\0CARGO_MULTIARCH_VERIFY\0"),
+            Err("app is not a fat binary, or was built by an older cargo-multiarch".to_string())
+        );
+    }
+
+    #[test]
+    fn empty_fallback_is_rejected() {
+        let path = std::path::Path::new("target/release/app");
+        assert_eq!(
+            check_executable("fallback build", path, &[]),
+            Err(
+                "The fallback build target/release/app is empty, rebuild it before packing"
+                    .to_string()
+            ),
+        );
+        assert_eq!(check_executable("fallback build", path, b"\x7fELF"), Ok(()));
+    }
+}
@@ -0,0 +1,170 @@
+//! Storage format of the flavors embedded in a cargo-multiarch fat binary,
+//! to reconstruct them in custom loaders that select and run flavors their own way.
+//!
+//! The dispatcher build script embeds, as static byte slices:
+//! - the base executable: the fallback without extra CPU features,
+//!   or the flavor with the fewest when built with `--no-fallback`.
+//!   It is a single zstd frame with the content size in its header.
+//! - one patch per other flavor, in the bsdiff format of the `qbsdiff` crate,
+//!   computed against the *uncompressed* base executable,
+//!   or with `--delta-chain` against the next flavor with fewer CPU features.
+//!   The flavor each patch applies to is embedded, `None` for the base executable.
+//! - the CPU features of each flavor, as rustc names like `avx2`,
+//!   and its minimum glibc version as `(major, minor)`, `(0, 0)` without requirement.
+//! - the configured priority of each flavor, `None` if unset: among the flavors a host
+//!   supports, those with the highest priority are selected over the ranked ones.
+//! - the CPU features to distrust on hosts with the CPUID hypervisor bit,
+//!   flavors using them are skipped in virtual machines.
+//! - the [`fnv1a`] hash of each reconstructed executable, starting from [`FNV_OFFSET`].
+//!
+//! The base executable is decoded with [`decode_base`], or streamed with [`decode_base_into`].
+//! A flavor is reconstructed by decoding the base with [`decode_base`],
+//! then applying its patch with [`apply_patch`], preceded in a delta chain
+//! by the patches of the flavors it is based on, see [`patch_chain`] and [`reconstruct`].
+//! The layout is versioned by [`FORMAT_VERSION`].
+use std::fmt;
+use std::io;
+
+use qbsdiff::Bspatch;
+
+mod format;
+pub use format::*;
+
+/// Decodes the zstd-compressed base executable
+pub fn decode_base(default_exe: &[u8]) -> io::Result<Vec<u8>> {
+    // The content size is in the frame header, avoid growing the buffer on large executables
+    let base_size = zstd::zstd_safe::get_frame_content_size(default_exe)
+        .ok()
+        .flatten()
+        .unwrap_or(0);
+    let mut base = Vec::with_capacity(base_size as usize);
+    zstd::stream::copy_decode(default_exe, &mut base)?;
+    Ok(base)
+}
+
+/// Streams the decoded base executable into `output`, without holding it in memory
+pub fn decode_base_into(default_exe: &[u8], output: impl io::Write) -> io::Result<()> {
+    zstd::stream::copy_decode(default_exe, output)
+}
+
+/// Applies the patch of a flavor to the decoded base executable
+pub fn apply_patch(base: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    let patcher = Bspatch::new(patch)
+        .map_err(|e| io::Error::new(e.kind(), format!("invalid patch header: {e}")))?;
+    let mut exe = Vec::with_capacity(patcher.hint_target_size() as usize);
+    patcher.apply(base, io::Cursor::new(&mut exe))?;
+    Ok(exe)
+}
+
+/// Patches to apply in order to the base executable to reconstruct the flavor `id`,
+/// from the embedded patches and the flavor each applies to. `None` is the base executable.
+pub fn patch_chain<'a>(
+    patches: &[&'a [u8]],
+    bases: &[Option<usize>],
+    id: Option<usize>,
+) -> io::Result<Vec<&'a [u8]>> {
+    let mut chain = Vec::new();
+    let mut next = id;
+    while let Some(id) = next {
+        if chain.len() == patches.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "cycle"));
+        }
+        chain.push(patches[id]);
+        next = bases[id];
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// Step of [`reconstruct`] that failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    DecodeBase,
+    Patch,
+    Write,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::DecodeBase => "zstd decode of the base executable",
+            Stage::Patch => "bspatch",
+            Stage::Write => "write to the executable file",
+        })
+    }
+}
+
+/// Error of [`reconstruct`], with the stage that failed
+#[derive(Debug)]
+pub struct ReconstructError {
+    pub stage: Stage,
+    pub error: io::Error,
+}
+
+impl fmt::Display for ReconstructError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: {}", self.stage, self.error)
+    }
+}
+
+impl std::error::Error for ReconstructError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<ReconstructError> for io::Error {
+    fn from(e: ReconstructError) -> Self {
+        io::Error::new(e.error.kind(), e.to_string())
+    }
+}
+
+/// Hashes what is written to `inner` and records whether writing failed,
+/// to tell output errors from decoding errors when the decoder writes directly to the output
+struct HashingWriter<W> {
+    inner: W,
+    hash: u64,
+    failed: bool,
+}
+
+impl<W: io::Write> io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf).inspect_err(|_| self.failed = true)?;
+        self.hash = fnv1a(self.hash, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().inspect_err(|_| self.failed = true)
+    }
+}
+
+/// Reconstructs a flavor into `output` by applying `patches` in order to the base executable:
+/// none for the base executable, a single one unless built with `--delta-chain`.
+/// The base executable alone is streamed, it is not held in memory.
+/// Returns the [`fnv1a`] hash of the executable to compare with the embedded one
+pub fn reconstruct(
+    default_exe: &[u8],
+    patches: &[&[u8]],
+    mut output: impl io::Write,
+) -> Result<u64, ReconstructError> {
+    let stage = |stage| move |error| ReconstructError { stage, error };
+    if patches.is_empty() {
+        let mut output = HashingWriter {
+            inner: output,
+            hash: FNV_OFFSET,
+            failed: false,
+        };
+        return match decode_base_into(default_exe, &mut output) {
+            Ok(()) => Ok(output.hash),
+            Err(e) if output.failed => Err(stage(Stage::Write)(e)),
+            Err(e) => Err(stage(Stage::DecodeBase)(e)),
+        };
+    }
+    let mut exe = decode_base(default_exe).map_err(stage(Stage::DecodeBase))?;
+    for patch in patches {
+        exe = apply_patch(&exe, patch).map_err(stage(Stage::Patch))?;
+    }
+    output.write_all(&exe).map_err(stage(Stage::Write))?;
+    Ok(fnv1a(FNV_OFFSET, &exe))
+}
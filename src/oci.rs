@@ -0,0 +1,115 @@
+//! `--emit oci`: writes the final binary as a single-layer image in an OCI image layout
+//! directory (`oci-layout`, `index.json` and `blobs/sha256`), which registry tools such as
+//! skopeo, crane or oras can push as is. No registry client is involved.
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use target_lexicon::{Architecture, Triple};
+
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+
+/// Platform architecture of the image, named like GOARCH as the image spec requires
+pub(crate) fn image_architecture(target: &Triple) -> Option<&'static str> {
+    Some(match target.architecture {
+        Architecture::X86_64 => "amd64",
+        Architecture::X86_32(_) => "386",
+        Architecture::Aarch64(_) => "arm64",
+        Architecture::Arm(_) => "arm",
+        Architecture::Riscv64(_) => "riscv64",
+        Architecture::Powerpc64le => "ppc64le",
+        Architecture::Powerpc64 => "ppc64",
+        Architecture::S390x => "s390x",
+        Architecture::LoongArch64 => "loong64",
+        _ => return None,
+    })
+}
+
+/// Writes an image layout to `dir`, replacing a previous one, with `binary` as `/<name>`
+/// and entrypoint. The layer has no timestamps, a reproducible binary gives the same digests.
+pub(crate) fn write_image_layout(
+    binary: &Path,
+    name: &str,
+    target: &Triple,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let architecture = image_architecture(target)
+        .with_context(|| format!("No OCI image architecture for '{}'", target.architecture))?;
+
+    if dir.exists() {
+        fs::remove_dir_all(dir)
+            .with_context(|| format!("Failed to remove the previous image `{}`", dir.display()))?;
+    }
+    let blobs = dir.join("blobs").join("sha256");
+    fs::create_dir_all(&blobs)
+        .with_context(|| format!("Failed to create directory `{}`", blobs.display()))?;
+    // Content-addressed, returns the digest and size referencing the blob
+    let write_blob = |content: &[u8]| -> anyhow::Result<(String, usize)> {
+        let digest = format!("{:x}", Sha256::digest(content));
+        let path = blobs.join(&digest);
+        fs::write(&path, content)
+            .with_context(|| format!("Failed to write `{}`", path.display()))?;
+        Ok((format!("sha256:{digest}"), content.len()))
+    };
+
+    let pack_layer = || -> std::io::Result<Vec<u8>> {
+        let mut layer = tar::Builder::new(Vec::new());
+        // No timestamps nor owners, only the executable bit is kept
+        layer.mode(tar::HeaderMode::Deterministic);
+        layer.append_path_with_name(binary, name)?;
+        layer.into_inner()
+    };
+    let layer = pack_layer()
+        .with_context(|| format!("Failed to pack `{}` into an image layer", binary.display()))?;
+    // The config references the uncompressed layer, the manifest the compressed blob
+    let diff_id = format!("sha256:{:x}", Sha256::digest(&layer));
+    let layer = zstd::encode_all(layer.as_slice(), zstd::DEFAULT_COMPRESSION_LEVEL)
+        .context("Failed to compress the image layer")?;
+    let (layer_digest, layer_size) = write_blob(&layer)?;
+
+    let config = serde_json::to_vec(&json!({
+        "architecture": architecture,
+        "os": "linux",
+        "config": { "Entrypoint": [format!("/{name}")] },
+        "rootfs": { "type": "layers", "diff_ids": [diff_id] },
+    }))?;
+    let (config_digest, config_size) = write_blob(&config)?;
+
+    let manifest = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": MANIFEST_MEDIA_TYPE,
+        "config": {
+            "mediaType": CONFIG_MEDIA_TYPE,
+            "digest": config_digest,
+            "size": config_size,
+        },
+        "layers": [{
+            "mediaType": LAYER_MEDIA_TYPE,
+            "digest": layer_digest,
+            "size": layer_size,
+        }],
+    }))?;
+    let (manifest_digest, manifest_size) = write_blob(&manifest)?;
+
+    let index = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [{
+            "mediaType": MANIFEST_MEDIA_TYPE,
+            "digest": manifest_digest,
+            "size": manifest_size,
+            "platform": { "architecture": architecture, "os": "linux" },
+            "annotations": { "org.opencontainers.image.ref.name": "latest" },
+        }],
+    });
+    let index_path = dir.join("index.json");
+    fs::write(&index_path, format!("{index:#}\n"))
+        .with_context(|| format!("Failed to write `{}`", index_path.display()))?;
+    let layout_path = dir.join("oci-layout");
+    fs::write(&layout_path, r#"{"imageLayoutVersion":"1.0.0"}"#)
+        .with_context(|| format!("Failed to write `{}`", layout_path.display()))
+}
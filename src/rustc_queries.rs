@@ -1,10 +1,12 @@
-use std::io::BufRead;
-use std::path::PathBuf;
-use std::process::Command;
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::sync::LazyLock;
 
-use anyhow;
 use indoc::formatdoc;
+use itertools::Itertools;
+use serde::Serialize;
 
 static RUSTC: LazyLock<PathBuf> = LazyLock::new(|| {
     std::env::var_os("CARGO")
@@ -18,32 +20,239 @@ static RUSTC: LazyLock<PathBuf> = LazyLock::new(|| {
         .unwrap_or_else(|| "rustc".into())
 });
 
+#[derive(Serialize)]
+pub struct ListedCpu {
+    name: String,
+    description: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ListedFeature {
+    name: String,
+    description: Option<String>,
+    enabled: bool, // by the queried CPU
+}
+
+/// Effective toolchain, for bug reports
+#[derive(Serialize)]
+pub struct ToolchainSummary {
+    cargo_multiarch: &'static str,
+    rustc: PathBuf,
+    // None when the query failed, the summary is most useful when something is broken
+    version: Option<String>,
+    channel: Option<&'static str>,
+    host: Option<String>,
+    host_cpu: Option<String>,
+}
+
+impl ToolchainSummary {
+    pub fn for_humans(&self) -> String {
+        let unknown = |value: Option<&str>| value.unwrap_or("unknown").to_owned();
+        formatdoc!(
+            r#"
+            cargo-multiarch: {}
+            rustc:           {}
+            version:         {}
+            channel:         {}
+            host:            {}
+            host CPU:        {}"#,
+            self.cargo_multiarch,
+            self.rustc.display(),
+            unknown(self.version.as_deref()),
+            unknown(self.channel),
+            unknown(self.host.as_deref()),
+            unknown(self.host_cpu.as_deref()),
+        )
+    }
+}
+
+/// Separator of the flags in CARGO_ENCODED_RUSTFLAGS (ASCII unit separator)
+const ENCODED_RUSTFLAGS_SEPARATOR: char = '\x1f';
+
 /// Wrapper around the `rustc` command
+#[derive(Debug)]
 pub struct Rustc;
 
+/// The target and CPU queries the configuration and the builds depend on,
+/// implemented by [`Rustc`] and replaceable by canned data without a toolchain
+pub(crate) trait RustcQueries: std::fmt::Debug {
+    fn get_host_target(&self) -> anyhow::Result<String>;
+
+    /// See [`Rustc::target_triple_or_host`]
+    fn target_triple_or_host(&self, target_triple: Option<&str>) -> anyhow::Result<String> {
+        match target_triple {
+            Some(target_triple) => Ok(target_triple.to_owned()),
+            None => self.get_host_target(),
+        }
+    }
+
+    /// See [`Rustc::get_cpufeatures_for_programs`]
+    fn get_cpufeatures_for_programs(
+        &self,
+        target_triple: Option<&str>,
+        target_cpu: Option<&str>,
+    ) -> anyhow::Result<Vec<String>>;
+
+    /// See [`Rustc::get_effective_cpufeatures`]
+    fn get_effective_cpufeatures(
+        &self,
+        target_triple: &str,
+        target_features: &str,
+    ) -> anyhow::Result<BTreeSet<String>>;
+
+    /// See [`Rustc::get_target_features`]
+    fn get_target_features(&self, target_triple: &str) -> anyhow::Result<BTreeSet<String>>;
+
+    /// See [`Rustc::get_cpu_names`]
+    fn get_cpu_names(&self, target_triple: &str) -> anyhow::Result<BTreeSet<String>>;
+}
+
+impl RustcQueries for Rustc {
+    fn get_host_target(&self) -> anyhow::Result<String> {
+        Rustc::get_host_target()
+    }
+
+    fn get_cpufeatures_for_programs(
+        &self,
+        target_triple: Option<&str>,
+        target_cpu: Option<&str>,
+    ) -> anyhow::Result<Vec<String>> {
+        Rustc::get_cpufeatures_for_programs(target_triple, target_cpu)
+    }
+
+    fn get_effective_cpufeatures(
+        &self,
+        target_triple: &str,
+        target_features: &str,
+    ) -> anyhow::Result<BTreeSet<String>> {
+        Rustc::get_effective_cpufeatures(target_triple, target_features)
+    }
+
+    fn get_target_features(&self, target_triple: &str) -> anyhow::Result<BTreeSet<String>> {
+        Rustc::get_target_features(target_triple)
+    }
+
+    fn get_cpu_names(&self, target_triple: &str) -> anyhow::Result<BTreeSet<String>> {
+        Rustc::get_cpu_names(target_triple)
+    }
+}
+
 impl Rustc {
     fn command() -> Command {
         Command::new(RUSTC.as_path())
     }
 
-    /// Returns true if rustc is on the nightly release channel
-    pub fn is_nightly() -> bool {
-        let Ok(output) = Self::command().arg("-vV").output() else {
-            return false;
-        };
+    /// Returns the release of rustc, e.g. `1.86.0-nightly`
+    fn get_release() -> Option<String> {
+        let output = Self::command().arg("-vV").output().ok()?;
 
-        let release = output
+        output
             .stdout
             .lines()
             .map_while(Result::ok)
             .find_map(|line| line.strip_prefix("release: ").map(ToOwned::to_owned))
-            .unwrap_or_default();
+    }
+
+    /// Returns true if rustc is on the nightly release channel
+    pub fn is_nightly() -> bool {
+        Self::get_release().is_some_and(|release| release.contains("nightly"))
+    }
+
+    /// Summarizes the rustc used by cargo-multiarch and the host it runs on,
+    /// each query failing independently
+    pub fn get_toolchain_summary() -> ToolchainSummary {
+        ToolchainSummary {
+            cargo_multiarch: env!("CARGO_PKG_VERSION"),
+            rustc: RUSTC.to_path_buf(),
+            version: Self::get_version().ok(),
+            channel: Self::get_release().map(|release| {
+                if release.contains("nightly") {
+                    "nightly"
+                } else if release.contains("beta") {
+                    "beta"
+                } else {
+                    "stable"
+                }
+            }),
+            host: Self::get_host_target().ok(),
+            host_cpu: Self::get_host_cpu().ok(),
+        }
+    }
+
+    /// Returns the version line of rustc, e.g. `rustc 1.86.0-nightly (...)`
+    pub fn get_version() -> anyhow::Result<String> {
+        let output = Self::command().arg("-V").output()?;
+        anyhow::ensure!(output.status.success(), "Failed to query the rustc version");
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+
+    /// Returns the rustflags of the environment like cargo reads them:
+    /// CARGO_ENCODED_RUSTFLAGS takes precedence, flags may contain spaces like
+    /// `--remap-path-prefix=/home/my user=~`, else RUSTFLAGS is split on whitespace
+    pub fn get_env_rustflags() -> Vec<String> {
+        if let Ok(encoded) = std::env::var("CARGO_ENCODED_RUSTFLAGS") {
+            return encoded
+                .split(ENCODED_RUSTFLAGS_SEPARATOR)
+                .filter(|flag| !flag.is_empty())
+                .map(ToOwned::to_owned)
+                .collect();
+        }
+        std::env::var("RUSTFLAGS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+
+    /// Returns the CARGO_ENCODED_RUSTFLAGS value passing `flags` unsplit to rustc
+    pub fn encode_rustflags(flags: &[String]) -> String {
+        flags.join(&ENCODED_RUSTFLAGS_SEPARATOR.to_string())
+    }
 
-        release.contains("nightly")
+    /// Returns true if rustc accepts the unstable `features` gates,
+    /// by compiling an empty library with them into `out_dir`
+    pub fn supports_feature_gates(features: &[&str], out_dir: &Path) -> anyhow::Result<bool> {
+        let source: String = features
+            .iter()
+            .map(|feature| format!("#![feature({feature})]\n"))
+            .collect();
+        let mut child = Self::command()
+            .args([
+                "--crate-type=lib",
+                "--emit=metadata",
+                "--crate-name=multiarch_probe",
+            ])
+            .arg("--out-dir")
+            .arg(out_dir)
+            .args(["-A", "internal_features", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(source.as_bytes())?;
+        }
+        Ok(child.wait()?.success())
     }
 
     pub fn get_target_list() -> anyhow::Result<String> {
         let output = Self::command().args(["--print", "target-list"]).output()?;
+        // Human-facing output, an odd locale must not make it fail
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    pub fn get_target_spec_json(target_triple: Option<&str>) -> anyhow::Result<String> {
+        let target_triple = Self::target_triple_or_host(target_triple)?;
+        let output = Self::command()
+            .args(["-Zunstable-options", "--print=target-spec-json"])
+            .args(["--target", &target_triple])
+            .output()?;
+        anyhow::ensure!(
+            output.status.success(),
+            "Failed to query the target spec of '{target_triple}': {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
         String::from_utf8(output.stdout).map_err(anyhow::Error::msg)
     }
 
@@ -81,8 +290,8 @@ impl Rustc {
             {stderr}
             [rustc-end]"#,
             desc = format!("Querying CPUs for target '{}'", target_triple),
-            stdout = String::from_utf8(output.stdout).map_err(anyhow::Error::msg)?,
-            stderr = String::from_utf8(output.stderr).map_err(anyhow::Error::msg)?,
+            stdout = String::from_utf8_lossy(&output.stdout),
+            stderr = String::from_utf8_lossy(&output.stderr),
         ))
     }
 
@@ -95,18 +304,26 @@ impl Rustc {
             .stdout
             .lines()
             .map_while(Result::ok)
-            .find_map(|line| {
-                // We don't need a full blown regex compiler for such a simple line
-                line.strip_prefix(
-                    "    native                  - Select the CPU of the current host (currently ",
-                )?
-                .strip_suffix(").")
-                .map(ToOwned::to_owned)
-            })
+            .find_map(|line| Self::parse_native_cpu(&line))
             .ok_or_else(|| anyhow::anyhow!("cargo-multiarch: Failed to detect host CPU"))
     }
 
-    fn target_cpu_or_host(target_cpu: Option<&str>) -> anyhow::Result<String> {
+    /// Extracts the host CPU from the `native` line of `--print=target-cpus`, e.g.
+    /// `    native                  - Select the CPU of the current host (currently znver4).`
+    /// Column alignment and wording around `(currently <cpu>)` are not relied upon.
+    fn parse_native_cpu(line: &str) -> Option<String> {
+        let description = line.trim_start().strip_prefix("native")?;
+        if !description.starts_with(char::is_whitespace) {
+            // e.g. a CPU named `native-foo`
+            return None;
+        }
+        let (_, cpu) = description.split_once("(currently")?;
+        let (cpu, _) = cpu.split_once(')')?;
+        let cpu = cpu.trim();
+        (!cpu.is_empty()).then(|| cpu.to_owned())
+    }
+
+    pub(crate) fn target_cpu_or_host(target_cpu: Option<&str>) -> anyhow::Result<String> {
         // Hey dawg, I heard you liked to target CPUs
         if let Some(target_cpu) = target_cpu {
             Ok(target_cpu.to_owned())
@@ -128,10 +345,8 @@ impl Rustc {
             .arg(format!("-Ctarget-cpu={}", target_cpu))
             .output()?;
 
-        let features = output
-            .stdout
+        let features = String::from_utf8_lossy(&output.stdout)
             .lines()
-            .map_while(Result::ok)
             .take_while(|line| !line.starts_with("Code-generation features supported by LLVM"))
             .collect::<Vec<_>>()
             .join("\n");
@@ -145,7 +360,7 @@ impl Rustc {
             [rustc-end]"#,
             desc = format!("Querying features for CPU '{}'", target_cpu),
             features = features,
-            stderr = String::from_utf8(output.stderr).map_err(anyhow::Error::msg)?,
+            stderr = String::from_utf8_lossy(&output.stderr),
         ))
     }
     pub fn get_cpufeatures_for_programs(
@@ -167,17 +382,197 @@ impl Rustc {
             "Invalid CPU '{target_cpu}'"
         );
 
-        let features = output
-            .stdout
+        Ok(Self::parse_cfg_features(&output.stdout).collect())
+    }
+
+    /// Returns the CPU features rustc actually enables for a target
+    /// when passed `-Ctarget-feature=<target_features>` (e.g. `+avx2,+bmi2`).
+    /// This includes implied and baseline features
+    /// and excludes features rustc ignored.
+    pub fn get_effective_cpufeatures(
+        target_triple: &str,
+        target_features: &str,
+    ) -> anyhow::Result<BTreeSet<String>> {
+        let output = Self::command()
+            .arg("--print=cfg")
+            .args(["--target", target_triple])
+            .arg(format!("-Ctarget-feature={target_features}"))
+            .output()?;
+
+        // Unknown or unstable features are reported as warnings on stderr,
+        // features the target forbids or contradictory flags as errors
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let errors = stderr
+                .lines()
+                .filter(|line| line.starts_with("error"))
+                .join("\n");
+            anyhow::bail!(
+                "Invalid CPU features '{target_features}' for target '{target_triple}'{}{errors}",
+                if errors.is_empty() { "" } else { ":\n" }
+            );
+        }
+
+        Ok(Self::parse_cfg_features(&output.stdout).collect())
+    }
+
+    /// Returns the names of all the CPU features rustc and LLVM know for a target
+    pub fn get_target_features(target_triple: &str) -> anyhow::Result<BTreeSet<String>> {
+        let output = Self::command()
+            .arg("--print=target-features")
+            .args(["--target", target_triple])
+            .output()?;
+        anyhow::ensure!(
+            output.status.success(),
+            "Failed to query the CPU features of target '{target_triple}'"
+        );
+
+        Ok(
+            Self::parse_listing(&String::from_utf8_lossy(&output.stdout))
+                .map(|(name, _)| name)
+                .collect(),
+        )
+    }
+
+    /// Returns the names of all the CPUs rustc knows for a target
+    pub fn get_cpu_names(target_triple: &str) -> anyhow::Result<BTreeSet<String>> {
+        Ok(Self::list_cpus(target_triple)?
+            .into_iter()
+            .map(|cpu| cpu.name)
+            .collect())
+    }
+
+    /// Structured `--print=target-cpus`
+    pub fn list_cpus(target_triple: &str) -> anyhow::Result<Vec<ListedCpu>> {
+        let output = Self::command()
+            .args(["--print=target-cpus", "--target", target_triple])
+            .output()?;
+        anyhow::ensure!(
+            output.status.success(),
+            "Failed to query the CPUs of target '{target_triple}'"
+        );
+
+        Ok(
+            Self::parse_listing(&String::from_utf8_lossy(&output.stdout))
+                .map(|(name, description)| ListedCpu { name, description })
+                .collect(),
+        )
+    }
+
+    /// Structured `--print=target-features`, restricted to the features supported by rustc,
+    /// with those enabled by `target_cpu`
+    pub fn list_features(
+        target_triple: &str,
+        target_cpu: &str,
+    ) -> anyhow::Result<Vec<ListedFeature>> {
+        let output = Self::command()
+            .arg("--print=target-features")
+            .args(["--target", target_triple])
+            .output()?;
+        anyhow::ensure!(
+            output.status.success(),
+            "Failed to query the CPU features of target '{target_triple}'"
+        );
+        let enabled: BTreeSet<String> =
+            Self::get_cpufeatures_for_programs(Some(target_triple), Some(target_cpu))?
+                .into_iter()
+                .collect();
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let (rustc_features, _) = stdout
+            .split_once("Code-generation features supported by LLVM")
+            .unwrap_or((&stdout, ""));
+        Ok(Self::parse_listing(rustc_features)
+            .map(|(name, description)| ListedFeature {
+                enabled: enabled.contains(&name),
+                name,
+                description,
+            })
+            .collect())
+    }
+
+    /// Parses the indented entries of `--print=target-cpus` and `--print=target-features`, e.g.
+    /// `    avx2                    - AVX2 (Advanced Vector Extensions 2).`
+    /// The description is optional.
+    fn parse_listing(stdout: &str) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+        stdout
             .lines()
-            .map_while(Result::ok)
+            .filter(|line| line.starts_with(char::is_whitespace))
             .filter_map(|line| {
-                // We don't need a full blown regex compiler for such a simple line
-                line.strip_prefix("target_feature=\"")?
-                    .strip_suffix('"')
-                    .map(ToOwned::to_owned)
+                let (name, description) = match line.trim().split_once(" - ") {
+                    Some((name, description)) => {
+                        (name.trim_end(), Some(description.trim().to_owned()))
+                    }
+                    None => (line.trim(), None),
+                };
+                (!name.is_empty() && !name.contains(' ')).then(|| (name.to_owned(), description))
             })
-            .collect();
-        Ok(features)
+    }
+
+    /// Extracts features from `--print=cfg` output
+    fn parse_cfg_features(stdout: &[u8]) -> impl Iterator<Item = String> + '_ {
+        stdout.lines().map_while(Result::ok).filter_map(|line| {
+            // We don't need a full blown regex compiler for such a simple line
+            line.strip_prefix("target_feature=\"")?
+                .strip_suffix('"')
+                .map(ToOwned::to_owned)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `rustc --print=target-cpus` of rustc 1.70, descriptions aligned on the longest x86 CPU name
+    const TARGET_CPUS_1_70: &str = "\
+Available CPUs for this target:
+    native          - Select the CPU of the current host (currently znver3).
+    alderlake
+    amdfam10
+    x86-64-v3
+";
+
+    /// `rustc --print=target-cpus` of rustc 1.80, with a description per CPU and wider columns
+    const TARGET_CPUS_1_80: &str = "\
+Available CPUs for this target:
+    native                  - Select the CPU of the current host (currently znver4).
+    alderlake
+    amdfam10
+    x86-64                  - This is the default target CPU for the current build target (currently x86_64-unknown-linux-gnu).
+    x86-64-v3
+";
+
+    fn native_cpu(stdout: &str) -> Option<String> {
+        stdout.lines().find_map(Rustc::parse_native_cpu)
+    }
+
+    #[test]
+    fn native_cpu_in_old_and_new_alignment() {
+        assert_eq!(native_cpu(TARGET_CPUS_1_70).as_deref(), Some("znver3"));
+        assert_eq!(native_cpu(TARGET_CPUS_1_80).as_deref(), Some("znver4"));
+    }
+
+    #[test]
+    fn native_cpu_needs_a_current_cpu() {
+        assert_eq!(
+            native_cpu("    native - Select the CPU of the current host."),
+            None
+        );
+        assert_eq!(native_cpu("    native-foo - (currently bar)"), None);
+        assert_eq!(native_cpu("    native - (currently )"), None);
+    }
+
+    #[test]
+    fn listing_with_and_without_descriptions() {
+        let cpus: Vec<_> = Rustc::parse_listing(TARGET_CPUS_1_80).collect();
+        assert_eq!(cpus[1], ("alderlake".to_string(), None));
+        assert_eq!(cpus[3].0, "x86-64");
+        assert!(cpus[3]
+            .1
+            .as_deref()
+            .unwrap()
+            .starts_with("This is the default"));
+        assert_eq!(cpus.len(), 5);
     }
 }
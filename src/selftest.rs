@@ -0,0 +1,124 @@
+//! `--selftest`: runs each flavor of a freshly packed fat binary on the build host,
+//! to catch flavors that fail to load (linker, ABI or glibc issues) before distribution.
+//!
+//! The dispatcher is forced to select each flavor in turn by disabling the host
+//! CPU features the flavor does not use with `CARGO_MULTIARCH_DISABLE_FEATURES`,
+//! so the extraction is exercised too.
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use itertools::Itertools;
+
+/// A flavor still running by then has loaded, it is killed
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) enum Probe {
+    /// The host lacks some CPU features of the flavor
+    Unsupported,
+    /// How the flavor exited, any exit code counts: the probe argument may be unknown to it
+    Launched(String),
+    Failed(String),
+}
+
+/// Runs the fat binary with `probe` as its only argument, restricted to the
+/// `cpufeatures` of a flavor, empty for the fallback
+pub(crate) fn probe_flavor(
+    fatbin: &Path,
+    cpufeatures: &[String],
+    host_features: &BTreeSet<String>,
+    probe: &str,
+) -> anyhow::Result<Probe> {
+    if !cpufeatures
+        .iter()
+        .all(|feature| host_features.contains(feature))
+    {
+        return Ok(Probe::Unsupported);
+    }
+    let disabled = host_features
+        .iter()
+        .filter(|&feature| !cpufeatures.contains(feature))
+        .join(",");
+
+    // A relative path without separator would be looked up in PATH
+    let program = std::path::absolute(fatbin)
+        .with_context(|| format!("Invalid path `{}`", fatbin.display()))?;
+    let mut child = Command::new(&program)
+        .arg(probe)
+        .env("CARGO_MULTIARCH_DISABLE_FEATURES", disabled)
+        // Its diagnostics would be taken for dispatcher errors
+        .env_remove("CARGO_MULTIARCH_LOG")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run `{}`", fatbin.display()))?;
+
+    // Read aside: a flavor filling the pipe would block, and a process it spawned
+    // could keep the pipe open after it is killed
+    let (stderr_tx, stderr_rx) = mpsc::channel();
+    if let Some(mut pipe) = child.stderr.take() {
+        thread::spawn(move || {
+            let mut stderr = String::new();
+            let _ = pipe.read_to_string(&mut stderr);
+            let _ = stderr_tx.send(stderr);
+        });
+    }
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .context("Failed to wait for the fat binary")?
+        {
+            break Some(status);
+        }
+        if started.elapsed() >= PROBE_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let stderr = stderr_rx
+        .recv_timeout(Duration::from_secs(1))
+        .unwrap_or_default();
+
+    let Some(status) = status else {
+        return Ok(Probe::Launched(format!(
+            "still running after {}s",
+            PROBE_TIMEOUT.as_secs()
+        )));
+    };
+    // The dispatcher prefixes its errors, e.g. a corrupted patch
+    let dispatcher_error = stderr.lines().find(|line| line.starts_with("multiarch:"));
+    Ok(match dispatcher_error {
+        Some(error) => Probe::Failed(error.to_owned()),
+        None if failed_to_load(&status) => {
+            let detail = stderr.lines().rfind(|line| !line.trim().is_empty());
+            Probe::Failed(match detail {
+                Some(detail) => format!("{status}: {detail}"),
+                None => status.to_string(),
+            })
+        }
+        None => Probe::Launched(status.to_string()),
+    })
+}
+
+/// Killed by a signal like SIGILL or SIGSEGV, or rejected by the dynamic loader
+#[cfg(unix)]
+fn failed_to_load(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().is_some() || matches!(status.code(), Some(126 | 127))
+}
+
+/// NTSTATUS error codes like STATUS_ILLEGAL_INSTRUCTION or STATUS_DLL_NOT_FOUND
+#[cfg(windows)]
+fn failed_to_load(status: &ExitStatus) -> bool {
+    status.code().is_some_and(|code| code as u32 >= 0xC000_0000)
+}
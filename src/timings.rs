@@ -0,0 +1,127 @@
+//! `--timings`: how long each flavor took to compile and each fat binary to pack,
+//! reported like `cargo build --timings` to justify or prune expensive flavors.
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Record {
+    binary: String,
+    // Flavor description, or the number of packed flavors
+    unit: String,
+    step: &'static str, // compile or pack
+    seconds: f64,
+}
+
+#[derive(Default)]
+pub(crate) struct Timings {
+    records: RefCell<Vec<Record>>,
+}
+
+impl Timings {
+    pub(crate) fn record_compile(&self, binary: &str, flavor: &str, duration: Duration) {
+        self.record(binary, flavor.to_owned(), "compile", duration);
+    }
+
+    pub(crate) fn record_pack(&self, binary: &str, num_flavors: usize, duration: Duration) {
+        self.record(binary, format!("{num_flavors} flavors"), "pack", duration);
+    }
+
+    fn record(&self, binary: &str, unit: String, step: &'static str, duration: Duration) {
+        self.records.borrow_mut().push(Record {
+            binary: binary.to_owned(),
+            unit,
+            step,
+            seconds: duration.as_secs_f64(),
+        });
+    }
+
+    /// Writes `multiarch-timings.json` and `multiarch-timings.html` to `dir`,
+    /// returns the path of the HTML report
+    pub(crate) fn write_report(&self, dir: &Path) -> anyhow::Result<PathBuf> {
+        let records = self.records.borrow();
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory `{}`", dir.display()))?;
+
+        let json_path = dir.join("multiarch-timings.json");
+        let json = serde_json::to_vec_pretty(&*records).context("Failed to encode the timings")?;
+        fs::write(&json_path, json)
+            .with_context(|| format!("Failed to write to `{}`", json_path.display()))?;
+
+        let html_path = dir.join("multiarch-timings.html");
+        fs::write(&html_path, html_report(&records))
+            .with_context(|| format!("Failed to write to `{}`", html_path.display()))?;
+        Ok(html_path)
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A table of the steps, slowest first, with a bar proportional to their duration
+fn html_report(records: &[Record]) -> String {
+    let total: f64 = records.iter().map(|record| record.seconds).sum();
+    let longest = records
+        .iter()
+        .map(|record| record.seconds)
+        .fold(0.0, f64::max);
+    let mut sorted: Vec<&Record> = records.iter().collect();
+    sorted.sort_by(|a, b| b.seconds.total_cmp(&a.seconds));
+
+    let rows: String = sorted
+        .iter()
+        .map(|record| {
+            let width = if longest > 0.0 {
+                record.seconds / longest * 100.0
+            } else {
+                0.0
+            };
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td class=\"num\">{:.1}s</td>\
+                <td class=\"bar\"><div class=\"{}\" style=\"width: {width:.1}%\"></div></td></tr>\n",
+                html_escape(&record.binary),
+                record.step,
+                html_escape(&record.unit),
+                record.seconds,
+                record.step,
+            )
+        })
+        .collect();
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>cargo-multiarch timings</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; width: 100%; }}
+td, th {{ padding: 4px 8px; border-bottom: 1px solid #ddd; text-align: left; }}
+td.num {{ text-align: right; white-space: nowrap; }}
+td.bar {{ width: 40%; }}
+td.bar div {{ height: 1em; }}
+div.compile {{ background: #4e79a7; }}
+div.pack {{ background: #f28e2b; }}
+</style>
+</head>
+<body>
+<h1>cargo-multiarch timings</h1>
+<p>{} steps, {total:.1}s in total</p>
+<table>
+<tr><th>Binary</th><th>Step</th><th>Flavor</th><th>Duration</th><th></th></tr>
+{rows}</table>
+</body>
+</html>
+"#,
+        records.len(),
+    )
+}
@@ -0,0 +1,249 @@
+//! Unpacks the flavors embedded in a fat binary, for `cargo multiarch add-flavor` to repack them.
+//!
+//! Like `verify`, the dispatcher reconstructs the flavors itself
+//! when run with `EXTRACT_ENV` set, so the fat binary must run on this host.
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::Context;
+use itertools::Itertools;
+
+use crate::cli::Ranking;
+use crate::format::EXTRACT_ENV;
+use crate::gen_fatbin_pkg::DispatcherOptions;
+
+pub(crate) struct UnpackedFlavor {
+    // Empty for the fallback
+    pub(crate) cpufeatures: Vec<String>,
+    pub(crate) min_glibc: Option<String>,
+    pub(crate) priority: Option<u32>,
+    pub(crate) path: PathBuf,
+}
+
+/// Dispatcher options the fat binary was built with
+#[derive(Debug, PartialEq)]
+pub(crate) struct UnpackedOptions {
+    simple_ranking: bool,
+    // None when the fat binary has too few flavors to tell
+    delta_chain: Option<bool>,
+    hypervisor_ignore_features: Vec<String>,
+    build_info: bool,
+}
+
+impl UnpackedOptions {
+    /// Parses the first line of the `EXTRACT_ENV` output
+    fn parse(line: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let ["options", ranking, delta_chain, hypervisor, build_info] = fields[..] else {
+            anyhow::bail!("Unexpected output of the fat binary: {line}");
+        };
+        Ok(Self {
+            simple_ranking: ranking == "simple",
+            delta_chain: match delta_chain {
+                "" => None,
+                delta_chain => Some(delta_chain == "1"),
+            },
+            hypervisor_ignore_features: hypervisor
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(ToOwned::to_owned)
+                .collect(),
+            build_info: build_info == "1",
+        })
+    }
+
+    /// The options to pass for a repacked fat binary to dispatch like this one,
+    /// empty if the current ones already match
+    pub(crate) fn missing_options(
+        &self,
+        current: &DispatcherOptions,
+        build_info: bool,
+    ) -> Vec<String> {
+        let mut missing = Vec::new();
+        if self.simple_ranking != (current.ranking == Ranking::Simple) {
+            missing.push(match self.simple_ranking {
+                true => "--ranking simple".to_owned(),
+                false => "--ranking curated".to_owned(),
+            });
+        }
+        match self.delta_chain {
+            Some(true) if !current.delta_chain => missing.push("--delta-chain".to_owned()),
+            Some(false) if current.delta_chain => missing.push("no --delta-chain".to_owned()),
+            _ => {}
+        }
+        let ignored: BTreeSet<&String> = self.hypervisor_ignore_features.iter().collect();
+        if ignored != current.hypervisor_ignore_features.iter().collect() {
+            missing.push(match ignored.is_empty() {
+                true => "no --hypervisor-ignore-features".to_owned(),
+                false => format!("--hypervisor-ignore-features {}", ignored.iter().join(",")),
+            });
+        }
+        if self.build_info != build_info {
+            missing.push(match self.build_info {
+                true => "--embed-build-info".to_owned(),
+                false => "no --embed-build-info".to_owned(),
+            });
+        }
+        missing
+    }
+}
+
+/// Reconstructs the flavors of `fatbin` into `dir`, the base executable first
+pub(crate) fn unpack(
+    fatbin: &Path,
+    dir: &Path,
+) -> anyhow::Result<(UnpackedOptions, Vec<UnpackedFlavor>)> {
+    // Refuse to run an arbitrary executable, or a fat binary that would run its flavor
+    let content =
+        std::fs::read(fatbin).with_context(|| format!("Failed to read `{}`", fatbin.display()))?;
+    anyhow::ensure!(
+        content
+            .windows(EXTRACT_ENV.len())
+            .any(|window| window == EXTRACT_ENV.as_bytes()),
+        "`{}` is not a fat binary built by this version of cargo-multiarch",
+        fatbin.display()
+    );
+    drop(content);
+
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory `{}`", dir.display()))?;
+    let dir =
+        std::path::absolute(dir).with_context(|| format!("Invalid path `{}`", dir.display()))?;
+    // A relative path without separator would be looked up in PATH
+    let program = std::path::absolute(fatbin)
+        .with_context(|| format!("Invalid path `{}`", fatbin.display()))?;
+    let output = Command::new(&program)
+        .env(EXTRACT_ENV, &dir)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| {
+            format!(
+                "Failed to run `{}`, it must be executable on this host",
+                fatbin.display()
+            )
+        })?;
+    anyhow::ensure!(
+        output.status.success(),
+        "`{}` failed to unpack its flavors ({})",
+        fatbin.display(),
+        output.status
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    let options = UnpackedOptions::parse(lines.next().unwrap_or_default())?;
+
+    let flavors = lines
+        .map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            let (Some(features), Some(min_glibc), Some(priority), Some(path)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                anyhow::bail!("Unexpected output of the fat binary: {line}");
+            };
+            let priority = match priority {
+                "" => None,
+                priority => Some(priority.parse().with_context(|| {
+                    format!("Unexpected priority in the output of the fat binary: {line}")
+                })?),
+            };
+            Ok(UnpackedFlavor {
+                cpufeatures: features
+                    .split(',')
+                    .filter(|feature| !feature.is_empty())
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                min_glibc: (!min_glibc.is_empty()).then(|| min_glibc.to_owned()),
+                priority,
+                path: PathBuf::from(path),
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok((options, flavors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{DispatcherAlloc, StripFatbin};
+
+    fn current(ranking: Ranking, delta_chain: bool, ignored: &[&str]) -> DispatcherOptions {
+        DispatcherOptions {
+            ranking,
+            alloc: DispatcherAlloc::default(),
+            bsdiff_fast: false,
+            delta_chain,
+            hypervisor_ignore_features: ignored.iter().map(|f| f.to_string()).collect(),
+            strip: StripFatbin::default(),
+            static_pie: false,
+        }
+    }
+
+    fn current_default(delta_chain: bool) -> DispatcherOptions {
+        current(Ranking::Curated, delta_chain, &[])
+    }
+
+    #[test]
+    fn options_line() {
+        let options = UnpackedOptions::parse("options\tsimple\t1\tavx512f,avx512bw\t0").unwrap();
+        assert_eq!(
+            options,
+            UnpackedOptions {
+                simple_ranking: true,
+                delta_chain: Some(true),
+                hypervisor_ignore_features: vec!["avx512f".to_owned(), "avx512bw".to_owned()],
+                build_info: false,
+            }
+        );
+        let options = UnpackedOptions::parse("options\tcurated\t\t\t1").unwrap();
+        assert_eq!(options.delta_chain, None);
+        assert!(options.hypervisor_ignore_features.is_empty());
+        assert!(options.build_info);
+
+        // Fat binaries of older versions list their flavors right away
+        assert!(UnpackedOptions::parse("avx2\t\t/tmp/app-avx2").is_err());
+    }
+
+    #[test]
+    fn matching_options_need_nothing() {
+        let options = UnpackedOptions::parse("options\tcurated\t1\tavx512bw,avx512f\t1").unwrap();
+        let current = current(Ranking::Curated, true, &["avx512f", "avx512bw"]);
+        assert!(options.missing_options(&current, true).is_empty());
+
+        // Fewer than two flavors cannot tell whether they were delta-chained
+        let options = UnpackedOptions::parse("options\tcurated\t\t\t0").unwrap();
+        assert!(options
+            .missing_options(&current_default(false), false)
+            .is_empty());
+        assert!(options
+            .missing_options(&current_default(true), false)
+            .is_empty());
+    }
+
+    #[test]
+    fn differing_options_are_listed() {
+        let options = UnpackedOptions::parse("options\tsimple\t1\tavx512f\t1").unwrap();
+        assert_eq!(
+            options.missing_options(&current_default(false), false),
+            [
+                "--ranking simple",
+                "--delta-chain",
+                "--hypervisor-ignore-features avx512f",
+                "--embed-build-info"
+            ]
+        );
+
+        let options = UnpackedOptions::parse("options\tcurated\t0\t\t0").unwrap();
+        assert_eq!(
+            options.missing_options(&current(Ranking::Simple, true, &["avx512f"]), true),
+            [
+                "--ranking curated",
+                "no --delta-chain",
+                "no --hypervisor-ignore-features",
+                "no --embed-build-info"
+            ]
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! `cargo multiarch verify`: checks that every flavor embedded in a fat binary
+//! is reconstructed identical to the executable it was built from.
+//!
+//! The flavors are compiled into the dispatcher, which reconstructs and checks them
+//! when run with `VERIFY_ENV` set.
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::Context;
+use console::style;
+
+use crate::format::{self, BUILD_INFO_MARKER, VERIFY_ENV};
+
+pub(crate) fn verify(fatbin: &Path) -> anyhow::Result<()> {
+    // Refuse to run an arbitrary executable, or a fat binary that would not understand VERIFY_ENV
+    let content =
+        std::fs::read(fatbin).with_context(|| format!("Failed to read `{}`", fatbin.display()))?;
+    format::check_format_version(fatbin, &content).map_err(anyhow::Error::msg)?;
+    // Embedded with --embed-build-info
+    let build_info = format::find_marked(&content, BUILD_INFO_MARKER)
+        .map(|info| String::from_utf8_lossy(info).into_owned());
+    drop(content);
+
+    eprintln!(
+        "{:>12} {}",
+        style("Verifying").green().bold(),
+        fatbin.display()
+    );
+    for line in build_info.iter().flat_map(|info| info.lines()) {
+        println!("{:>12} {line}", style("Build").cyan());
+    }
+
+    // A relative path without separator would be looked up in PATH
+    let program = std::path::absolute(fatbin)
+        .with_context(|| format!("Invalid path `{}`", fatbin.display()))?;
+    let output = Command::new(&program)
+        .env(VERIFY_ENV, "1")
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| {
+            format!(
+                "Failed to run `{}`, it must be executable on this host",
+                fatbin.display()
+            )
+        })?;
+
+    let mut flavors = 0;
+    let mut failures = 0;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut fields = line.splitn(4, '\t');
+        let (Some(status), Some(features), Some(size), detail) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            anyhow::bail!("Unexpected output of the fat binary: {line}");
+        };
+        flavors += 1;
+        if status == "ok" {
+            println!("{:>12} [{features}] ({size} bytes)", style("ok").green());
+        } else {
+            failures += 1;
+            println!(
+                "{:>12} [{features}] ({size} bytes): {}",
+                style("FAILED").red().bold(),
+                detail.unwrap_or_default()
+            );
+        }
+    }
+
+    anyhow::ensure!(
+        flavors > 0,
+        "`{}` reported no flavors ({})",
+        fatbin.display(),
+        output.status
+    );
+    anyhow::ensure!(
+        failures == 0,
+        "{failures} of {flavors} flavors of `{}` failed verification",
+        fatbin.display()
+    );
+    anyhow::ensure!(
+        output.status.success(),
+        "`{}` failed verification ({})",
+        fatbin.display(),
+        output.status
+    );
+    Ok(())
+}